@@ -0,0 +1,312 @@
+// Coordinates the hat processes using the same bluetooth adapter, so they don't connect at the
+// same time (BlueZ answers org.bluez.Error.InProgress) or stop discovery under each other.
+//
+// Every adapter has two small files in the lock directory ($HAT_LOCK_DIR, or hat/ in the temp
+// dir), each listing who is using it, one "pid id" line per process or thread. They are only read
+// and written while flock()ed, and users whose process is gone are dropped, so a crashed process
+// doesn't keep the adapter.
+//
+// The adapter is shared by every (unix) user, so are the files: the directory is world-writable
+// and sticky, like /tmp, and the files are writable by everyone.
+
+use std::env;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a queued operation looks whether it is its turn
+const POLL_INTERVAL_MS: u64 = 100;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// $HAT_LOCK_DIR, or hat/ in the temp dir
+pub fn lock_dir() -> PathBuf {
+    match env::var_os("HAT_LOCK_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => env::temp_dir().join("hat"),
+    }
+}
+
+// The adapter (e.g. hci0) an adapter or device path (/org/bluez/hci0/dev_...) belongs to
+pub fn adapter_name(path: &str) -> Option<&str> {
+    path.split('/').nth(3).filter(|name| !name.is_empty())
+}
+
+// The turn of an operation on the adapter, e.g. connecting. Operations wait for the ones queued
+// before them - in this and other processes - first come, first served. The turn ends when this
+// is dropped
+#[derive(Debug)]
+pub struct OperationLock {
+    registry: Registry,
+    user: User,
+}
+
+impl OperationLock {
+    // Queues for the adapter, and waits for its turn for at most `timeout` (None is as long as it
+    // takes)
+    pub fn acquire(adapter: &str, timeout: Option<Duration>) -> io::Result<OperationLock> {
+        OperationLock::acquire_in(&lock_dir(), adapter, timeout)
+    }
+
+    fn acquire_in(dir: &Path, adapter: &str, timeout: Option<Duration>) -> io::Result<OperationLock> {
+        let registry = Registry::new(dir, adapter, "queue")?;
+        let user = User::new();
+        let until = timeout.map(|timeout| Instant::now() + timeout);
+
+        registry.update(|users| users.push(user))?;
+
+        let lock = OperationLock { registry, user };
+
+        while !lock.registry.update(|users| users.first() == Some(&user))? {
+            if until.is_some_and(|until| Instant::now() >= until) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} is busy with another hat process", adapter),
+                ));
+            }
+
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        Ok(lock)
+    }
+}
+
+impl Drop for OperationLock {
+    fn drop(&mut self) {
+        let user = self.user;
+
+        self.registry.update(|users| users.retain(|u| *u != user)).ok();
+    }
+}
+
+// A process discovering through the adapter. Discovery is only to be stopped by the last one
+#[derive(Debug)]
+pub struct DiscoveryRef {
+    registry: Registry,
+    user: User,
+}
+
+impl DiscoveryRef {
+    pub fn register(adapter: &str) -> io::Result<DiscoveryRef> {
+        DiscoveryRef::register_in(&lock_dir(), adapter)
+    }
+
+    fn register_in(dir: &Path, adapter: &str) -> io::Result<DiscoveryRef> {
+        let registry = Registry::new(dir, adapter, "discovery")?;
+        let user = User::new();
+
+        registry.update(|users| users.push(user))?;
+
+        Ok(DiscoveryRef { registry, user })
+    }
+
+    // Gives up the reference. True when nobody else is discovering any more, so discovery should
+    // be stopped
+    pub fn release(self) -> io::Result<bool> {
+        let user = self.user;
+
+        self.registry.update(|users| {
+            users.retain(|u| *u != user);
+            users.is_empty()
+        })
+    }
+}
+
+impl Drop for DiscoveryRef {
+    fn drop(&mut self) {
+        let user = self.user;
+
+        self.registry.update(|users| users.retain(|u| *u != user)).ok();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct User {
+    pid: u32,
+    /// Tells apart the users within a process (e.g. threads)
+    id: u64,
+}
+
+impl User {
+    fn new() -> User {
+        User {
+            pid: process::id(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn parse(line: &str) -> Option<User> {
+        let mut fields = line.split_whitespace();
+
+        Some(User {
+            pid: fields.next()?.parse().ok()?,
+            id: fields.next()?.parse().ok()?,
+        })
+    }
+
+    fn alive(&self) -> bool {
+        // Signal 0 only checks that the process exists. It may belong to another user (EPERM).
+        // 0 and negative pids would be process groups
+        let pid = self.pid as libc::pid_t;
+
+        pid > 0
+            && (unsafe { libc::kill(pid, 0) == 0 } || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    path: PathBuf,
+}
+
+impl Registry {
+    fn new(dir: &Path, adapter: &str, what: &str) -> io::Result<Registry> {
+        if !dir.is_dir() {
+            DirBuilder::new().recursive(true).mode(0o1777).create(dir)?;
+            // Shared by every user running hat, whatever their umask. Sticky, like /tmp, so that
+            // nobody removes the files of someone else
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o1777)).ok();
+        }
+
+        Ok(Registry {
+            path: dir.join(format!("{}.{}", adapter, what)),
+        })
+    }
+
+    // Runs `f` on the users still alive, with the file locked, and keeps the users it leaves
+    fn update<T, F: FnOnce(&mut Vec<User>) -> T>(&self, f: F) -> io::Result<T> {
+        let mut file = self.open()?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut content = String::new();
+
+        file.read_to_string(&mut content)?;
+
+        let mut users: Vec<User> = content.lines().filter_map(User::parse).filter(User::alive).collect();
+        let result = f(&mut users);
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        for user in users {
+            writeln!(file, "{} {}", user.pid, user.id)?;
+        }
+
+        // Closing the file unlocks it
+        Ok(result)
+    }
+
+    fn open(&self) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+
+        options.read(true).write(true).custom_flags(libc::O_NOFOLLOW);
+
+        loop {
+            // Opened without O_CREAT when it is there: in a sticky directory, the kernel may refuse
+            // O_CREAT on a file of another user (fs.protected_regular)
+            match options.open(&self.path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => return result,
+            }
+
+            match options.clone().create_new(true).mode(0o666).open(&self.path) {
+                Ok(file) => {
+                    // Writable by every user, whatever our umask
+                    file.set_permissions(fs::Permissions::from_mode(0o666))?;
+
+                    return Ok(file);
+                }
+                // Created by someone else in the meantime
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A lock directory of its own for every test, removed when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            TestDir(env::temp_dir().join(format!("hat-test-{}-{}", process::id(), name)))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn adapter_name_of_paths() {
+        assert_eq!(adapter_name("/org/bluez/hci0"), Some("hci0"));
+        assert_eq!(adapter_name("/org/bluez/hci1/dev_C4_7C_8D_67_C2_8B"), Some("hci1"));
+        assert_eq!(adapter_name("/"), None);
+    }
+
+    #[test]
+    fn operations_take_turns() {
+        let dir = TestDir::new("turns");
+        let first = OperationLock::acquire_in(&dir.0, "hci0", None).unwrap();
+
+        assert_eq!(
+            OperationLock::acquire_in(&dir.0, "hci0", Some(Duration::from_millis(250)))
+                .err()
+                .map(|e| e.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+
+        drop(first);
+
+        assert!(OperationLock::acquire_in(&dir.0, "hci0", Some(Duration::from_millis(250))).is_ok());
+    }
+
+    #[test]
+    fn last_discovery_ref_stops() {
+        let dir = TestDir::new("discovery");
+        let first = DiscoveryRef::register_in(&dir.0, "hci0").unwrap();
+        let second = DiscoveryRef::register_in(&dir.0, "hci0").unwrap();
+
+        assert!(!first.release().unwrap());
+        assert!(second.release().unwrap());
+    }
+
+    #[test]
+    fn files_are_shared_by_every_user() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TestDir::new("mode");
+        let lock = OperationLock::acquire_in(&dir.0, "hci0", None).unwrap();
+
+        assert_eq!(lock.registry.path, dir.0.join("hci0.queue"));
+        assert_eq!(fs::metadata(&lock.registry.path).unwrap().mode() & 0o777, 0o666);
+        assert_eq!(fs::metadata(&dir.0).unwrap().mode() & 0o7777, 0o1777);
+    }
+
+    #[test]
+    fn users_without_a_process_are_dropped() {
+        let dir = TestDir::new("dead");
+        let registry = Registry::new(&dir.0, "hci0", "queue").unwrap();
+
+        // Above any pid_max, and a process group if it were taken for a pid_t
+        fs::write(&registry.path, "0 1\n4294967295 2\n").unwrap();
+
+        assert!(OperationLock::acquire_in(&dir.0, "hci0", Some(Duration::from_millis(250))).is_ok());
+    }
+}
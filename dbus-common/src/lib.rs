@@ -5,6 +5,7 @@ pub mod org_bluez_gatt_characteristic1;
 pub mod org_bluez_gatt_descriptor1;
 pub mod utils;
 pub mod dbus_processor;
+pub mod adapter_lock;
 
 #[cfg(test)]
 mod tests {
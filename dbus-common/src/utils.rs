@@ -1,11 +1,15 @@
 use dbus::{Connection, Message, MessageItem, Props};
 
 use std::error::Error;
+use std::io;
 
 pub static SERVICE_NAME: &'static str = "org.bluez";
 pub static ADAPTER_INTERFACE: &'static str = "org.bluez.Adapter1";
 pub static DEVICE_INTERFACE: &'static str = "org.bluez.Device1";
 
+// Calls to BlueZ itself (not to devices) answer right away, or not at all
+pub const DBUS_TIMEOUT_MS: i32 = 1000;
+
 fn get_managed_objects(service_name : &str, connection : &Connection) -> Result<Vec<MessageItem>, Box<dyn Error>> {
     let m = Message::new_method_call(
         service_name,
@@ -14,7 +18,7 @@ fn get_managed_objects(service_name : &str, connection : &Connection) -> Result<
         "GetManagedObjects",
     )?;
 
-    let r = connection.send_with_reply_and_block(m, 1000)?;
+    let r = connection.send_with_reply_and_block(m, DBUS_TIMEOUT_MS)?;
 
     Ok(r.get_items())
 }
@@ -63,7 +67,7 @@ pub fn get_property(
     object_path: &str,
     property_name: &str,
     ) -> Result<MessageItem, Box<dyn Error>> {
-    let p = Props::new(&connection, SERVICE_NAME, object_path, interface, 1000);
+    let p = Props::new(&connection, SERVICE_NAME, object_path, interface, DBUS_TIMEOUT_MS);
     Ok(p.get(property_name)?.clone())
 }
 
@@ -75,7 +79,7 @@ pub fn get_adapter(connection : &Connection) -> Result<String, Box<dyn Error>> {
     let adapters = get_adapters(connection)?;
 
     if adapters.is_empty() {
-        return Err(Box::from("Bluetooth adapter not found"));
+        return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Bluetooth adapter not found")));
     }
 
     Ok(adapters[0].clone())
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+// Friendly names for devices ("basil" instead of "C4:7C:8D:65:BD:8B"). Aliases are matched
+// case insensitively
+#[derive(Default)]
+pub struct Aliases {
+    /// lowercase alias -> address
+    addresses: HashMap<String, String>,
+    /// address -> alias
+    aliases: HashMap<String, String>,
+}
+
+impl Aliases {
+    // Takes alias -> address, as written in the config
+    pub fn new(config: &HashMap<String, String>) -> Result<Aliases, String> {
+        let mut aliases = Aliases::default();
+
+        for (alias, address) in config {
+            if !is_address(address) {
+                return Err(format!("Alias '{}' is for '{}', which isn't an address", alias, address));
+            }
+
+            if is_address(alias) {
+                return Err(format!("Alias '{}' looks like an address", alias));
+            }
+
+            let address = address.to_uppercase();
+
+            if let Some(other) = aliases.aliases.get(&address) {
+                return Err(format!("{} has two aliases: '{}' and '{}'", address, other, alias));
+            }
+
+            // Only differing in case, e.g. "Basil" and "basil"
+            if let Some(other) = aliases.addresses.get(&alias.to_lowercase()) {
+                return Err(format!("Alias '{}' is for both {} and {}", alias, other, address));
+            }
+
+            aliases.addresses.insert(alias.to_lowercase(), address.clone());
+            aliases.aliases.insert(address, alias.clone());
+        }
+
+        Ok(aliases)
+    }
+
+    // Turns an address or alias into an address
+    pub fn resolve(&self, name: &str) -> Result<String, String> {
+        if is_address(name) {
+            return Ok(name.to_uppercase());
+        }
+
+        self.addresses
+            .get(&name.to_lowercase())
+            .cloned()
+            .ok_or_else(|| format!("'{}' is neither an address nor a known alias", name))
+    }
+
+    pub fn resolve_all(&self, names: &[String]) -> Result<Vec<String>, String> {
+        names.iter().map(|name| self.resolve(name)).collect()
+    }
+
+    pub fn alias(&self, address: &str) -> Option<&str> {
+        self.aliases.get(&address.to_uppercase()).map(String::as_str)
+    }
+}
+
+// "C4:7C:8D:65:BD:8B"
+pub fn is_address(s: &str) -> bool {
+    let parts: Vec<&str> = s.split(':').collect();
+
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(config: &[(&str, &str)]) -> Result<Aliases, String> {
+        Aliases::new(&config.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect())
+    }
+
+    #[test]
+    fn resolves_aliases_in_any_case() {
+        let aliases = aliases(&[("Basil", "c4:7c:8d:65:bd:8b")]).unwrap();
+
+        assert_eq!(aliases.resolve("basil"), Ok("C4:7C:8D:65:BD:8B".to_string()));
+        assert_eq!(aliases.resolve("BASIL"), Ok("C4:7C:8D:65:BD:8B".to_string()));
+        assert_eq!(aliases.alias("c4:7c:8d:65:bd:8b"), Some("Basil"));
+    }
+
+    #[test]
+    fn addresses_resolve_to_themselves() {
+        let aliases = aliases(&[("basil", "C4:7C:8D:65:BD:8B")]).unwrap();
+
+        assert_eq!(aliases.resolve("c4:7c:8d:67:c2:8b"), Ok("C4:7C:8D:67:C2:8B".to_string()));
+        assert_eq!(aliases.alias("C4:7C:8D:67:C2:8B"), None);
+        assert!(aliases.resolve("monstera").is_err());
+        assert!(aliases.resolve_all(&["basil".to_string(), "monstera".to_string()]).is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_config() {
+        assert!(aliases(&[("basil", "C4:7C:8D:65:BD:8B"), ("Basil", "C4:7C:8D:67:C2:8B")]).is_err());
+        assert!(aliases(&[("basil", "C4:7C:8D:65:BD:8B"), ("thyme", "c4:7c:8d:65:bd:8b")]).is_err());
+        assert!(aliases(&[("C4:7C:8D:67:C2:8B", "C4:7C:8D:65:BD:8B")]).is_err());
+        assert!(aliases(&[("basil", "kitchen")]).is_err());
+    }
+
+    #[test]
+    fn recognises_addresses() {
+        assert!(is_address("C4:7C:8D:65:BD:8B"));
+        assert!(is_address("c4:7c:8d:65:bd:8b"));
+        assert!(!is_address("C4:7C:8D:65:BD"));
+        assert!(!is_address("C4:7C:8D:65:BD:8G"));
+        assert!(!is_address("C47C:8D:65:BD:8B:00"));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::alias::Aliases;
+
+// Device (address or alias) -> metric -> adjustment, as written in the config
+pub type CalibrationConfig = HashMap<String, HashMap<String, Adjustment>>;
+
+// A linear correction of one metric: value * scale + offset
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Adjustment {
+    pub offset: f64,
+    pub scale: f64,
+}
+
+impl Default for Adjustment {
+    fn default() -> Self {
+        Adjustment { offset: 0.0, scale: 1.0 }
+    }
+}
+
+impl Adjustment {
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
+
+// Corrections for devices that don't agree with a reference. Metrics are named as in the output
+// (temperature, moisture, weight, ...), and metrics a device doesn't have are ignored
+#[derive(Default)]
+pub struct Calibrations {
+    /// address -> metric -> adjustment
+    devices: HashMap<String, HashMap<String, Adjustment>>,
+}
+
+impl Calibrations {
+    pub fn new(config: &CalibrationConfig, aliases: &Aliases) -> Result<Calibrations, String> {
+        let mut devices = HashMap::new();
+
+        for (device, metrics) in config {
+            for (metric, adjustment) in metrics {
+                if !adjustment.offset.is_finite() || !adjustment.scale.is_finite() || adjustment.scale == 0.0 {
+                    return Err(format!("Calibration of {} for '{}' has to have a finite, non-zero scale", metric, device));
+                }
+            }
+
+            let address = aliases.resolve(device).map_err(|e| format!("Calibration: {}", e))?;
+
+            if devices.insert(address.clone(), metrics.clone()).is_some() {
+                return Err(format!("{} is calibrated twice", address));
+            }
+        }
+
+        Ok(Calibrations { devices })
+    }
+
+    pub fn is_calibrated(&self, address: &str) -> bool {
+        self.devices.contains_key(&address.to_uppercase())
+    }
+
+    // The calibrated value, or None if the metric isn't calibrated for the device
+    pub fn apply(&self, address: &str, metric: &str, value: f64) -> Option<f64> {
+        self.devices
+            .get(&address.to_uppercase())
+            .and_then(|metrics| metrics.get(metric))
+            .map(|adjustment| adjustment.apply(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adjustment(offset: f64, scale: f64) -> Adjustment {
+        Adjustment { offset, scale }
+    }
+
+    fn calibrations(config: &[(&str, &str, Adjustment)]) -> Result<Calibrations, String> {
+        let aliases = Aliases::new(&vec![("basil".to_string(), "C4:7C:8D:65:BD:8B".to_string())].into_iter().collect())?;
+        let mut devices = CalibrationConfig::new();
+
+        for (device, metric, adjustment) in config {
+            devices
+                .entry(device.to_string())
+                .or_default()
+                .insert(metric.to_string(), *adjustment);
+        }
+
+        Calibrations::new(&devices, &aliases)
+    }
+
+    #[test]
+    fn scales_then_offsets() {
+        assert_eq!(adjustment(-1.5, 1.0).apply(23.9), 23.9 - 1.5);
+        assert_eq!(adjustment(0.0, 1.1).apply(40.0), 40.0 * 1.1);
+        assert_eq!(adjustment(-0.3, 0.5).apply(80.0), 39.7);
+        assert_eq!(Adjustment::default().apply(72.5), 72.5);
+    }
+
+    #[test]
+    fn reads_defaults_from_config() {
+        let adjustment: Adjustment = serde_json::from_str(r#"{ "offset": -0.3 }"#).unwrap();
+
+        assert_eq!(adjustment, Adjustment { offset: -0.3, scale: 1.0 });
+        assert!(serde_json::from_str::<Adjustment>(r#"{ "ofset": -0.3 }"#).is_err());
+    }
+
+    #[test]
+    fn looks_up_device_and_metric() {
+        let calibrations = calibrations(&[
+            ("basil", "temperature", adjustment(-1.0, 1.0)),
+            ("C4:7C:8D:67:C2:8B", "moisture", adjustment(0.0, 2.0)),
+        ])
+        .unwrap();
+
+        assert_eq!(calibrations.apply("c4:7c:8d:65:bd:8b", "temperature", 24.0), Some(23.0));
+        assert_eq!(calibrations.apply("C4:7C:8D:65:BD:8B", "moisture", 40.0), None);
+        assert_eq!(calibrations.apply("C4:7C:8D:67:C2:8B", "moisture", 40.0), Some(80.0));
+        assert_eq!(calibrations.apply("EF:FB:0D:B1:43:97", "weight", 72.5), None);
+        assert!(calibrations.is_calibrated("c4:7c:8d:67:c2:8b"));
+        assert!(!calibrations.is_calibrated("EF:FB:0D:B1:43:97"));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(calibrations(&[("basil", "weight", adjustment(0.0, 0.0))]).is_err());
+        assert!(calibrations(&[("basil", "weight", adjustment(f64::NAN, 1.0))]).is_err());
+        assert!(calibrations(&[("monstera", "weight", adjustment(0.3, 1.0))]).is_err());
+        assert!(calibrations(&[
+            ("basil", "weight", adjustment(0.3, 1.0)),
+            ("c4:7c:8d:65:bd:8b", "impedance", adjustment(0.0, 1.0)),
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+// The tools share one config file. Each picks the parts it needs, and ignores the rest
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/hat/config.json"))
+}
+
+// Loads the given config file. Without one, the default location is used if it exists
+pub fn load<T: DeserializeOwned + Default>(path: Option<&Path>) -> Result<T, Box<dyn Error>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(T::default()),
+        },
+    };
+
+    debug!("loading config from {:?}", path);
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Cannot read config {:?}: {:}", path, e))?;
+
+    serde_json::from_str(&content)
+        .map_err(|e| Box::from(format!("Invalid config {:?}: {:}", path, e)))
+}
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+
+use crate::format::Format;
+
+// How a run failed. Each kind has its own exit code, and is the `kind` of the JSON error object
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Anything not covered below, e.g. an invalid config
+    Failed,
+    /// Device, adapter or GATT characteristic not found
+    NotFound,
+    Timeout,
+    ConnectFailed,
+    ReadFailed,
+    InvalidData,
+    PermissionDenied,
+    WriteFailed,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Failed => 1,
+            ErrorKind::NotFound => 2,
+            ErrorKind::Timeout => 3,
+            ErrorKind::ConnectFailed => 4,
+            ErrorKind::ReadFailed => 5,
+            ErrorKind::InvalidData => 6,
+            ErrorKind::PermissionDenied => 7,
+            ErrorKind::WriteFailed => 8,
+        }
+    }
+
+    pub fn of_io(kind: io::ErrorKind) -> ErrorKind {
+        match kind {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorKind::InvalidData,
+            _ => ErrorKind::Failed,
+        }
+    }
+
+    // The kind a D-Bus error name tells, if it tells more than what was being done when it happened
+    pub fn of_dbus_name(name: &str) -> Option<ErrorKind> {
+        match name {
+            "org.freedesktop.DBus.Error.AccessDenied" | "org.bluez.Error.NotAuthorized" | "org.bluez.Error.NotPermitted" => {
+                Some(ErrorKind::PermissionDenied)
+            }
+            "org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout" => Some(ErrorKind::Timeout),
+            _ => None,
+        }
+    }
+
+    // The kind a set of failures share, or Failed if they differ
+    pub fn common<I: IntoIterator<Item = ErrorKind>>(kinds: I) -> ErrorKind {
+        let mut kinds = kinds.into_iter();
+        let first = kinds.next().unwrap_or(ErrorKind::Failed);
+
+        if kinds.all(|kind| kind == first) {
+            first
+        } else {
+            ErrorKind::Failed
+        }
+    }
+}
+
+// An error that already knows its kind
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Failure {
+        Failure {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl Error for Failure {}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// Tells what kind of failure an error is. `known` knows the errors of the tool it is called from,
+// the ones every tool has are looked at here
+pub fn error_kind<F>(e: &(dyn Error + 'static), known: F) -> ErrorKind
+where
+    F: Fn(&(dyn Error + 'static)) -> Option<ErrorKind>,
+{
+    if let Some(e) = e.downcast_ref::<Failure>() {
+        e.kind
+    } else if let Some(kind) = known(e) {
+        kind
+    } else if let Some(e) = e.downcast_ref::<io::Error>() {
+        ErrorKind::of_io(e.kind())
+    } else {
+        ErrorKind::Failed
+    }
+}
+
+// What is printed when a run fails
+#[derive(Serialize)]
+pub struct ErrorReport {
+    pub error: String,
+    pub kind: ErrorKind,
+    pub exit_code: i32,
+}
+
+impl ErrorReport {
+    pub fn new(kind: ErrorKind, error: &dyn Error) -> ErrorReport {
+        ErrorReport {
+            error: error.to_string(),
+            kind,
+            exit_code: kind.exit_code(),
+        }
+    }
+
+    // Prints the report - as a JSON object on stdout when the output is JSON, so that scripts
+    // reading it get it too - and returns the exit code
+    pub fn print(&self, format: Format) -> i32 {
+        match serde_json::to_string(self) {
+            Ok(json) if format.is_json() => println!("{}", json),
+            _ => eprintln!("ERROR: {}", self.error),
+        }
+
+        self.exit_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [ErrorKind; 8] = [
+        ErrorKind::Failed,
+        ErrorKind::NotFound,
+        ErrorKind::Timeout,
+        ErrorKind::ConnectFailed,
+        ErrorKind::ReadFailed,
+        ErrorKind::InvalidData,
+        ErrorKind::PermissionDenied,
+        ErrorKind::WriteFailed,
+    ];
+
+    #[test]
+    fn every_kind_has_its_own_exit_code() {
+        let codes: Vec<i32> = KINDS.iter().map(|kind| kind.exit_code()).collect();
+
+        assert_eq!(codes, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn maps_io_errors() {
+        assert_eq!(ErrorKind::of_io(io::ErrorKind::NotFound), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of_io(io::ErrorKind::TimedOut), ErrorKind::Timeout);
+        assert_eq!(ErrorKind::of_io(io::ErrorKind::PermissionDenied), ErrorKind::PermissionDenied);
+        assert_eq!(ErrorKind::of_io(io::ErrorKind::UnexpectedEof), ErrorKind::InvalidData);
+        assert_eq!(ErrorKind::of_io(io::ErrorKind::BrokenPipe), ErrorKind::Failed);
+    }
+
+    #[test]
+    fn maps_dbus_error_names() {
+        assert_eq!(ErrorKind::of_dbus_name("org.bluez.Error.NotPermitted"), Some(ErrorKind::PermissionDenied));
+        assert_eq!(ErrorKind::of_dbus_name("org.freedesktop.DBus.Error.NoReply"), Some(ErrorKind::Timeout));
+        assert_eq!(ErrorKind::of_dbus_name("org.bluez.Error.Failed"), None);
+    }
+
+    #[test]
+    fn failures_of_one_kind_keep_it() {
+        assert_eq!(ErrorKind::common(vec![ErrorKind::Timeout, ErrorKind::Timeout]), ErrorKind::Timeout);
+        assert_eq!(ErrorKind::common(vec![ErrorKind::Timeout, ErrorKind::NotFound]), ErrorKind::Failed);
+        assert_eq!(ErrorKind::common(vec![]), ErrorKind::Failed);
+    }
+
+    #[test]
+    fn tells_kind_of_errors() {
+        let known = |e: &(dyn Error + 'static)| e.downcast_ref::<fmt::Error>().map(|_| ErrorKind::WriteFailed);
+        let kind = |e: Box<dyn Error>| error_kind(&*e, known);
+
+        assert_eq!(kind(Box::new(Failure::new(ErrorKind::NotFound, "Device not found"))), ErrorKind::NotFound);
+        assert_eq!(kind(Box::new(fmt::Error)), ErrorKind::WriteFailed);
+        assert_eq!(kind(Box::new(io::Error::new(io::ErrorKind::TimedOut, "Deadline passed"))), ErrorKind::Timeout);
+        assert_eq!(kind(Box::from("Invalid config")), ErrorKind::Failed);
+    }
+
+    #[test]
+    fn reports_kind_and_exit_code() {
+        let report = ErrorReport::new(ErrorKind::WriteFailed, &Failure::new(ErrorKind::WriteFailed, "Write failed"));
+
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"error":"Write failed","kind":"write_failed","exit_code":8}"#
+        );
+    }
+}
@@ -0,0 +1,751 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::time::FORMAT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    /// One JSON array with every record
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    Csv,
+    /// InfluxDB line protocol
+    Influx,
+    /// Prometheus textfile collector format
+    Prometheus,
+}
+
+impl Format {
+    pub fn is_json(self) -> bool {
+        self == Format::Json || self == Format::Ndjson
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            "influx" => Ok(Format::Influx),
+            "prometheus" => Ok(Format::Prometheus),
+            _ => Err(format!(
+                "unknown format '{}' (expected table, json, ndjson, csv, influx or prometheus)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    None,
+    Celsius,
+    Lux,
+    Percent,
+    MicroSiemensPerCm,
+    Kilograms,
+    Ohms,
+    Seconds,
+    Dbm,
+}
+
+impl Unit {
+    // Appended to metric names (influx, prometheus, csv)
+    fn suffix(self) -> Option<&'static str> {
+        match self {
+            Unit::None => None,
+            Unit::Celsius => Some("celsius"),
+            Unit::Lux => Some("lux"),
+            Unit::Percent => Some("percent"),
+            Unit::MicroSiemensPerCm => Some("microsiemens_per_cm"),
+            Unit::Kilograms => Some("kilograms"),
+            Unit::Ohms => Some("ohms"),
+            Unit::Seconds => Some("seconds"),
+            Unit::Dbm => Some("dbm"),
+        }
+    }
+
+    // Shown after values in tables
+    fn symbol(self) -> &'static str {
+        match self {
+            Unit::None => "",
+            Unit::Celsius => "°C",
+            Unit::Lux => "lux",
+            Unit::Percent => "%",
+            Unit::MicroSiemensPerCm => "µS/cm",
+            Unit::Kilograms => "kg",
+            Unit::Ohms => "Ω",
+            Unit::Seconds => "s",
+            Unit::Dbm => "dBm",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Float(f32),
+    Int(i64),
+    Missing,
+}
+
+impl Value {
+    fn display(self) -> Option<String> {
+        match self {
+            Value::Float(v) => Some(v.to_string()),
+            Value::Int(v) => Some(v.to_string()),
+            Value::Missing => None,
+        }
+    }
+}
+
+pub struct Field {
+    /// Same as the JSON key of the value
+    pub name: &'static str,
+    pub unit: Unit,
+    pub value: Value,
+}
+
+impl Field {
+    pub fn new(name: &'static str, unit: Unit, value: Value) -> Field {
+        Field { name, unit, value }
+    }
+
+    // The unit is appended, unless the name already is the unit (lux). battery_pct is already
+    // named after its unit, so that is replaced by the proper suffix
+    fn metric_name(&self) -> String {
+        let name = self.name.trim_end_matches("_pct");
+
+        match self.unit.suffix() {
+            Some(suffix) if !name.ends_with(suffix) => format!("{}_{}", name, suffix),
+            _ => name.to_string(),
+        }
+    }
+}
+
+// Something that can be output in every format. JSON uses the serde layout of the record, the
+// other formats are built from the tags and fields
+pub trait Record: Serialize {
+    /// Measurement name (influx) and metric prefix (prometheus)
+    fn measurement(&self) -> &'static str;
+    fn datetime(&self) -> DateTime<Local>;
+    fn address(&self) -> &str;
+    fn alias(&self) -> Option<&str> {
+        None
+    }
+    /// Extra columns for table and csv, that aren't metrics (firmware version, record number)
+    fn info(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+    /// Every info column a record like this one can have, in order. The header is written before
+    /// later records are seen, so it needs the ones this record hasn't got as well
+    fn info_names(&self) -> Vec<&'static str> {
+        self.info().into_iter().map(|(name, _)| name).collect()
+    }
+    fn fields(&self) -> Vec<Field>;
+}
+
+// Shortest alias column of a table
+const ALIAS_WIDTH: usize = 12;
+
+// The columns of a table or csv, fixed by the first record so that later rows line up with the
+// header. Fields the first record didn't have are left out, missing values are empty
+struct Columns {
+    /// Width of the alias column, if there is one
+    alias: Option<usize>,
+    /// Name and width
+    info: Vec<(&'static str, usize)>,
+    /// Name and unit, the value is always missing
+    fields: Vec<Field>,
+}
+
+impl Columns {
+    fn of<R: Record>(record: &R, alias: bool, fields: &[Field]) -> Columns {
+        let alias = match record.alias() {
+            Some(a) => Some(a.chars().count().max(ALIAS_WIDTH)),
+            None if alias => Some(ALIAS_WIDTH),
+            None => None,
+        };
+
+        let info = record.info();
+
+        Columns {
+            alias,
+            info: record
+                .info_names()
+                .into_iter()
+                .map(|name| {
+                    let value = info.iter().find(|(n, _)| *n == name).map_or(0, |(_, value)| value.chars().count());
+
+                    (name, name.len().max(value))
+                })
+                .collect(),
+            fields: fields.iter().map(|f| Field::new(f.name, f.unit, Value::Missing)).collect(),
+        }
+    }
+
+    // The values of the fields of a record, in the order of the columns
+    fn values(&self, mut fields: Vec<Field>) -> Vec<Value> {
+        self.fields
+            .iter()
+            .map(|column| match fields.iter().position(|f| f.name == column.name) {
+                Some(i) => fields.swap_remove(i).value,
+                None => Value::Missing,
+            })
+            .collect()
+    }
+
+    // The info values of a record, in the order of the columns
+    fn info<R: Record>(&self, record: &R) -> Vec<Option<String>> {
+        let mut info = record.info();
+
+        self.info
+            .iter()
+            .map(|(name, _)| {
+                info.iter()
+                    .position(|(n, _)| n == name)
+                    .map(|i| info.swap_remove(i).1)
+            })
+            .collect()
+    }
+}
+
+struct Sample {
+    help: String,
+    // labels -> value. Prometheus only has room for one value per series, so the last one wins
+    series: BTreeMap<String, String>,
+}
+
+pub struct Formatter<W: Write> {
+    out: W,
+    format: Format,
+    headers: bool,
+    selected: Vec<&'static str>,
+    alias: bool,
+    columns: Option<Columns>,
+    json: Vec<String>,
+    prometheus: BTreeMap<String, Sample>,
+}
+
+impl Formatter<io::Stdout> {
+    pub fn stdout(format: Format, headers: bool) -> Formatter<io::Stdout> {
+        Formatter::new(io::stdout(), format, headers)
+    }
+}
+
+impl<W: Write> Formatter<W> {
+    pub fn new(out: W, format: Format, headers: bool) -> Formatter<W> {
+        Formatter {
+            out,
+            format,
+            headers,
+            selected: Vec::new(),
+            alias: false,
+            columns: None,
+            json: Vec::new(),
+            prometheus: BTreeMap::new(),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    // Only output these fields (all of them if empty)
+    pub fn select(&mut self, fields: Vec<&'static str>) {
+        self.selected = fields;
+    }
+
+    // Have an alias column in table and csv even if the first record has no alias, as records of
+    // several devices may follow
+    pub fn alias_column(&mut self, alias: bool) {
+        self.alias = alias;
+    }
+
+    fn selected_fields<R: Record>(&self, record: &R) -> Vec<Field> {
+        record
+            .fields()
+            .into_iter()
+            .filter(|f| self.selected.is_empty() || self.selected.contains(&f.name))
+            .collect()
+    }
+
+    pub fn write<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            Format::Table => self.write_table(record),
+            Format::Json | Format::Ndjson => self.write_json(record),
+            Format::Csv => self.write_csv(record),
+            Format::Influx => self.write_influx(record),
+            Format::Prometheus => self.write_prometheus(record),
+        }
+    }
+
+    // Writes a record that isn't a reading (an error for example). Only JSON formats have room
+    // for those - returns false for the others
+    pub fn write_other<T: Serialize>(&mut self, value: &T) -> Result<bool, Box<dyn Error>> {
+        if !self.format.is_json() {
+            return Ok(false);
+        }
+
+        self.write_json_line(serde_json::to_string(value)?)?;
+
+        Ok(true)
+    }
+
+    // Writes whatever the format had to hold back until every record was seen
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            Format::Json => {
+                writeln!(self.out, "[{}]", self.json.join(","))?;
+                self.json.clear();
+            }
+            Format::Prometheus => {
+                for (name, sample) in &self.prometheus {
+                    writeln!(self.out, "# HELP {} {}", name, sample.help)?;
+                    writeln!(self.out, "# TYPE {} gauge", name)?;
+
+                    for (labels, value) in &sample.series {
+                        writeln!(self.out, "{}{{{}}} {}", name, labels, value)?;
+                    }
+                }
+                self.prometheus.clear();
+            }
+            _ => (),
+        }
+
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn write_json<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        // going through a serde_json::Value sorts the keys, so only do that when fields must go
+        if self.selected.is_empty() {
+            return self.write_json_line(serde_json::to_string(record)?);
+        }
+
+        let mut value = serde_json::to_value(record)?;
+
+        if let Some(object) = value.as_object_mut() {
+            for field in record.fields() {
+                if !self.selected.contains(&field.name) {
+                    object.remove(field.name);
+                }
+            }
+        }
+
+        self.write_json_line(serde_json::to_string(&value)?)
+    }
+
+    fn write_json_line(&mut self, line: String) -> Result<(), Box<dyn Error>> {
+        if self.format == Format::Json {
+            self.json.push(line);
+        } else {
+            writeln!(self.out, "{}", line)?;
+            self.out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_table<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        let fields = self.selected_fields(record);
+
+        if self.columns.is_none() {
+            let columns = Columns::of(record, self.alias, &fields);
+
+            if self.headers {
+                let mut header = format!("{:19} {:17}", "datetime", "address");
+
+                if let Some(width) = columns.alias {
+                    header += &format!(" {:width$}", "alias", width = width);
+                }
+                for (name, width) in &columns.info {
+                    header += &format!(" {:>width$}", name, width = width);
+                }
+                for field in &columns.fields {
+                    header += &format!(" {:>width$}", field.name, width = table_width(field));
+                }
+
+                writeln!(self.out, "{}", header.trim_end())?;
+            }
+
+            self.columns = Some(columns);
+        }
+
+        let columns = self.columns.as_ref().expect("columns are set above");
+        let mut line = format!(
+            "{:19} {:17}",
+            record.datetime().format(FORMAT).to_string(),
+            record.address()
+        );
+
+        if let Some(width) = columns.alias {
+            line += &format!(" {:width$}", record.alias().unwrap_or("-"), width = width);
+        }
+        for ((_, width), value) in columns.info.iter().zip(columns.info(record)) {
+            line += &format!(" {:>width$}", value.as_deref().unwrap_or("-"), width = width);
+        }
+        for (field, value) in columns.fields.iter().zip(columns.values(fields)) {
+            let value = match value.display() {
+                Some(value) => format!("{} {}", value, field.unit.symbol()),
+                None => "-".to_string(),
+            };
+
+            line += &format!(" {:>width$}", value.trim_end(), width = table_width(field));
+        }
+
+        writeln!(self.out, "{}", line.trim_end())?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn write_csv<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        let fields = self.selected_fields(record);
+
+        if self.columns.is_none() {
+            let columns = Columns::of(record, true, &fields);
+
+            if self.headers {
+                let mut header = vec!["datetime".to_string(), "address".to_string(), "alias".to_string()];
+
+                header.extend(columns.info.iter().map(|(name, _)| name.to_string()));
+                header.extend(columns.fields.iter().map(Field::metric_name));
+
+                writeln!(self.out, "{}", header.join(","))?;
+            }
+
+            self.columns = Some(columns);
+        }
+
+        let mut line = vec![
+            record.datetime().format(FORMAT).to_string(),
+            csv_escape(record.address()),
+            csv_escape(record.alias().unwrap_or("")),
+        ];
+
+        let columns = self.columns.as_ref().expect("columns are set above");
+
+        line.extend(columns.info(record).iter().map(|value| csv_escape(value.as_deref().unwrap_or(""))));
+        line.extend(columns.values(fields).into_iter().map(|value| value.display().unwrap_or_default()));
+
+        writeln!(self.out, "{}", line.join(","))?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn write_influx<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        let values: Vec<String> = self
+            .selected_fields(record)
+            .iter()
+            .filter_map(|field| {
+                let name = influx_escape(&field.metric_name());
+
+                match field.value {
+                    Value::Float(v) => Some(format!("{}={}", name, v)),
+                    Value::Int(v) => Some(format!("{}={}i", name, v)),
+                    Value::Missing => None,
+                }
+            })
+            .collect();
+
+        // a line without fields isn't valid
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        let mut tags = format!("address={}", influx_escape(record.address()));
+
+        if let Some(alias) = record.alias() {
+            tags += &format!(",alias={}", influx_escape(alias));
+        }
+
+        writeln!(
+            self.out,
+            "{},{} {} {}",
+            influx_escape(record.measurement()),
+            tags,
+            values.join(","),
+            record.datetime().timestamp_nanos()
+        )?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn write_prometheus<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        let mut labels = format!("address=\"{}\"", prometheus_escape(record.address()));
+
+        if let Some(alias) = record.alias() {
+            labels += &format!(",alias=\"{}\"", prometheus_escape(alias));
+        }
+
+        for field in self.selected_fields(record) {
+            let value = match field.value.display() {
+                Some(value) => value,
+                None => continue,
+            };
+            let name = format!("{}_{}", record.measurement(), field.metric_name());
+            let sample = self.prometheus.entry(name).or_insert_with(|| Sample {
+                help: match field.unit {
+                    Unit::None => format!("{} {}", record.measurement(), field.name),
+                    unit => format!("{} {} ({})", record.measurement(), field.name, unit.symbol()),
+                },
+                series: BTreeMap::new(),
+            });
+
+            sample.series.insert(labels.clone(), value);
+        }
+
+        Ok(())
+    }
+}
+
+fn table_width(field: &Field) -> usize {
+    field.name.len().max(10)
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn influx_escape(s: &str) -> String {
+    s.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn prometheus_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[derive(Serialize)]
+    struct Reading {
+        address: String,
+        alias: Option<String>,
+        firmware_version: String,
+        calibrated: bool,
+        temperature: f32,
+        raw_temperature: Option<f32>,
+    }
+
+    impl Reading {
+        fn new(address: &str, alias: Option<&str>, firmware_version: &str) -> Reading {
+            Reading {
+                address: address.to_string(),
+                alias: alias.map(String::from),
+                firmware_version: firmware_version.to_string(),
+                calibrated: false,
+                temperature: 21.5,
+                raw_temperature: None,
+            }
+        }
+
+        fn calibrated(mut self) -> Reading {
+            self.calibrated = true;
+            self
+        }
+
+        fn raw(mut self, raw_temperature: f32) -> Reading {
+            self.raw_temperature = Some(raw_temperature);
+            self
+        }
+    }
+
+    impl Record for Reading {
+        fn measurement(&self) -> &'static str {
+            "plant"
+        }
+
+        fn datetime(&self) -> DateTime<Local> {
+            Local.timestamp(1_574_589_731, 0)
+        }
+
+        fn address(&self) -> &str {
+            &self.address
+        }
+
+        fn alias(&self) -> Option<&str> {
+            self.alias.as_deref()
+        }
+
+        fn info(&self) -> Vec<(&'static str, String)> {
+            let mut info = vec![("firmware_version", self.firmware_version.clone())];
+
+            if self.calibrated {
+                info.push(("calibrated", "true".to_string()));
+            }
+
+            info
+        }
+
+        fn info_names(&self) -> Vec<&'static str> {
+            vec!["firmware_version", "calibrated"]
+        }
+
+        fn fields(&self) -> Vec<Field> {
+            let mut fields = vec![Field::new("temperature", Unit::Celsius, Value::Float(self.temperature))];
+
+            fields.extend(self.raw_temperature.map(|raw| Field::new("raw_temperature", Unit::Celsius, Value::Float(raw))));
+            fields
+        }
+    }
+
+    fn output(format: Format, alias: bool, records: &[Reading]) -> String {
+        let mut formatter = Formatter::new(Vec::new(), format, true);
+
+        formatter.alias_column(alias);
+
+        for record in records {
+            formatter.write(record).unwrap();
+        }
+        formatter.finish().unwrap();
+
+        String::from_utf8(formatter.out).unwrap()
+    }
+
+    #[test]
+    fn table_rows_line_up_with_header() {
+        let table = output(
+            Format::Table,
+            true,
+            &[
+                Reading::new("C4:7C:8D:67:C2:8B", None, "3.1.9"),
+                Reading::new("C4:7C:8D:65:BD:8B", Some("a-very-long-alias"), "3.10.100"),
+            ],
+        );
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains(" alias "));
+        // The first record has no alias, but the column is there for the second one. Values are
+        // right aligned, so a row that lines up ends where the header does
+        assert_eq!(lines[1].chars().count(), lines[0].chars().count());
+        assert!(lines[1].contains(" -            "));
+        assert!(lines[2].contains(" a-very-long-alias "));
+    }
+
+    #[test]
+    fn header_has_info_columns_of_later_records() {
+        let records = [
+            Reading::new("C4:7C:8D:67:C2:8B", None, "3.1.9"),
+            Reading::new("C4:7C:8D:65:BD:8B", None, "3.1.9").calibrated(),
+        ];
+        let datetime = Local.timestamp(1_574_589_731, 0).format(FORMAT);
+
+        assert_eq!(
+            output(Format::Csv, false, &records),
+            format!(
+                "datetime,address,alias,firmware_version,calibrated,temperature_celsius\n\
+                 {0},C4:7C:8D:67:C2:8B,,3.1.9,,21.5\n\
+                 {0},C4:7C:8D:65:BD:8B,,3.1.9,true,21.5\n",
+                datetime
+            )
+        );
+
+        let table = output(Format::Table, false, &records);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert!(lines[0].contains(" calibrated "));
+        assert_eq!(lines[1].chars().count(), lines[0].chars().count());
+        assert_eq!(lines[2].chars().count(), lines[0].chars().count());
+        assert!(lines[2].contains(" true "));
+    }
+
+    #[test]
+    fn rows_keep_the_fields_of_the_header() {
+        let records = [
+            Reading::new("C4:7C:8D:67:C2:8B", None, "3.1.9").raw(20.5),
+            Reading::new("C4:7C:8D:65:BD:8B", None, "3.1.9"),
+        ];
+        let datetime = Local.timestamp(1_574_589_731, 0).format(FORMAT);
+
+        assert_eq!(
+            output(Format::Csv, false, &records),
+            format!(
+                "datetime,address,alias,firmware_version,calibrated,temperature_celsius,raw_temperature_celsius\n\
+                 {0},C4:7C:8D:67:C2:8B,,3.1.9,,21.5,20.5\n\
+                 {0},C4:7C:8D:65:BD:8B,,3.1.9,,21.5,\n",
+                datetime
+            )
+        );
+
+        // a field the first record didn't have has no column to go in
+        let records = [
+            Reading::new("C4:7C:8D:67:C2:8B", None, "3.1.9"),
+            Reading::new("C4:7C:8D:65:BD:8B", None, "3.1.9").raw(20.5),
+        ];
+        let table = output(Format::Table, false, &records);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].chars().count(), lines[0].chars().count());
+        assert_eq!(lines[2].chars().count(), lines[0].chars().count());
+    }
+
+    #[test]
+    fn csv_quotes_separators() {
+        assert_eq!(csv_escape("basil"), "basil");
+        assert_eq!(csv_escape("basil, left"), "\"basil, left\"");
+        assert_eq!(csv_escape("the \"big\" one"), "\"the \"\"big\"\" one\"");
+        assert_eq!(csv_escape("two\nlines"), "\"two\nlines\"");
+
+        let csv = output(Format::Csv, false, &[Reading::new("C4:7C:8D:67:C2:8B", Some("basil, left"), "3.1.9")]);
+
+        let datetime = Local.timestamp(1_574_589_731, 0).format(FORMAT);
+
+        assert_eq!(
+            csv,
+            format!(
+                "datetime,address,alias,firmware_version,calibrated,temperature_celsius\n\
+                 {},C4:7C:8D:67:C2:8B,\"basil, left\",3.1.9,,21.5\n",
+                datetime
+            )
+        );
+    }
+
+    #[test]
+    fn influx_escapes_tags() {
+        assert_eq!(influx_escape("living room,left=1"), "living\\ room\\,left\\=1");
+
+        let influx = output(Format::Influx, false, &[Reading::new("C4:7C:8D:67:C2:8B", Some("big basil"), "3.1.9")]);
+
+        assert_eq!(
+            influx,
+            "plant,address=C4:7C:8D:67:C2:8B,alias=big\\ basil temperature_celsius=21.5 1574589731000000000\n"
+        );
+    }
+
+    #[test]
+    fn prometheus_escapes_labels() {
+        assert_eq!(prometheus_escape("the \"big\" one\\\n"), "the \\\"big\\\" one\\\\\\n");
+
+        let prometheus = output(Format::Prometheus, false, &[Reading::new("C4:7C:8D:67:C2:8B", Some("\"basil\""), "3.1.9")]);
+
+        assert_eq!(
+            prometheus,
+            "# HELP plant_temperature_celsius plant temperature (°C)\n\
+             # TYPE plant_temperature_celsius gauge\n\
+             plant_temperature_celsius{address=\"C4:7C:8D:67:C2:8B\",alias=\"\\\"basil\\\"\"} 21.5\n"
+        );
+    }
+}
@@ -0,0 +1,10 @@
+#[macro_use]
+extern crate log;
+
+pub mod alias;
+pub mod calibration;
+pub mod config;
+pub mod exit;
+pub mod format;
+pub mod store;
+pub mod time;
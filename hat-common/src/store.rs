@@ -0,0 +1,413 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use rusqlite::types::ToSql;
+use rusqlite::{params, params_from_iter, Connection, Result, Row, TransactionBehavior};
+
+use crate::time::from_unix;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS miflora_realtime (
+    address TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    battery_pct INTEGER NOT NULL,
+    firmware_version TEXT NOT NULL,
+    temperature REAL NOT NULL,
+    lux INTEGER,
+    moisture INTEGER NOT NULL,
+    conductivity INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS miflora_realtime_datetime ON miflora_realtime (address, datetime);
+
+CREATE TABLE IF NOT EXISTS miflora_history (
+    address TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    record_number INTEGER NOT NULL,
+    total_records INTEGER NOT NULL,
+    temperature REAL NOT NULL,
+    lux INTEGER,
+    moisture INTEGER NOT NULL,
+    conductivity INTEGER NOT NULL,
+    PRIMARY KEY (address, datetime)
+);
+
+CREATE TABLE IF NOT EXISTS scale (
+    address TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    weight REAL,
+    impedance INTEGER
+);
+CREATE INDEX IF NOT EXISTS scale_datetime ON scale (address, datetime);
+
+PRAGMA user_version = 1;
+";
+
+// Stores from before Flower pots (which have no light sensor) require lux. SQLite can't drop a
+// NOT NULL constraint, so the tables are copied
+const NULLABLE_LUX: &str = "
+CREATE TABLE miflora_realtime_new (
+    address TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    battery_pct INTEGER NOT NULL,
+    firmware_version TEXT NOT NULL,
+    temperature REAL NOT NULL,
+    lux INTEGER,
+    moisture INTEGER NOT NULL,
+    conductivity INTEGER NOT NULL
+);
+INSERT INTO miflora_realtime_new SELECT address, datetime, battery_pct, firmware_version, temperature, lux, moisture, conductivity FROM miflora_realtime;
+DROP TABLE miflora_realtime;
+ALTER TABLE miflora_realtime_new RENAME TO miflora_realtime;
+CREATE INDEX miflora_realtime_datetime ON miflora_realtime (address, datetime);
+
+CREATE TABLE miflora_history_new (
+    address TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    record_number INTEGER NOT NULL,
+    total_records INTEGER NOT NULL,
+    temperature REAL NOT NULL,
+    lux INTEGER,
+    moisture INTEGER NOT NULL,
+    conductivity INTEGER NOT NULL,
+    PRIMARY KEY (address, datetime)
+);
+INSERT INTO miflora_history_new SELECT address, datetime, record_number, total_records, temperature, lux, moisture, conductivity FROM miflora_history;
+DROP TABLE miflora_history;
+ALTER TABLE miflora_history_new RENAME TO miflora_history;
+";
+
+pub struct RealtimeRow {
+    pub address: String,
+    pub datetime: DateTime<Local>,
+    pub battery_pct: u8,
+    pub firmware_version: String,
+    pub temperature: f32,
+    /// None for models without a light sensor
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+}
+
+pub struct HistoryRow {
+    pub address: String,
+    pub datetime: DateTime<Local>,
+    pub record_number: u16,
+    pub total_records: u16,
+    pub temperature: f32,
+    /// None for models without a light sensor
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+}
+
+pub struct WeightRow {
+    pub address: String,
+    pub datetime: DateTime<Local>,
+    pub weight: Option<f32>,
+    pub impedance: Option<u16>,
+}
+
+#[derive(Default)]
+pub struct Filter {
+    /// Only these addresses (all if empty)
+    pub addresses: Vec<String>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+}
+
+impl Filter {
+    fn to_sql(&self) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+        if !self.addresses.is_empty() {
+            let placeholders = vec!["?"; self.addresses.len()].join(", ");
+            conditions.push(format!("address IN ({})", placeholders));
+
+            for address in &self.addresses {
+                values.push(Box::new(address.clone()));
+            }
+        }
+
+        if let Some(since) = self.since {
+            conditions.push("datetime >= ?".to_string());
+            values.push(Box::new(since.timestamp()));
+        }
+
+        if let Some(until) = self.until {
+            conditions.push("datetime <= ?".to_string());
+            values.push(Box::new(until.timestamp()));
+        }
+
+        let sql = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        (sql, values)
+    }
+}
+
+// SQLite store for everything the hat tools read. History records are stored once per
+// (address, datetime), so reading the same records again doesn't create duplicates
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Store> {
+        debug!("opening store {:?}", path);
+
+        Store::init(Connection::open(path)?)
+    }
+
+    // Creates or migrates the tables. Processes opening the same store at once (a new one, say)
+    // take turns: the write lock is taken before the version is read
+    fn init(mut conn: Connection) -> Result<Store> {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version: i32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version < 1 {
+            let tables: i32 = tx.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'miflora_realtime'",
+                [],
+                |row| row.get(0),
+            )?;
+
+            if tables > 0 {
+                debug!("migrating store to version 1");
+                tx.execute_batch(NULLABLE_LUX)?;
+            }
+
+            tx.execute_batch(SCHEMA)?;
+        }
+
+        tx.commit()?;
+
+        Ok(Store { conn })
+    }
+
+    pub fn insert_realtime(&self, row: &RealtimeRow) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO miflora_realtime (address, datetime, battery_pct, firmware_version, temperature, lux, moisture, conductivity)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                row.address,
+                row.datetime.timestamp(),
+                row.battery_pct,
+                row.firmware_version,
+                row.temperature,
+                row.lux,
+                row.moisture,
+                row.conductivity
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Returns false if the record was already stored
+    pub fn insert_history(&self, row: &HistoryRow) -> Result<bool> {
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO miflora_history (address, datetime, record_number, total_records, temperature, lux, moisture, conductivity)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                row.address,
+                row.datetime.timestamp(),
+                row.record_number,
+                row.total_records,
+                row.temperature,
+                row.lux,
+                row.moisture,
+                row.conductivity
+            ],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    pub fn insert_weight(&self, row: &WeightRow) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO scale (address, datetime, weight, impedance) VALUES (?1, ?2, ?3, ?4)",
+            params![row.address, row.datetime.timestamp(), row.weight, row.impedance],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn realtime(&self, filter: &Filter) -> Result<Vec<RealtimeRow>> {
+        self.select(
+            "SELECT address, datetime, battery_pct, firmware_version, temperature, lux, moisture, conductivity FROM miflora_realtime",
+            filter,
+            |row| {
+                Ok(RealtimeRow {
+                    address: row.get(0)?,
+                    datetime: from_unix(row.get(1)?),
+                    battery_pct: row.get(2)?,
+                    firmware_version: row.get(3)?,
+                    temperature: row.get(4)?,
+                    lux: row.get(5)?,
+                    moisture: row.get(6)?,
+                    conductivity: row.get(7)?,
+                })
+            },
+        )
+    }
+
+    pub fn history(&self, filter: &Filter) -> Result<Vec<HistoryRow>> {
+        self.select(
+            "SELECT address, datetime, record_number, total_records, temperature, lux, moisture, conductivity FROM miflora_history",
+            filter,
+            |row| {
+                Ok(HistoryRow {
+                    address: row.get(0)?,
+                    datetime: from_unix(row.get(1)?),
+                    record_number: row.get(2)?,
+                    total_records: row.get(3)?,
+                    temperature: row.get(4)?,
+                    lux: row.get(5)?,
+                    moisture: row.get(6)?,
+                    conductivity: row.get(7)?,
+                })
+            },
+        )
+    }
+
+    pub fn weights(&self, filter: &Filter) -> Result<Vec<WeightRow>> {
+        self.select(
+            "SELECT address, datetime, weight, impedance FROM scale",
+            filter,
+            |row| {
+                Ok(WeightRow {
+                    address: row.get(0)?,
+                    datetime: from_unix(row.get(1)?),
+                    weight: row.get(2)?,
+                    impedance: row.get(3)?,
+                })
+            },
+        )
+    }
+
+    fn select<T, F>(&self, select: &str, filter: &Filter, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        let (condition, values) = filter.to_sql();
+        let sql = format!("{} {} ORDER BY datetime, address", select, condition);
+
+        debug!("query: {}", sql);
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(values.iter()), f)?;
+
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        Store::init(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn history(address: &str, datetime: i64, record_number: u16) -> HistoryRow {
+        HistoryRow {
+            address: address.to_string(),
+            datetime: from_unix(datetime),
+            record_number,
+            total_records: 3,
+            temperature: 23.9,
+            lux: Some(112),
+            moisture: 41,
+            conductivity: 498,
+        }
+    }
+
+    fn version(store: &Store) -> i32 {
+        store.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn new_store_is_current() {
+        assert_eq!(version(&store()), 1);
+    }
+
+    #[test]
+    fn history_is_stored_once() {
+        let store = store();
+
+        assert!(store.insert_history(&history("C4:7C:8D:67:C2:8B", 3600, 0)).unwrap());
+        assert!(!store.insert_history(&history("C4:7C:8D:67:C2:8B", 3600, 0)).unwrap());
+        assert!(store.insert_history(&history("C4:7C:8D:65:BD:8B", 3600, 0)).unwrap());
+
+        assert_eq!(store.history(&Filter::default()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn filters_by_address_and_time() {
+        let store = store();
+
+        for (address, datetime) in &[("C4:7C:8D:67:C2:8B", 3600), ("C4:7C:8D:67:C2:8B", 7200), ("C4:7C:8D:65:BD:8B", 7200)] {
+            store
+                .insert_weight(&WeightRow {
+                    address: address.to_string(),
+                    datetime: from_unix(*datetime),
+                    weight: Some(72.5),
+                    impedance: None,
+                })
+                .unwrap();
+        }
+
+        let filter = Filter {
+            addresses: vec!["C4:7C:8D:67:C2:8B".to_string()],
+            since: Some(from_unix(7200)),
+            until: None,
+        };
+        let rows = store.weights(&filter).unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].address.as_str(), rows[0].datetime.timestamp()), ("C4:7C:8D:67:C2:8B", 7200));
+
+        let filter = Filter {
+            until: Some(from_unix(3600)),
+            ..Filter::default()
+        };
+
+        assert_eq!(store.weights(&filter).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn migrates_stores_requiring_lux() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch(&SCHEMA.replace("lux INTEGER,", "lux INTEGER NOT NULL,").replace("PRAGMA user_version = 1;", ""))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO miflora_realtime VALUES ('C4:7C:8D:67:C2:8B', 3600, 98, '3.1.9', 23.9, 112, 41, 498)",
+            [],
+        )
+        .unwrap();
+
+        let store = Store::init(conn).unwrap();
+
+        assert_eq!(version(&store), 1);
+        assert_eq!(store.realtime(&Filter::default()).unwrap()[0].lux, Some(112));
+
+        store
+            .insert_realtime(&RealtimeRow {
+                address: "C4:7C:8D:65:BD:8B".to_string(),
+                datetime: from_unix(7200),
+                battery_pct: 100,
+                firmware_version: "1.0.0".to_string(),
+                temperature: 21.0,
+                lux: None,
+                moisture: 30,
+                conductivity: 350,
+            })
+            .unwrap();
+        assert!(store.insert_history(&HistoryRow { lux: None, ..history("C4:7C:8D:65:BD:8B", 7200, 0) }).unwrap());
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+pub const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Parses a local time given as "2019-05-13 16:03:35", "2019-05-13T16:03:35" or "2019-05-13"
+pub fn parse_datetime(s: &str) -> Result<DateTime<Local>, String> {
+    let naive = NaiveDateTime::parse_from_str(s, FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("invalid time '{}' (expected YYYY-MM-DD [HH:MM:SS])", s))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .single()
+        .ok_or_else(|| format!("ambiguous local time '{}'", s))
+}
+
+// Parses a duration given as a number and a unit: "90s", "30m", "24h", "7d" or "2w"
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let error = || format!("invalid duration '{}' (expected e.g. 30m, 24h or 7d)", s);
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?);
+    let unit_sec: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit_sec))
+        .map(Duration::from_secs)
+        .ok_or_else(error)
+}
+
+// Unix time is never ambiguous in local time, so this can't fail
+pub fn from_unix(secs: i64) -> DateTime<Local> {
+    Local.timestamp_opt(secs, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_in_every_unit() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("0h"), Ok(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in &["", "24", "h", "24x", "24 h", "-1h", "1.5h", "24H", "1h30m"] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert!(parse_duration("18446744073709551615s").is_ok());
+        assert!(parse_duration("18446744073709551616s").is_err());
+        assert!(parse_duration("30500568904944w").is_err());
+    }
+
+    #[test]
+    fn parses_datetimes() {
+        let expected = Local.from_local_datetime(&NaiveDate::from_ymd_opt(2026, 10, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()).single();
+
+        assert_eq!(parse_datetime("2026-10-01").ok(), expected);
+        assert_eq!(parse_datetime("2026-10-01 00:00:00").ok(), expected);
+        assert_eq!(parse_datetime("2026-10-01T00:00:00").ok(), expected);
+        assert!(parse_datetime("01-10-2026").is_err());
+    }
+}
@@ -1,5 +1,11 @@
+use std::path::PathBuf;
+
+use hat_common::format::Format;
 use structopt::StructOpt;
 
+use crate::plausibility::{RejectedAction, Stabilization};
+use crate::query::Metric;
+
 #[derive(StructOpt)]
 pub struct Cli {
     /// Wait for data and exit when received
@@ -8,4 +14,68 @@ pub struct Cli {
     /// How many seconds should it wait for weight data. 0 is forever
     #[structopt(short = "s", long = "seconds", default_value = "60")]
     pub duration: u64,
+    /// Reject weights below this (kg)
+    #[structopt(long = "min-weight")]
+    pub min_weight: Option<f32>,
+    /// Reject weights above this (kg)
+    #[structopt(long = "max-weight")]
+    pub max_weight: Option<f32>,
+    /// Reject weights differing more than this from the last accepted weight of the user (kg)
+    #[structopt(long = "max-change")]
+    pub max_change: Option<f32>,
+    /// Reject impedance below this
+    #[structopt(long = "min-impedance")]
+    pub min_impedance: Option<u16>,
+    /// Reject impedance above this
+    #[structopt(long = "max-impedance")]
+    pub max_impedance: Option<u16>,
+    /// Stabilization flags required: none, weight, impedance or both
+    #[structopt(long = "require-stabilized", default_value = "weight")]
+    pub require_stabilized: Stabilization,
+    /// What to do with rejected weigh-ins: drop, or emit with a rejected_reason
+    #[structopt(long = "rejected", default_value = "drop")]
+    pub rejected: RejectedAction,
+    /// File remembering the last accepted weight per user (used by --max-change)
+    #[structopt(long = "state-file", parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+    /// Output format: table, json, ndjson, csv, influx or prometheus
+    #[structopt(short = "f", long = "format", default_value = "ndjson")]
+    pub format: Format,
+    /// Don't show headers (table and csv only)
+    #[structopt(short = "H", long = "no-headers")]
+    pub no_headers: bool,
+    /// Config file (defaults to ~/.config/hat/config.json)
+    #[structopt(short = "C", long = "config", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Also output the values from before calibration
+    #[structopt(long = "raw")]
+    pub raw: bool,
+    /// Store accepted weigh-ins in this SQLite database
+    #[structopt(short = "S", long = "store", parse(from_os_str))]
+    pub store: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Show weigh-ins kept in the store (instead of listening for new ones)
+    #[structopt(name = "query")]
+    Query(QueryOpts),
+}
+
+#[derive(StructOpt)]
+pub struct QueryOpts {
+    /// Only weigh-ins from this address or alias (can be repeated)
+    #[structopt(short = "a", long = "address")]
+    pub addresses: Vec<String>,
+    /// Only show these metrics: weight or impedance (can be repeated)
+    #[structopt(short = "m", long = "metric")]
+    pub metrics: Vec<Metric>,
+    /// Only weigh-ins from this time or later (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long = "since")]
+    pub since: Option<String>,
+    /// Only weigh-ins from this time or earlier (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long = "until")]
+    pub until: Option<String>,
 }
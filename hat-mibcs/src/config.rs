@@ -0,0 +1,16 @@
+use std::collections::HashMap;
+
+use hat_common::calibration::CalibrationConfig;
+use serde::Deserialize;
+
+// The part of the shared config file used by hat-mibcs
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Alias -> address
+    pub aliases: HashMap<String, String>,
+    /// Address or alias -> metric (weight or impedance) -> offset and scale
+    pub calibration: CalibrationConfig,
+    /// User name -> about what they weigh (kg), to tell the users of a shared scale apart
+    pub users: HashMap<String, f32>,
+}
@@ -6,10 +6,19 @@ extern crate chrono;
 extern crate log;
 
 mod cli;
+mod config;
+mod plausibility;
+mod query;
 mod scanner;
 mod weight_data;
 
-use cli::Cli;
+use std::error::Error;
+
+use cli::{Cli, Command};
+use config::Config;
+use hat_common::alias::Aliases;
+use hat_common::calibration::Calibrations;
+use hat_common::exit::{self, ErrorKind, ErrorReport};
 use scanner::Scanner;
 
 use structopt::StructOpt;
@@ -55,22 +64,38 @@ use structopt::StructOpt;
 //   *100 for pounds and catty, *200 for kilograms
 
 
+fn load_config(cli: &Cli) -> Result<(Aliases, Calibrations, Config), Box<dyn Error>> {
+    let config: Config = hat_common::config::load(cli.config.as_deref())?;
+    let aliases = Aliases::new(&config.aliases)?;
+    let calibrations = Calibrations::new(&config.calibration, &aliases)?;
+
+    Ok((aliases, calibrations, config))
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let (aliases, calibrations, config) = load_config(cli)?;
+
+    if let Some(Command::Query(ref opts)) = cli.cmd {
+        return query::query(cli, &aliases, &calibrations, opts);
+    }
+
+    Scanner::new(cli, aliases, calibrations, &config.users)?.listen_for_signals()
+}
+
+// The kind of the D-Bus errors, for exit::error_kind
+fn dbus_error_kind(error: &(dyn Error + 'static)) -> Option<ErrorKind> {
+    error
+        .downcast_ref::<dbus::Error>()
+        .map(|error| error.name().and_then(ErrorKind::of_dbus_name).unwrap_or(ErrorKind::Failed))
+}
+
 fn main() {
     env_logger::init();
 
     let cli = Cli::from_args();
 
-    match Scanner::new(&cli) {
-        Ok(scanner) => {
-            match scanner.listen_for_signals() {
-                Ok(_) => (),
-                Err(error) => {
-                    eprintln!("ERROR: {:?}", error);
-                }
-            }
-        }
-        Err(error) => {
-            eprintln!("ERROR: {:?}", error);
-        }
+    if let Err(error) = run(&cli) {
+        debug!("{:?}", error);
+        std::process::exit(ErrorReport::new(exit::error_kind(&*error, dbus_error_kind), &*error).print(cli.format));
     }
 }
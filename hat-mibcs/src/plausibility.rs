@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cli::Cli;
+use crate::weight_data::WeightData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stabilization {
+    None,
+    Weight,
+    Impedance,
+    Both,
+}
+
+impl FromStr for Stabilization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Stabilization::None),
+            "weight" => Ok(Stabilization::Weight),
+            "impedance" => Ok(Stabilization::Impedance),
+            "both" => Ok(Stabilization::Both),
+            _ => Err(format!("unknown stabilization '{}' (expected none, weight, impedance or both)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectedAction {
+    Drop,
+    Emit,
+}
+
+impl FromStr for RejectedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(RejectedAction::Drop),
+            "emit" => Ok(RejectedAction::Emit),
+            _ => Err(format!("unknown action '{}' (expected drop or emit)", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RejectReason {
+    WeightNotStabilized,
+    ImpedanceNotStabilized,
+    WeightTooLow,
+    WeightTooHigh,
+    WeightChangeTooLarge,
+    ImpedanceTooLow,
+    ImpedanceTooHigh,
+}
+
+impl RejectReason {
+    // The scale is still settling. Such frames come before every weigh-in, and aren't rejected
+    // weigh-ins of their own
+    pub fn is_unstable(&self) -> bool {
+        *self == RejectReason::WeightNotStabilized || *self == RejectReason::ImpedanceNotStabilized
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            RejectReason::WeightNotStabilized => "weight_not_stabilized",
+            RejectReason::ImpedanceNotStabilized => "impedance_not_stabilized",
+            RejectReason::WeightTooLow => "weight_too_low",
+            RejectReason::WeightTooHigh => "weight_too_high",
+            RejectReason::WeightChangeTooLarge => "weight_change_too_large",
+            RejectReason::ImpedanceTooLow => "impedance_too_low",
+            RejectReason::ImpedanceTooHigh => "impedance_too_high",
+        };
+
+        write!(f, "{}", reason)
+    }
+}
+
+// Decides if a decoded weigh-in is believable. The last accepted weight is tracked per user, and
+// optionally persisted, so sudden jumps (someone stepping off, the dog) are caught. The scale
+// doesn't say who is on it: a weigh-in is taken to be of the user whose last weight is closest.
+// Without configured users, every scale has a single user, tracked by the scale's address
+pub struct Plausibility {
+    min_weight: Option<f32>,
+    max_weight: Option<f32>,
+    max_change: Option<f32>,
+    min_impedance: Option<u16>,
+    max_impedance: Option<u16>,
+    require_stabilized: Stabilization,
+    pub rejected: RejectedAction,
+    state_file: Option<PathBuf>,
+    /// User name -> about what they weigh, until a weigh-in of theirs is accepted
+    users: HashMap<String, f32>,
+    /// User name (or scale address) -> last accepted weight
+    last_accepted: HashMap<String, f32>,
+}
+
+impl Plausibility {
+    pub fn new(cli: &Cli, users: &HashMap<String, f32>) -> Result<Plausibility, Box<dyn Error>> {
+        let last_accepted = match cli.state_file {
+            Some(ref path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)?,
+            _ => HashMap::new(),
+        };
+
+        Ok(Plausibility {
+            min_weight: cli.min_weight,
+            max_weight: cli.max_weight,
+            max_change: cli.max_change,
+            min_impedance: cli.min_impedance,
+            max_impedance: cli.max_impedance,
+            require_stabilized: cli.require_stabilized,
+            rejected: cli.rejected,
+            state_file: cli.state_file.clone(),
+            users: users.clone(),
+            last_accepted,
+        })
+    }
+
+    // The user a weight is closest to, and their last weight. None before the first weigh-in of
+    // a scale without configured users
+    fn user(&self, address: &str, weight: f32) -> Option<(String, f32)> {
+        if self.users.is_empty() {
+            return self.last_accepted.get(address).map(|last| (address.to_string(), *last));
+        }
+
+        self.users
+            .iter()
+            .map(|(name, usual)| (name.clone(), *self.last_accepted.get(name).unwrap_or(usual)))
+            .min_by(|(_, a), (_, b)| (weight - a).abs().total_cmp(&(weight - b).abs()))
+    }
+
+    pub fn check(&self, data: &WeightData) -> Result<(), RejectReason> {
+        let (weight_required, impedance_required) = match self.require_stabilized {
+            Stabilization::None => (false, false),
+            Stabilization::Weight => (true, false),
+            Stabilization::Impedance => (false, true),
+            Stabilization::Both => (true, true),
+        };
+
+        if weight_required && !data.weight_stabilized {
+            return Err(RejectReason::WeightNotStabilized);
+        }
+
+        if impedance_required && !data.impedance_stabilized {
+            return Err(RejectReason::ImpedanceNotStabilized);
+        }
+
+        if let Some(weight) = data.weight {
+            if self.min_weight.is_some_and(|min| weight < min) {
+                return Err(RejectReason::WeightTooLow);
+            }
+
+            if self.max_weight.is_some_and(|max| weight > max) {
+                return Err(RejectReason::WeightTooHigh);
+            }
+
+            if let (Some(max_change), Some((_, last))) = (self.max_change, self.user(&data.address, weight)) {
+                if (weight - last).abs() > max_change {
+                    return Err(RejectReason::WeightChangeTooLarge);
+                }
+            }
+        }
+
+        if let Some(impedance) = data.impedance {
+            if self.min_impedance.is_some_and(|min| impedance < min) {
+                return Err(RejectReason::ImpedanceTooLow);
+            }
+
+            if self.max_impedance.is_some_and(|max| impedance > max) {
+                return Err(RejectReason::ImpedanceTooHigh);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Called once a weigh-in has been emitted, so later ones are compared to it
+    pub fn accepted(&mut self, data: &WeightData) -> Result<(), Box<dyn Error>> {
+        if let Some(weight) = data.weight {
+            let user = self.user(&data.address, weight).map_or_else(|| data.address.clone(), |(user, _)| user);
+
+            self.last_accepted.insert(user, weight);
+
+            if let Some(ref path) = self.state_file {
+                fs::write(path, serde_json::to_string(&self.last_accepted)?)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plausibility() -> Plausibility {
+        Plausibility {
+            min_weight: Some(20.0),
+            max_weight: Some(150.0),
+            max_change: Some(3.0),
+            min_impedance: Some(200),
+            max_impedance: Some(1000),
+            require_stabilized: Stabilization::Weight,
+            rejected: RejectedAction::Drop,
+            state_file: None,
+            users: HashMap::new(),
+            last_accepted: HashMap::new(),
+        }
+    }
+
+    // A frame of the body composition characteristic, as the scale sends it
+    fn weigh_in(weight: f32, impedance: Option<u16>, weight_stabilized: bool) -> WeightData {
+        let mut status = 0b00000100;
+
+        if weight_stabilized {
+            status |= 0b00100000;
+        }
+        if impedance.is_some() {
+            status |= 0b10000010;
+        }
+
+        let impedance = impedance.unwrap_or(0).to_le_bytes();
+        let weight = ((weight * 200.0).round() as u16).to_le_bytes();
+        let frame = vec![
+            0x02, status, 0xe3, 0x07, 5, 14, 17, 9, 12, impedance[0], impedance[1], weight[0], weight[1],
+        ];
+
+        WeightData::decode(&frame, "EF:FB:0D:B1:43:97").unwrap()
+    }
+
+    #[test]
+    fn rejects_weights_out_of_range() {
+        let plausibility = plausibility();
+
+        assert_eq!(plausibility.check(&weigh_in(95.2, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(3.4, None, true)), Err(RejectReason::WeightTooLow));
+        assert_eq!(plausibility.check(&weigh_in(151.0, None, true)), Err(RejectReason::WeightTooHigh));
+        assert_eq!(plausibility.check(&weigh_in(20.0, None, true)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_impedance_out_of_range() {
+        let plausibility = plausibility();
+
+        assert_eq!(plausibility.check(&weigh_in(95.2, Some(480), true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(95.2, Some(150), true)), Err(RejectReason::ImpedanceTooLow));
+        assert_eq!(plausibility.check(&weigh_in(95.2, Some(1200), true)), Err(RejectReason::ImpedanceTooHigh));
+    }
+
+    #[test]
+    fn rejects_large_changes_from_last_accepted() {
+        let mut plausibility = plausibility();
+
+        // Nothing to compare the first weigh-in with
+        assert_eq!(plausibility.check(&weigh_in(95.2, None, true)), Ok(()));
+
+        plausibility.accepted(&weigh_in(95.2, None, true)).unwrap();
+
+        assert_eq!(plausibility.check(&weigh_in(97.0, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(98.4, None, true)), Err(RejectReason::WeightChangeTooLarge));
+        assert_eq!(plausibility.check(&weigh_in(92.0, None, true)), Err(RejectReason::WeightChangeTooLarge));
+    }
+
+    #[test]
+    fn tracks_last_weight_per_user() {
+        let mut plausibility = plausibility();
+
+        plausibility.users.insert("alice".to_string(), 62.0);
+        plausibility.users.insert("bob".to_string(), 95.0);
+
+        // Whoever is closer, within --max-change of their usual weight
+        assert_eq!(plausibility.check(&weigh_in(63.1, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(94.2, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(80.0, None, true)), Err(RejectReason::WeightChangeTooLarge));
+
+        // Weigh-ins of one user don't move the other's weight
+        for weight in &[63.1, 95.8, 64.9, 96.5, 67.5] {
+            plausibility.accepted(&weigh_in(*weight, None, true)).unwrap();
+        }
+
+        assert_eq!(plausibility.last_accepted.get("alice"), Some(&67.5));
+        assert_eq!(plausibility.last_accepted.get("bob"), Some(&96.5));
+        assert_eq!(plausibility.check(&weigh_in(70.0, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(99.0, None, true)), Ok(()));
+        assert_eq!(plausibility.check(&weigh_in(62.0, None, true)), Err(RejectReason::WeightChangeTooLarge));
+    }
+
+    #[test]
+    fn waits_for_stabilization() {
+        let mut plausibility = plausibility();
+        let settling = plausibility.check(&weigh_in(60.3, None, false));
+
+        assert_eq!(settling, Err(RejectReason::WeightNotStabilized));
+        assert!(settling.unwrap_err().is_unstable());
+        assert!(!RejectReason::WeightTooLow.is_unstable());
+
+        plausibility.require_stabilized = Stabilization::Both;
+
+        assert_eq!(plausibility.check(&weigh_in(95.2, None, true)), Err(RejectReason::ImpedanceNotStabilized));
+        assert_eq!(plausibility.check(&weigh_in(95.2, Some(480), true)), Ok(()));
+
+        plausibility.require_stabilized = Stabilization::None;
+
+        assert_eq!(plausibility.check(&weigh_in(60.3, None, false)), Ok(()));
+    }
+}
@@ -0,0 +1,74 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use hat_common::alias::Aliases;
+use hat_common::calibration::Calibrations;
+use hat_common::format::{Formatter, Record, Value};
+use hat_common::store::{Filter, Store};
+use hat_common::time::parse_datetime;
+
+use crate::cli::{Cli, QueryOpts};
+use crate::weight_data::WeightData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Weight,
+    Impedance,
+}
+
+impl Metric {
+    // Name of the field holding this metric
+    fn field(self) -> &'static str {
+        match self {
+            Metric::Weight => "weight",
+            Metric::Impedance => "impedance",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weight" => Ok(Metric::Weight),
+            "impedance" => Ok(Metric::Impedance),
+            _ => Err(format!("unknown metric '{}' (expected weight or impedance)", s)),
+        }
+    }
+}
+
+// Prints the stored weigh-ins the same way they were emitted. Weigh-ins without any of the
+// requested metrics are skipped, and metrics that weren't requested are left out
+pub fn query(cli: &Cli, aliases: &Aliases, calibrations: &Calibrations, opts: &QueryOpts) -> Result<(), Box<dyn Error>> {
+    let path = cli.store.as_ref().ok_or("No store given - use --store")?;
+    let store = Store::open(path)?;
+    let filter = Filter {
+        addresses: aliases.resolve_all(&opts.addresses)?,
+        since: opts.since.as_deref().map(parse_datetime).transpose()?,
+        until: opts.until.as_deref().map(parse_datetime).transpose()?,
+    };
+    let selected: Vec<&'static str> = opts.metrics.iter().map(|m| m.field()).collect();
+    let mut output = Formatter::stdout(cli.format, !cli.no_headers);
+
+    output.select(selected.clone());
+    output.alias_column(opts.addresses.len() != 1);
+
+    for row in store.weights(&filter)? {
+        let mut weight_data = WeightData::from_row(row);
+
+        weight_data.alias = aliases.alias(&weight_data.address).map(String::from);
+        weight_data.calibrate(calibrations, cli.raw);
+
+        let has_selected = weight_data
+            .fields()
+            .iter()
+            .any(|f| f.value != Value::Missing && (selected.is_empty() || selected.contains(&f.name)));
+
+        if has_selected {
+            output.write(&weight_data)?;
+        }
+    }
+
+    output.finish()
+}
@@ -3,38 +3,74 @@ use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
 use dbus::MessageType::Signal;
 use dbus::{BusType, Connection, ConnectionItem, SignalArgs};
 use std::boxed::Box;
+use std::collections::HashMap;
 use std::error::Error;
+use std::io;
 use std::time::SystemTime;
 use std::time::Duration;
 
 use crate::cli::Cli;
+use hat_common::alias::Aliases;
+use hat_common::calibration::Calibrations;
+use hat_common::format::Formatter;
+use hat_common::store::Store;
+use dbus_common::adapter_lock::{self, DiscoveryRef};
 use dbus_common::org_bluez_adapter1::OrgBluezAdapter1;
 use dbus_common::org_bluez_device1::OrgFreedesktopDBusProperties;
 use dbus_common::utils::{SERVICE_NAME, DEVICE_INTERFACE, get_adapter};
 use crate::weight_data::WeightData;
+use crate::plausibility::{Plausibility, RejectedAction};
 
 static BODY_COMPOSITION_UUID: &'static str = "0000181b-0000-1000-8000-00805f9b34fb";
 
 pub struct Scanner<'a> {
     connection: Connection,
     cli: &'a Cli,
+    plausibility: Plausibility,
+    store: Option<Store>,
+    aliases: Aliases,
+    calibrations: Calibrations,
+    output: Formatter<io::Stdout>,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(cli: &'a Cli) -> Result<Scanner, Box<dyn Error>> {
+    pub fn new(
+        cli: &'a Cli,
+        aliases: Aliases,
+        calibrations: Calibrations,
+        users: &HashMap<String, f32>,
+    ) -> Result<Scanner<'a>, Box<dyn Error>> {
         let connection = Connection::get_private(BusType::System)?;
+        let plausibility = Plausibility::new(cli, users)?;
+        let store = match cli.store {
+            Some(ref path) => Some(Store::open(path)?),
+            None => None,
+        };
 
-        Ok(Scanner { connection, cli })
+        let mut output = Formatter::stdout(cli.format, !cli.no_headers);
+
+        // Every scale in range is listened to
+        output.alias_column(true);
+
+        Ok(Scanner { connection, cli, plausibility, store, aliases, calibrations, output })
     }
 
-    pub fn listen_for_signals(&self) -> Result<(), Box<dyn Error>> {
+    pub fn listen_for_signals(&mut self) -> Result<(), Box<dyn Error>> {
         self.connection
             .add_match(&PropertiesPropertiesChanged::match_str(None, None))?;
 
         let now = SystemTime::now();
-        let adapter = self
-            .connection
-            .with_path(SERVICE_NAME, get_adapter(&self.connection)?, 1000);
+        let adapter_path = get_adapter(&self.connection)?;
+        let adapter = self.connection.with_path(SERVICE_NAME, adapter_path.clone(), 1000);
+
+        // hat-miflora may be discovering through the same adapter
+        let discovery = match DiscoveryRef::register(adapter_lock::adapter_name(&adapter_path).unwrap_or("hci0")) {
+            Ok(discovery) => Some(discovery),
+            Err(e) => {
+                warn!("Other hat processes may stop discovery: {}", e);
+                None
+            }
+        };
 
         let mut last_weight_data_seen = SystemTime::now();
         let mut last_weight_data : Option<WeightData> = None;
@@ -52,8 +88,25 @@ impl<'a> Scanner<'a> {
                                 debug!("  empty reading, ignoring");
                             }
                             else {
-                                last_weight_data = Some(weight_data);
-                                last_weight_data_seen = SystemTime::now();
+                                match self.plausibility.check(&weight_data) {
+                                    Ok(_) => {
+                                        last_weight_data = Some(weight_data);
+                                        last_weight_data_seen = SystemTime::now();
+                                    },
+                                    Err(reason) if reason.is_unstable() => {
+                                        debug!("  scale is still settling: {}", reason);
+                                    },
+                                    Err(reason) => {
+                                        debug!("  rejected reading: {}", reason);
+
+                                        if self.plausibility.rejected == RejectedAction::Emit {
+                                            let mut weight_data = weight_data;
+                                            weight_data.rejected_reason = Some(reason.to_string());
+                                            weight_data.calibrate(&self.calibrations, self.cli.raw);
+                                            self.output.write(&weight_data)?;
+                                        }
+                                    }
+                                }
                             }
                         },
                         None => {}
@@ -66,7 +119,16 @@ impl<'a> Scanner<'a> {
                 if weight_data.done() || last_weight_data_seen.elapsed()? > Duration::new(30,0) {
                     debug!("  outputing weight data");
 
-                    weight_data.dump()?;
+                    let mut calibrated = weight_data.clone();
+                    calibrated.calibrate(&self.calibrations, self.cli.raw);
+
+                    self.output.write(&calibrated)?;
+                    self.plausibility.accepted(weight_data)?;
+
+                    if let Some(store) = &self.store {
+                        store.insert_weight(&weight_data.to_row())?;
+                    }
+
                     last_weight_data = None;
 
                     if self.cli.until_data {
@@ -86,9 +148,13 @@ impl<'a> Scanner<'a> {
             }
         }
 
-        adapter.stop_discovery()?;
+        if discovery.map_or(Ok(true), DiscoveryRef::release)? {
+            adapter.stop_discovery()?;
+        } else {
+            debug!("  leaving discovery on for other hat processes");
+        }
 
-        Ok(())
+        self.output.finish()
     }
 
     fn handle_signal(&self, signal: &dbus::Message) -> Result<Option<WeightData>, Box<dyn Error>> {
@@ -217,7 +283,11 @@ impl<'a> Scanner<'a> {
                         .filter_map(|x| x.inner::<u8>().ok())
                         .collect();
 
-                    return Ok(Some(WeightData::decode(&bytes, btaddr)?));
+                    let mut weight_data = WeightData::decode(&bytes, btaddr)?;
+
+                    weight_data.alias = self.aliases.alias(btaddr).map(String::from);
+
+                    return Ok(Some(weight_data));
                 }
             }
         }
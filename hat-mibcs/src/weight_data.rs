@@ -3,6 +3,9 @@ use chrono::prelude::*;
 use serde::Serialize;
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::Cursor;
+use hat_common::calibration::Calibrations;
+use hat_common::format::{Field, Record, Unit, Value};
+use hat_common::store::WeightRow;
 
 static SOURCE: &'static str = "hat-mibcs";
 
@@ -10,10 +13,31 @@ static SOURCE: &'static str = "hat-mibcs";
 pub struct WeightData {
     pub source: &'static str,
     pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
     #[serde(with = "my_date_format", rename = "datetime")]
     pub created_at: DateTime<Local>,
     pub weight: Option<f32>,
     pub impedance: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_reason: Option<String>,
+    /// Set when weight or impedance were calibrated
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub calibrated: bool,
+    /// Values before calibration, when asked for with --raw
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawWeight>,
+    #[serde(skip)]
+    pub weight_stabilized: bool,
+    #[serde(skip)]
+    pub impedance_stabilized: bool,
+}
+
+// Weight and impedance as the scale sent them
+#[derive(Clone, Serialize)]
+pub struct RawWeight {
+    pub weight: Option<f32>,
+    pub impedance: Option<u16>,
 }
 
 impl WeightData {
@@ -48,7 +72,8 @@ impl WeightData {
         debug!("      weight               {:?}", got_weight);
         debug!("      weight_stabilized    {:?}", weight_stabilized);
 
-        let weight = if got_weight && weight_stabilized {
+        // Stabilization is checked by the plausibility stage, so keep unstable weights around
+        let weight = if got_weight {
             Some(weight)
         } else {
             None
@@ -62,9 +87,15 @@ impl WeightData {
         let data = WeightData {
             source: SOURCE,
             address: btaddr.to_string(),
+            alias: None,
             created_at: Local::now(),
             weight,
             impedance,
+            rejected_reason: None,
+            calibrated: false,
+            raw: None,
+            weight_stabilized,
+            impedance_stabilized,
         };
 
         // println!("{}", serde_json::to_string(&data)?);
@@ -72,14 +103,107 @@ impl WeightData {
         Ok(data)
     }
 
+    pub fn to_row(&self) -> WeightRow {
+        WeightRow {
+            address: self.address.clone(),
+            datetime: self.created_at,
+            weight: self.weight,
+            impedance: self.impedance,
+        }
+    }
+
+    // Only accepted weigh-ins are stored, so they are assumed stabilized
+    pub fn from_row(row: WeightRow) -> WeightData {
+        WeightData {
+            source: SOURCE,
+            address: row.address,
+            alias: None,
+            created_at: row.datetime,
+            weight: row.weight,
+            impedance: row.impedance,
+            rejected_reason: None,
+            calibrated: false,
+            raw: None,
+            weight_stabilized: true,
+            impedance_stabilized: row.impedance.is_some(),
+        }
+    }
+
+    // Applies the calibration of the scale, if it has one. Only the output is calibrated - the
+    // store and the plausibility checks use the values as sent. `keep_raw` keeps the values as
+    // sent for scales without a calibration too, so every record has the same fields
+    pub fn calibrate(&mut self, calibrations: &Calibrations, keep_raw: bool) {
+        if keep_raw {
+            self.raw = Some(RawWeight {
+                weight: self.weight,
+                impedance: self.impedance,
+            });
+        }
+
+        if !calibrations.is_calibrated(&self.address) {
+            return;
+        }
+
+        self.weight = self
+            .weight
+            .map(|w| calibrations.apply(&self.address, "weight", w as f64).map(|w| w as f32).unwrap_or(w));
+        self.impedance = self.impedance.map(|i| {
+            calibrations.apply(&self.address, "impedance", i as f64).map(|i| i.round() as u16).unwrap_or(i)
+        });
+        self.calibrated = true;
+    }
+
     pub fn done(&self) -> bool {
         return self.impedance.is_some();
     }
+}
+
+impl Record for WeightData {
+    fn measurement(&self) -> &'static str {
+        "mibcs"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.created_at
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn info(&self) -> Vec<(&'static str, String)> {
+        let mut info = match self.rejected_reason {
+            Some(ref reason) => vec![("rejected_reason", reason.clone())],
+            None => Vec::new(),
+        };
+
+        if self.calibrated {
+            info.push(("calibrated", "true".to_string()));
+        }
+
+        info
+    }
 
-    pub fn dump(&self) -> Result<(), Box<dyn Error>>  {
-        println!("{}", serde_json::to_string(&self)?);
+    fn info_names(&self) -> Vec<&'static str> {
+        vec!["rejected_reason", "calibrated"]
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = vec![
+            Field::new("weight", Unit::Kilograms, self.weight.map(Value::Float).unwrap_or(Value::Missing)),
+            Field::new("impedance", Unit::Ohms, self.impedance.map(|i| Value::Int(i as i64)).unwrap_or(Value::Missing)),
+        ];
 
-        Ok(())
+        if let Some(ref raw) = self.raw {
+            fields.push(Field::new("raw_weight", Unit::Kilograms, raw.weight.map(Value::Float).unwrap_or(Value::Missing)));
+            fields.push(Field::new("raw_impedance", Unit::Ohms, raw.impedance.map(|i| Value::Int(i as i64)).unwrap_or(Value::Missing)));
+        }
+
+        fields
     }
 }
 
@@ -98,3 +222,65 @@ mod my_date_format {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hat_common::alias::Aliases;
+    use hat_common::calibration::Adjustment;
+
+    use super::*;
+
+    // 72.5 kg, impedance 480, both stabilized
+    const FRAME: [u8; 13] = [0x02, 0xa6, 0xe3, 0x07, 5, 14, 17, 9, 12, 0xe0, 0x01, 0xa4, 0x38];
+
+    fn calibrations() -> Calibrations {
+        let mut metrics = HashMap::new();
+
+        metrics.insert("weight".to_string(), Adjustment { offset: -0.3, scale: 1.0 });
+
+        let mut config = HashMap::new();
+
+        config.insert("EF:FB:0D:B1:43:97".to_string(), metrics);
+
+        Calibrations::new(&config, &Aliases::default()).unwrap()
+    }
+
+    #[test]
+    fn decodes_weigh_in() {
+        let data = WeightData::decode(&FRAME.to_vec(), "EF:FB:0D:B1:43:97").unwrap();
+
+        assert_eq!((data.weight, data.impedance), (Some(72.5), Some(480)));
+        assert!(data.weight_stabilized && data.impedance_stabilized);
+    }
+
+    #[test]
+    fn keeps_raw_values_when_asked() {
+        let mut data = WeightData::decode(&FRAME.to_vec(), "EF:FB:0D:B1:43:97").unwrap();
+
+        data.calibrate(&calibrations(), true);
+
+        assert_eq!((data.weight, data.impedance), (Some(72.2), Some(480)));
+        assert!(data.calibrated);
+        assert_eq!(data.raw.as_ref().map(|raw| (raw.weight, raw.impedance)), Some((Some(72.5), Some(480))));
+
+        let mut data = WeightData::decode(&FRAME.to_vec(), "EF:FB:0D:B1:43:97").unwrap();
+
+        data.calibrate(&calibrations(), false);
+
+        assert_eq!(data.weight, Some(72.2));
+        assert!(data.raw.is_none());
+    }
+
+    #[test]
+    fn leaves_uncalibrated_scales_alone() {
+        let mut data = WeightData::decode(&FRAME.to_vec(), "C8:47:8C:00:00:01").unwrap();
+
+        data.calibrate(&calibrations(), true);
+
+        assert_eq!(data.weight, Some(72.5));
+        assert!(!data.calibrated);
+        assert_eq!(data.raw.as_ref().map(|raw| (raw.weight, raw.impedance)), Some((Some(72.5), Some(480))));
+    }
+}
@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local};
+use hat_common::time::from_unix;
+use serde::{Deserialize, Serialize};
+
+use crate::device::Miflora;
+use crate::state::StateFiles;
+
+// Device time samples taken for every estimate
+const SAMPLES: usize = 3;
+
+// Mifloras log a history record every hour of uptime
+const LOG_INTERVAL_SEC: u32 = 3600;
+
+// Records logged this close to the hourly grid are moved onto it
+const GRID_TOLERANCE_SEC: u32 = 60;
+
+// Estimates are a few seconds apart between runs, so only a larger jump means the device has
+// rebooted (or the host clock was changed)
+const REBOOT_TOLERANCE_SEC: i64 = 300;
+
+// One read of the device time, and when (unix time) the request was sent and answered
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sample {
+    pub sent: f64,
+    pub received: f64,
+    pub device_time: u32,
+}
+
+impl Sample {
+    fn round_trip(&self) -> f64 {
+        self.received - self.sent
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClockState {
+    /// When the device booted (unix time)
+    boot_time: i64,
+    /// Device time (seconds since boot) when the estimate was last checked
+    device_time: u32,
+}
+
+// When a device booted, as used for turning device time into wall clock time
+pub(crate) struct BootTime {
+    /// Unix time
+    pub boot_time: i64,
+}
+
+impl BootTime {
+    pub fn record_time(&self, epoch: u32) -> DateTime<Local> {
+        from_unix(self.boot_time + snap(epoch) as i64)
+    }
+}
+
+// Keeps a boot time estimate per device, so the same history record gets the same timestamp
+// every time it is read
+pub(crate) struct Clock {
+    files: StateFiles,
+}
+
+impl Clock {
+    pub fn new(state_dir: PathBuf) -> Clock {
+        Clock {
+            files: StateFiles::new(state_dir.join("clock"), "clock state"),
+        }
+    }
+
+    // Estimates when the device booted. The saved estimate is kept unless the device rebooted, so
+    // timestamps don't move between runs
+    pub fn boot_time(&self, device: &Miflora) -> Result<BootTime, Box<dyn Error>> {
+        let address = device.get_address()?;
+        let samples = sample(device, SAMPLES)?;
+        let estimate = estimate(&samples).ok_or("No device time samples")?.round() as i64;
+        let device_time = samples.iter().map(|s| s.device_time).max().unwrap_or(0);
+
+        debug!("{:} boot time estimate {:}", address, from_unix(estimate));
+
+        let boot_time = match self.files.load::<ClockState>(&address)? {
+            Some(state)
+                if device_time >= state.device_time
+                    && (estimate - state.boot_time).abs() <= REBOOT_TOLERANCE_SEC =>
+            {
+                state.boot_time
+            }
+            Some(state) => {
+                warn!(
+                    "{:} has rebooted (boot time {:} -> {:})",
+                    address,
+                    from_unix(state.boot_time),
+                    from_unix(estimate)
+                );
+                estimate
+            }
+            None => estimate,
+        };
+
+        self.files.save(&address, &ClockState { boot_time, device_time })?;
+
+        Ok(BootTime { boot_time })
+    }
+
+    // Samples the device time again (after a long read) and warns if it no longer matches
+    pub fn check(&self, device: &Miflora, boot_time: &BootTime) -> Result<(), Box<dyn Error>> {
+        let samples = sample(device, 1)?;
+        let estimate = estimate(&samples).ok_or("No device time samples")?.round() as i64;
+
+        if (estimate - boot_time.boot_time).abs() > REBOOT_TOLERANCE_SEC {
+            warn!(
+                "{:} clock moved during the read (boot time {:} -> {:})",
+                device.get_address()?,
+                from_unix(boot_time.boot_time),
+                from_unix(estimate)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn unix_now() -> Result<f64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64())
+}
+
+fn sample(device: &Miflora, count: usize) -> Result<Vec<Sample>, Box<dyn Error>> {
+    let mut samples = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let sent = unix_now()?;
+        let device_time = device.get_device_time()?;
+        let received = unix_now()?;
+
+        debug!("device time {:} (round trip {:.3} s)", device_time, received - sent);
+
+        samples.push(Sample { sent, received, device_time });
+    }
+
+    Ok(samples)
+}
+
+// The device time is in whole seconds, and was read somewhere between sending the request and
+// receiving the answer. Every sample so bounds the boot time, and the middle of the range left
+// by all of them is used. If they contradict each other, the sample with the shortest round trip
+// is trusted
+pub(crate) fn estimate(samples: &[Sample]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+
+    let earliest = samples
+        .iter()
+        .map(|s| s.sent - s.device_time as f64 - 1.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let latest = samples
+        .iter()
+        .map(|s| s.received - s.device_time as f64)
+        .fold(f64::INFINITY, f64::min);
+
+    if earliest <= latest {
+        return Some((earliest + latest) / 2.0);
+    }
+
+    samples
+        .iter()
+        .min_by(|a, b| a.round_trip().total_cmp(&b.round_trip()))
+        .map(|s| (s.sent + s.received) / 2.0 - s.device_time as f64 - 0.5)
+}
+
+// Moves a record's device time onto the hourly logging grid, if it is close to it
+pub(crate) fn snap(epoch: u32) -> u32 {
+    let offset = epoch % LOG_INTERVAL_SEC;
+
+    if offset <= GRID_TOLERANCE_SEC {
+        epoch - offset
+    } else if LOG_INTERVAL_SEC - offset <= GRID_TOLERANCE_SEC {
+        epoch + (LOG_INTERVAL_SEC - offset)
+    } else {
+        epoch
+    }
+}
+
+// The record numbers `from..to` of the records logged between `since` and `until` (unix time,
+// None is open ended), worked out from the number and device time of the `first` and `last`
+// record. That only holds when the history is one unbroken run of hourly records: the two are as
+// many hours apart as they are records, and the last one isn't from before a reboot (later than
+// `now`). Otherwise None - the records have to be read to know their time. As records are only
+// logged about on the hour, the range is a record wider on both ends
+pub(crate) fn record_range(
+    boot_time: &BootTime,
+    first: (u16, u32),
+    last: (u16, u32),
+    since: Option<i64>,
+    until: Option<i64>,
+    now: i64,
+) -> Option<(u16, u16)> {
+    let interval = LOG_INTERVAL_SEC as i64;
+    let records = last.0.checked_sub(first.0)? as i64;
+    let apart = snap(last.1) as i64 - snap(first.1) as i64;
+    let last_time = boot_time.boot_time + snap(last.1) as i64;
+
+    if (apart - records * interval).abs() > GRID_TOLERANCE_SEC as i64 || last_time > now + interval {
+        return None;
+    }
+
+    // The number of the record logged at `time`, counting whole hours back from the last one
+    let number = |time: i64| last.0 as i64 - (last_time - time).div_euclid(interval);
+    let end = last.0 as i64 + 1;
+    let from = since.map_or(first.0 as i64, |since| number(since) - 1).clamp(first.0 as i64, end);
+    let to = until.map_or(end, |until| number(until) + 2).clamp(from, end);
+
+    Some((from as u16, to as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(sent: f64, round_trip: f64, device_time: u32) -> Sample {
+        Sample {
+            sent,
+            received: sent + round_trip,
+            device_time,
+        }
+    }
+
+    #[test]
+    fn narrows_boot_time_with_every_sample() {
+        // booted at 1000.4
+        let samples = [
+            sample(2000.0, 0.3, 999),
+            sample(2000.5, 0.3, 1000),
+            sample(2001.1, 0.3, 1000),
+        ];
+
+        let boot_time = estimate(&samples).unwrap();
+
+        assert!((boot_time - 1000.4).abs() < 0.3, "{}", boot_time);
+    }
+
+    #[test]
+    fn trusts_shortest_round_trip_on_contradicting_samples() {
+        let samples = [sample(2000.0, 0.2, 1000), sample(2010.0, 3.0, 1000)];
+
+        let boot_time = estimate(&samples).unwrap();
+
+        assert!((boot_time - 999.6).abs() < 0.001, "{}", boot_time);
+    }
+
+    #[test]
+    fn no_estimate_without_samples() {
+        assert_eq!(estimate(&[]), None);
+    }
+
+    // 100 records, one every hour since a boot at 1000000
+    const BOOT: BootTime = BootTime { boot_time: 1_000_000 };
+    const FIRST: (u16, u32) = (0, 3600);
+    const LAST: (u16, u32) = (99, 100 * 3600);
+    const NOW: i64 = 1_000_000 + 100 * 3600 + 600;
+
+    #[test]
+    fn dates_records_after_boot() {
+        // device time counts up from the boot, so a record is that long after it
+        assert_eq!(BOOT.record_time(5 * 3600), from_unix(1_000_000 + 5 * 3600));
+        assert_eq!(BOOT.record_time(5 * 3600 + 42), from_unix(1_000_000 + 5 * 3600));
+        assert_eq!(BOOT.record_time(5 * 3600 - 42), from_unix(1_000_000 + 5 * 3600));
+        assert_eq!(BOOT.record_time(5 * 3600 + 1800), from_unix(1_000_000 + 5 * 3600 + 1800));
+    }
+
+    #[test]
+    fn maps_times_to_records() {
+        let last_time = 1_000_000 + 100 * 3600;
+
+        assert_eq!(record_range(&BOOT, FIRST, LAST, Some(last_time - 24 * 3600), None, NOW), Some((74, 100)));
+        assert_eq!(record_range(&BOOT, FIRST, LAST, None, Some(1_000_000 + 11 * 3600), NOW), Some((0, 12)));
+        assert_eq!(record_range(&BOOT, FIRST, LAST, Some(NOW + 3600), None, NOW), Some((100, 100)));
+        assert_eq!(record_range(&BOOT, FIRST, LAST, None, Some(1_000_000), NOW), Some((0, 1)));
+    }
+
+    #[test]
+    fn unknown_when_history_isnt_hourly() {
+        // a reboot in between
+        assert_eq!(record_range(&BOOT, FIRST, (99, 50 * 3600), Some(NOW - 3600), None, NOW), None);
+        // the last record is from before a reboot
+        assert_eq!(record_range(&BOOT, FIRST, LAST, Some(NOW - 3600), None, 1_000_000), None);
+    }
+
+    #[test]
+    fn snaps_to_hourly_grid() {
+        assert_eq!(snap(7203), 7200);
+        assert_eq!(snap(7170), 7200);
+        assert_eq!(snap(7200), 7200);
+        assert_eq!(snap(9000), 9000);
+    }
+}
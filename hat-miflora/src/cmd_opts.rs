@@ -1,19 +1,90 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hat_common::alias::Aliases;
+use hat_common::format::Format;
+use hat_common::time::parse_duration;
 use structopt::StructOpt;
 
+use crate::query::Metric;
+
 #[derive(StructOpt)]
 pub struct CmdOpts {
-    /// Output JSON
-    #[structopt(short, long)]
-    pub json: bool,
+    /// Output format: table, json, ndjson, csv, influx or prometheus
+    #[structopt(short, long, default_value = "table")]
+    pub format: Format,
 
-    /// Don't show headers (ignored for JSON output)
+    /// Don't show headers (table and csv only)
     #[structopt(short = "H", long)]
     pub no_headers: bool,
 
+    /// Config file (defaults to ~/.config/hat/config.json)
+    #[structopt(short = "C", long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Store readings in this SQLite database (overrides store from the config)
+    #[structopt(short = "S", long, parse(from_os_str))]
+    pub store: Option<PathBuf>,
+
+    /// Also output the values from before calibration, and the bytes not decoded
+    #[structopt(long)]
+    pub raw: bool,
+
+    /// Give up after this many seconds, whatever the command is doing
+    #[structopt(long = "deadline")]
+    pub deadline_sec: Option<u64>,
+
     #[structopt(subcommand)]
     pub cmd: Command,
 }
 
+impl CmdOpts {
+    // Replaces every alias given on the command line with the address it stands for
+    pub fn resolve_aliases(&mut self, aliases: &Aliases) -> Result<(), String> {
+        match self.cmd {
+            Command::Read { ref mut devices, .. }
+            | Command::Info { ref mut devices }
+            | Command::HistoryCount { ref mut devices }
+            | Command::Sync { ref mut devices, .. }
+            | Command::History(HistoryOpts { ref mut devices, .. }) => {
+                devices.addrs = aliases.resolve_all(&devices.addrs)?;
+            }
+            Command::Listen { ref mut addrs, .. } => *addrs = aliases.resolve_all(addrs)?,
+            Command::Blink { ref mut addr }
+            | Command::Watch { ref mut addr, .. }
+            | Command::HistoryClear { ref mut addr }
+            | Command::RawRead { ref mut addr, .. }
+            | Command::RawWrite { ref mut addr, .. }
+            | Command::RawNotify { ref mut addr, .. } => {
+                *addr = aliases.resolve(addr)?;
+            }
+            Command::Query(ref mut opts) => opts.addresses = aliases.resolve_all(&opts.addresses)?,
+            Command::Scan { .. } | Command::Daemon => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl CmdOpts {
+    // Whether the command can output records of more than one device
+    pub fn several_devices(&self) -> bool {
+        match self.cmd {
+            Command::Read { ref devices, .. }
+            | Command::Info { ref devices }
+            | Command::HistoryCount { ref devices }
+            | Command::Sync { ref devices, .. }
+            | Command::History(HistoryOpts { ref devices, .. }) => devices.all || devices.addrs.len() > 1,
+            Command::Listen { ref addrs, .. } => addrs.len() != 1,
+            Command::Query(ref opts) => opts.addresses.len() != 1,
+            Command::Daemon => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(StructOpt)]
 pub enum Command {
     /// Scan for Miflora Devices
@@ -21,38 +92,259 @@ pub enum Command {
         /// How long should we listen for Mifloras
         #[structopt(short, long = "duration", default_value = "10")]
         duration_sec: u8,
+
+        /// Write the aliases from the config into BlueZ
+        #[structopt(long)]
+        set_alias: bool,
+
+        /// Show every Xiaomi device, not only plant sensors
+        #[structopt(short, long)]
+        all: bool,
     },
 
-    /// Read realtime data from Miflora device
-    Read { addr: String },
+    /// Read realtime data from Miflora device(s)
+    Read {
+        #[structopt(flatten)]
+        devices: DeviceSelection,
 
-    /// Make Miflora device blink
-    Blink { addr: String },
+        /// Take this many readings, and show their median, minimum and maximum
+        #[structopt(short = "n", long)]
+        samples: Option<NonZeroUsize>,
+    },
 
-    /// Read historical data from Miflora device
-    History {
+    /// Keep the connection open, and read realtime data at an interval
+    Watch {
+        /// Miflora address or alias
         addr: String,
 
-        /// Read from record number (defaults to first record)
-        #[structopt(short, long)]
-        from: Option<u16>,
+        /// Seconds between readings
+        #[structopt(short, long = "interval", default_value = "60")]
+        interval_sec: u64,
 
-        /// Read until record number (defaults to last record)
-        #[structopt(short, long)]
-        to: Option<u16>,
+        /// How long to watch. 0 is forever
+        #[structopt(short, long = "duration", default_value = "0")]
+        duration_sec: u64,
+    },
+
+    /// Show everything known about Miflora device(s), using one connection each
+    Info {
+        #[structopt(flatten)]
+        devices: DeviceSelection,
+    },
+
+    /// Listen for realtime data in advertisements, without connecting
+    Listen {
+        /// Only these Miflora address(es) or alias(es) (defaults to every Miflora heard)
+        addrs: Vec<String>,
 
-        /// Number of records to batch together, before reconnecting
+        /// How long to listen. 0 is forever
+        #[structopt(short, long = "duration", default_value = "0")]
+        duration_sec: u64,
+    },
+
+    /// Make Miflora device blink
+    Blink {
+        /// Miflora address or alias
+        addr: String,
+    },
+
+    /// Read historical data from Miflora device(s)
+    History(HistoryOpts),
+
+    /// Read number of historical records from Miflora device(s)
+    HistoryCount {
+        #[structopt(flatten)]
+        devices: DeviceSelection,
+    },
+
+    /// Clear historical data from Miflora device
+    HistoryClear {
+        /// Miflora address or alias
+        addr: String,
+    },
+
+    /// Read history records that weren't read by an earlier sync
+    Sync {
+        #[structopt(flatten)]
+        devices: DeviceSelection,
+
+        /// Number of records to batch together
         #[structopt(short, long)]
         page: Option<u16>,
 
-        /// Clear after successful reading (only if you read everything)
-        #[structopt(short, long)]
-        clear: bool,
+        /// Forget what was synced before, and read everything again
+        #[structopt(long)]
+        reset: bool,
     },
 
-    /// Read number of historical records from Miflora Device
-    HistoryCount { addr: String },
+    /// Read a GATT characteristic, and show its value as hex
+    RawRead {
+        /// Miflora address or alias
+        addr: String,
+        /// Characteristic UUID
+        uuid: String,
+    },
 
-    /// Clear historical data from Miflora device
-    HistoryClear { addr: String },
+    /// Write hex (e.g. a01f) to a GATT characteristic
+    RawWrite {
+        /// Miflora address or alias
+        addr: String,
+        /// Characteristic UUID
+        uuid: String,
+        /// Value to write, as hex
+        value: Hex,
+    },
+
+    /// Show the notifications of a GATT characteristic as hex
+    RawNotify {
+        /// Miflora address or alias
+        addr: String,
+        /// Characteristic UUID
+        uuid: String,
+        /// How long to listen. 0 is forever
+        #[structopt(short, long = "duration", default_value = "0")]
+        duration_sec: u64,
+    },
+
+    /// Keep polling the devices configured in the daemon section of the config
+    Daemon,
+
+    /// Show readings kept in the store
+    Query(QueryOpts),
+}
+
+#[derive(StructOpt)]
+pub struct DeviceSelection {
+    /// Miflora address(es) or alias(es)
+    #[structopt(required_unless = "all")]
+    pub addrs: Vec<String>,
+
+    /// Use every Miflora found by scanning
+    #[structopt(short, long)]
+    pub all: bool,
+
+    /// Talk to this many devices at a time
+    #[structopt(short = "j", long, default_value = "1")]
+    pub parallel: usize,
+}
+
+#[derive(StructOpt)]
+pub struct HistoryOpts {
+    #[structopt(flatten)]
+    pub devices: DeviceSelection,
+
+    /// Read from record number (defaults to first record)
+    #[structopt(short, long)]
+    pub from: Option<u16>,
+
+    /// Read until record number (defaults to last record)
+    #[structopt(short, long)]
+    pub to: Option<u16>,
+
+    /// Only records from this time or later (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long, conflicts_with_all = &["from", "to"])]
+    pub since: Option<String>,
+
+    /// Only records from this time or earlier (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long, conflicts_with_all = &["from", "to"])]
+    pub until: Option<String>,
+
+    /// Only records from the last 30m, 24h, 7d, ...
+    #[structopt(long, parse(try_from_str = parse_duration), conflicts_with_all = &["from", "to", "since"])]
+    pub last: Option<Duration>,
+
+    /// Number of records to read in one go
+    #[structopt(short, long)]
+    pub page: Option<u16>,
+
+    /// Clear after successful reading (only if you read everything)
+    #[structopt(short, long, conflicts_with_all = &["since", "until", "last"])]
+    pub clear: bool,
+
+    /// Start over, instead of resuming an interrupted read of the same records
+    #[structopt(long)]
+    pub restart: bool,
+}
+
+#[derive(StructOpt)]
+pub struct QueryOpts {
+    /// Only readings from this address or alias (can be repeated)
+    #[structopt(short, long = "address")]
+    pub addresses: Vec<String>,
+
+    /// Only show these metrics: battery, temperature, lux, moisture or conductivity (can be repeated)
+    #[structopt(short, long = "metric")]
+    pub metrics: Vec<Metric>,
+
+    /// Only readings from this time or later (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long)]
+    pub since: Option<String>,
+
+    /// Only readings from this time or earlier (YYYY-MM-DD [HH:MM:SS])
+    #[structopt(long)]
+    pub until: Option<String>,
+
+    /// Query history records instead of realtime readings
+    #[structopt(long)]
+    pub history: bool,
+}
+
+// Bytes given as "a01f", "a0 1f", "a0:1f" or "0xa01f"
+pub struct Hex(pub Vec<u8>);
+
+impl FromStr for Hex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s).map(Hex)
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: String = s
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' isn't hex", s));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("'{}' has an odd number of hex digits", s));
+    }
+
+    // only ASCII is left, so every index is on a char boundary
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| format!("'{}' isn't hex", s)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex() {
+        for s in &["a01f", "a0 1f", "a0:1f", "0xa01f", "A01F"] {
+            assert_eq!(parse_hex(s), Ok(vec![0xa0, 0x1f]), "{:?}", s);
+        }
+        assert_eq!(parse_hex(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn rejects_zero_samples() {
+        let read = |n: &str| CmdOpts::from_iter_safe(&["hat-miflora", "read", "--samples", n, "C4:7C:8D:67:C2:8B"]);
+
+        assert!(read("0").is_err());
+        assert!(matches!(read("3").unwrap().cmd, Command::Read { samples: Some(n), .. } if n.get() == 3));
+    }
+
+    #[test]
+    fn rejects_invalid_hex() {
+        for s in &["a01", "a0g1", "aéb", "éa", "a0 é"] {
+            assert!(parse_hex(s).is_err(), "{:?}", s);
+        }
+    }
 }
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use hat_common::alias::Aliases;
+use hat_common::calibration::CalibrationConfig;
+use hat_common::config;
+use serde::Deserialize;
+
+use crate::timeouts::TimeoutConfig;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Where sync state is kept (defaults to ~/.local/state/hat)
+    pub state_dir: Option<PathBuf>,
+    /// SQLite database every reading is stored in (not stored if unset)
+    pub store: Option<PathBuf>,
+    /// Alias -> address
+    pub aliases: HashMap<String, String>,
+    /// Address or alias -> metric -> offset and scale
+    pub calibration: CalibrationConfig,
+    pub plants: PlantsConfig,
+    pub daemon: DaemonConfig,
+    pub timeouts: TimeoutConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct PlantsConfig {
+    /// Species database (JSON or CSV) with the recommended ranges
+    pub database: Option<PathBuf>,
+    /// Address -> species growing next to that Miflora
+    pub devices: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct DaemonConfig {
+    /// Max random delay (seconds) added to every scheduled poll
+    pub jitter_sec: u64,
+    /// Delay before the first retry of a failed device. Doubled on every further failure
+    pub retry_min_sec: u64,
+    /// Upper bound for the retry delay
+    pub retry_max_sec: u64,
+    pub outputs: Vec<OutputConfig>,
+    pub devices: Vec<DaemonDeviceConfig>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            jitter_sec: 60,
+            retry_min_sec: 60,
+            retry_max_sec: 3600,
+            outputs: vec![OutputConfig::Stdout],
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DaemonDeviceConfig {
+    pub address: String,
+    /// Read realtime data every N minutes
+    #[serde(default)]
+    pub realtime_interval_min: Option<u64>,
+    /// Read new history records every N hours
+    #[serde(default)]
+    pub history_interval_hours: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum OutputConfig {
+    Stdout,
+    File { path: PathBuf },
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+        config::load(path)
+    }
+
+    pub fn aliases(&self) -> Result<Aliases, Box<dyn Error>> {
+        Ok(Aliases::new(&self.aliases)?)
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        match self.state_dir {
+            Some(ref dir) => dir.clone(),
+            None => env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".local/state/hat"))
+                .unwrap_or_else(|| PathBuf::from(".")),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hat_common::format::{Format, Formatter, Record};
+
+use crate::clock::Clock;
+use crate::config::{Config, DaemonConfig, OutputConfig};
+use crate::dbus_bluez::BluezManager;
+use crate::device::Miflora;
+use crate::records::ReadResult;
+use crate::recorder::Recorder;
+use crate::sync::{self, SyncStore};
+
+const HISTORY_PAGE_SIZE: u16 = 10;
+
+#[derive(Debug, Clone, Copy)]
+enum JobKind {
+    Realtime,
+    History,
+}
+
+struct Job {
+    address: String,
+    kind: JobKind,
+    interval: Duration,
+    due: Instant,
+    failures: u32,
+}
+
+// Every output gets every record, in the --format given
+struct Outputs {
+    outputs: Vec<Formatter<Box<dyn Write>>>,
+}
+
+impl Outputs {
+    fn open(config: &[OutputConfig], format: Format, headers: bool) -> Result<Outputs, Box<dyn Error>> {
+        // These are only complete once every record is in, and the daemon never gets there
+        if format == Format::Json || format == Format::Prometheus {
+            return Err(Box::from("daemon can't output --format json or prometheus (try ndjson)"));
+        }
+
+        let mut outputs = Vec::new();
+
+        for output in config {
+            let (out, headers): (Box<dyn Write>, bool) = match output {
+                OutputConfig::Stdout => (Box::new(io::stdout()), headers),
+                OutputConfig::File { path } => {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|e| format!("Cannot open output {:?}: {:}", path, e))?;
+                    // A file written before the daemon restarted has its header already
+                    let empty = file.metadata()?.len() == 0;
+
+                    (Box::new(file), headers && empty)
+                }
+            };
+            let mut formatter = Formatter::new(out, format, headers);
+
+            formatter.alias_column(true);
+            outputs.push(formatter);
+        }
+
+        Ok(Outputs { outputs })
+    }
+
+    fn write<R: Record>(&mut self, record: &R) -> Result<(), Box<dyn Error>> {
+        for output in self.outputs.iter_mut() {
+            output.write(record)?;
+        }
+
+        Ok(())
+    }
+}
+
+// xorshift - good enough for spreading polls out, and saves a dependency
+struct Jitter {
+    state: u64,
+}
+
+impl Jitter {
+    fn new() -> Jitter {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Jitter {
+            state: nanos ^ (std::process::id() as u64) | 1,
+        }
+    }
+
+    fn next(&mut self, max_sec: u64) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        if max_sec == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_millis(self.state % (max_sec * 1000))
+        }
+    }
+}
+
+pub(crate) struct Daemon<'a> {
+    manager: &'a BluezManager,
+    config: &'a DaemonConfig,
+    outputs: Outputs,
+    jitter: Jitter,
+    jobs: Vec<Job>,
+    sync_store: SyncStore,
+    clock: Clock,
+    recorder: &'a Recorder,
+}
+
+impl<'a> Daemon<'a> {
+    pub fn new(
+        manager: &'a BluezManager,
+        config: &'a Config,
+        recorder: &'a Recorder,
+        format: Format,
+        headers: bool,
+    ) -> Result<Daemon<'a>, Box<dyn Error>> {
+        let sync_store = SyncStore::new(config.state_dir());
+        let clock = Clock::new(config.state_dir());
+        let config = &config.daemon;
+        let mut jitter = Jitter::new();
+        let mut jobs = Vec::new();
+
+        for device in &config.devices {
+            let schedules = [
+                (JobKind::Realtime, device.realtime_interval_min.map(|m| m * 60)),
+                (JobKind::History, device.history_interval_hours.map(|h| h * 3600)),
+            ];
+
+            for (kind, interval_sec) in schedules.iter() {
+                if let Some(interval_sec) = interval_sec {
+                    jobs.push(Job {
+                        address: recorder.aliases.resolve(&device.address)?,
+                        kind: *kind,
+                        interval: Duration::from_secs(*interval_sec),
+                        due: Instant::now() + jitter.next(config.jitter_sec),
+                        failures: 0,
+                    });
+                }
+            }
+        }
+
+        if jobs.is_empty() {
+            return Err(Box::from("No devices scheduled - add some to the daemon section of the config"));
+        }
+
+        Ok(Daemon {
+            manager,
+            config,
+            outputs: Outputs::open(&config.outputs, format, headers)?,
+            jitter,
+            jobs,
+            sync_store,
+            clock,
+            recorder,
+        })
+    }
+
+    // Runs forever, or fails with a timeout once the deadline (--deadline) has passed. Jobs are
+    // executed one at a time, so there is never more than one connection
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            // Polls would fail right away from here on, and be retried over and over
+            self.manager.timeouts().check()?;
+
+            let idx = self
+                .jobs
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, job)| job.due)
+                .map(|(idx, _)| idx)
+                .ok_or("No jobs scheduled")?;
+
+            let now = Instant::now();
+            if self.jobs[idx].due > now {
+                self.manager.idle(self.jobs[idx].due - now);
+            }
+
+            let address = self.jobs[idx].address.clone();
+            let kind = self.jobs[idx].kind;
+
+            info!("{:} {:?} poll", address, kind);
+
+            let result = match kind {
+                JobKind::Realtime => self.poll_realtime(&address),
+                JobKind::History => self.poll_history(&address),
+            };
+
+            let job = &mut self.jobs[idx];
+
+            match result {
+                Ok(_) => {
+                    job.failures = 0;
+                    job.due = Instant::now() + job.interval + self.jitter.next(self.config.jitter_sec);
+                }
+                Err(e) => {
+                    job.failures += 1;
+
+                    let backoff = self
+                        .config
+                        .retry_min_sec
+                        .saturating_mul(1 << (job.failures - 1).min(16))
+                        .min(self.config.retry_max_sec);
+
+                    error!(
+                        "{:} {:?} poll failed ({:} in a row), retrying in {:} s: {:}",
+                        address, kind, job.failures, backoff, e
+                    );
+
+                    job.due = Instant::now() + Duration::from_secs(backoff);
+                }
+            }
+        }
+    }
+
+    fn poll_realtime(&mut self, address: &str) -> Result<(), Box<dyn Error>> {
+        let device = self.manager.find_by_address(address)?;
+        let mut device = Miflora::new(device, self.manager)?;
+
+        device.connect()?;
+
+        let result = self.recorder.realtime(ReadResult::read(&device)?)?;
+
+        self.outputs.write(&result)
+    }
+
+    fn poll_history(&mut self, address: &str) -> Result<(), Box<dyn Error>> {
+        let device = self.manager.find_by_address(address)?;
+        let mut device = Miflora::new(device, self.manager)?;
+
+        device.connect()?;
+
+        let outputs = &mut self.outputs;
+        let recorder = self.recorder;
+        let new_records = sync::sync(&mut device, &self.sync_store, &self.clock, HISTORY_PAGE_SIZE, |record| {
+            outputs.write(&recorder.history(record)?)
+        })?;
+
+        info!("{:} synced {:} new history records", address, new_records);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use hat_common::store::HistoryRow;
+    use std::fs;
+
+    use crate::records::HistoryRecordResult;
+
+    #[test]
+    fn writes_header_only_into_new_files() {
+        let path = std::env::temp_dir().join(format!("hat-miflora-daemon-test-{}.csv", std::process::id()));
+        let config = [OutputConfig::File { path: path.clone() }];
+        let record = HistoryRecordResult::from_row(HistoryRow {
+            address: "C4:7C:8D:67:C2:8B".to_string(),
+            datetime: Local.timestamp_opt(1_574_586_000, 0).unwrap(),
+            record_number: 0,
+            total_records: 1,
+            temperature: 23.9,
+            lux: Some(112),
+            moisture: 41,
+            conductivity: 498,
+        });
+
+        // the daemon restarting
+        for _ in 0..2 {
+            Outputs::open(&config, Format::Csv, true).unwrap().write(&record).unwrap();
+        }
+
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+
+        assert_eq!(lines.len(), 3, "{}", written);
+        assert!(lines[0].starts_with("datetime,"), "{}", written);
+        assert_eq!(lines[1], lines[2]);
+    }
+}
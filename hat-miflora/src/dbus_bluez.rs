@@ -1,29 +1,41 @@
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::io::{Error, ErrorKind};
-use std::rc::Rc;
+use std::io::{self, Error, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{error, fmt};
 
-use dbus::arg::RefArg;
+use dbus::arg::{self, IterAppend, RefArg, Variant};
 use dbus::stdintf::org_freedesktop_dbus::{
     ObjectManager, ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved,
+    PropertiesPropertiesChanged,
 };
-use dbus::{BusType, Connection, SignalArgs};
+use dbus::{BusType, Connection, MessageType, SignalArgs};
+use dbus_common::adapter_lock::{self, DiscoveryRef};
 use dbus_common::org_bluez_adapter1::OrgBluezAdapter1;
+use hat_common::exit::ErrorKind;
+
+use crate::timeouts::{Phase, Timeouts};
 
 pub(crate) static BLUEZ_SERVICE: &'static str = "org.bluez";
 pub(crate) static BLUEZ_INTERFACE_DEVICE1: &'static str = "org.bluez.Device1";
 pub(crate) static BLUEZ_INTERFACE_ADAPTER1: &'static str = "org.bluez.Adapter1";
 
+static PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
 pub(crate) static BLUEZ_GATT_CHARACTERISTIC_INTERFACE: &'static str =
     "org.bluez.GattCharacteristic1";
 
 type BoxErr = Box<dyn error::Error>;
-pub(crate) type DBusPath = dbus::ConnPath<'static, Rc<dbus::Connection>>;
 
 type DBusProperties = HashMap<String, dbus::arg::Variant<Box<dyn dbus::arg::RefArg>>>;
 type DBusObject = HashMap<String, DBusProperties>;
+type Objects = HashMap<dbus::Path<'static>, DBusObject>;
 
 #[derive(Debug)]
 pub struct TypedDbusError {
@@ -50,236 +62,809 @@ impl Display for TypedDbusError {
 
 impl From<dbus::Error> for TypedDbusError {
     fn from(cause: dbus::Error) -> Self {
-        let kind = if let Some(name) = cause.name() {
-            match name {
-                "org.freedesktop.DBus.Error.InvalidArgs" => TypedDbusErrorKind::InvalidArgs,
-                "org.freedesktop.DBus.Error.AccessDenied" => TypedDbusErrorKind::AccessDenied,
-                "org.freedesktop.DBus.Error.NoReply" => TypedDbusErrorKind::NoReply,
-                "org.bluez.Error.Failed" => TypedDbusErrorKind::Failed,
-                _ => TypedDbusErrorKind::Other,
-            }
-        } else {
-            TypedDbusErrorKind::Other
-        };
+        let kind = TypedDbusErrorKind::of(&cause);
 
         TypedDbusError { cause, kind }
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct BluezManager {
-    conn: Rc<dbus::Connection>,
-    objects: HashMap<dbus::Path<'static>, DBusObject>,
-    adapter: Option<DBusPath>,
+impl TypedDbusErrorKind {
+    pub fn of(error: &dbus::Error) -> Self {
+        match error.name() {
+            Some("org.freedesktop.DBus.Error.InvalidArgs") => TypedDbusErrorKind::InvalidArgs,
+            Some("org.freedesktop.DBus.Error.AccessDenied")
+            | Some("org.bluez.Error.NotAuthorized")
+            | Some("org.bluez.Error.NotPermitted") => TypedDbusErrorKind::AccessDenied,
+            Some("org.freedesktop.DBus.Error.NoReply") | Some("org.freedesktop.DBus.Error.Timeout") => {
+                TypedDbusErrorKind::NoReply
+            }
+            Some("org.bluez.Error.Failed") => TypedDbusErrorKind::Failed,
+            _ => TypedDbusErrorKind::Other,
+        }
+    }
+
+    // The kind of failure, if the D-Bus error tells more than what was being done when it happened
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        match self {
+            TypedDbusErrorKind::AccessDenied => Some(ErrorKind::PermissionDenied),
+            TypedDbusErrorKind::NoReply => Some(ErrorKind::Timeout),
+            _ => None,
+        }
+    }
 }
 
-impl BluezManager {
-    pub fn new() -> Result<Self, TypedDbusError> {
-        info!("Creating BluezManager");
+// A BlueZ object, and the timeout of the calls made to it. The calls go through the D-Bus thread
+#[derive(Debug, Clone)]
+pub(crate) struct DBusPath {
+    bus: Bus,
+    pub path: dbus::Path<'static>,
+    pub timeout: i32,
+}
 
-        let conn = Rc::new(Connection::get_private(BusType::System)?);
+impl DBusPath {
+    // Another object, talked to the same way
+    pub fn at(&self, path: dbus::Path<'static>) -> DBusPath {
+        DBusPath {
+            bus: self.bus.clone(),
+            path,
+            timeout: self.timeout,
+        }
+    }
 
-        let bus_name = dbus::BusName::from(BLUEZ_SERVICE);
-        let root_path = dbus::Path::from("/");
+    fn call<F: FnOnce(&mut IterAppend)>(&self, interface: &str, method: &str, args: F) -> Result<dbus::Message, dbus::Error> {
+        let mut msg = dbus::Message::new_method_call(BLUEZ_SERVICE, self.path.clone(), interface, method)
+            .map_err(|e| dbus::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &e))?;
 
-        let bluez = conn.with_path(BLUEZ_SERVICE, &root_path, 1000);
-        let objects = bluez.get_managed_objects()?;
+        args(&mut IterAppend::new(&mut msg));
 
-        conn.add_match(&ObjectManagerInterfacesAdded::match_str(
-            Some(&bus_name),
-            Some(&root_path),
-        ))?;
-        conn.add_match(&ObjectManagerInterfacesRemoved::match_str(
-            Some(&bus_name),
-            Some(&root_path),
-        ))?;
+        self.bus.call(msg, self.timeout)
+    }
 
-        let adapter = None;
+    fn get<T: for<'b> arg::Get<'b>>(&self, interface: &str, property: &str) -> Result<T, dbus::Error> {
+        let msg = self.call(PROPERTIES_INTERFACE, "Get", |args| {
+            args.append(interface);
+            args.append(property);
+        })?;
+        let value: Variant<T> = msg.iter_init().read()?;
 
-        Ok(BluezManager {
-            conn,
-            objects,
-            adapter,
+        Ok(value.0)
+    }
+
+    fn set<T: arg::Arg + arg::Append>(&self, interface: &str, property: &str, value: T) -> Result<(), dbus::Error> {
+        self.call(PROPERTIES_INTERFACE, "Set", |args| {
+            args.append(interface);
+            args.append(property);
+            args.append(Variant(value));
+        })?;
+
+        Ok(())
+    }
+
+    pub fn connect(&self) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_INTERFACE_DEVICE1, "Connect", |_| ()).map(|_| ())
+    }
+
+    pub fn disconnect(&self) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_INTERFACE_DEVICE1, "Disconnect", |_| ()).map(|_| ())
+    }
+
+    pub fn get_address(&self) -> Result<String, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Address")
+    }
+
+    pub fn get_address_type(&self) -> Result<String, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "AddressType")
+    }
+
+    pub fn get_name(&self) -> Result<String, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Name")
+    }
+
+    pub fn get_alias(&self) -> Result<String, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Alias")
+    }
+
+    pub fn set_alias(&self, alias: String) -> Result<(), dbus::Error> {
+        self.set(BLUEZ_INTERFACE_DEVICE1, "Alias", alias)
+    }
+
+    pub fn get_paired(&self) -> Result<bool, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Paired")
+    }
+
+    pub fn get_trusted(&self) -> Result<bool, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Trusted")
+    }
+
+    pub fn get_rssi(&self) -> Result<i16, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "RSSI")
+    }
+
+    pub fn get_tx_power(&self) -> Result<i16, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "TxPower")
+    }
+
+    pub fn get_connected(&self) -> Result<bool, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "Connected")
+    }
+
+    pub fn get_services_resolved(&self) -> Result<bool, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "ServicesResolved")
+    }
+
+    pub fn get_service_data(&self) -> Result<HashMap<String, Variant<Box<dyn RefArg + 'static>>>, dbus::Error> {
+        self.get(BLUEZ_INTERFACE_DEVICE1, "ServiceData")
+    }
+
+    pub fn read_value(&self) -> Result<Vec<u8>, dbus::Error> {
+        let msg = self.call(BLUEZ_GATT_CHARACTERISTIC_INTERFACE, "ReadValue", |args| args.append(no_options()))?;
+
+        Ok(msg.read1()?)
+    }
+
+    pub fn write_value(&self, value: Vec<u8>) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_GATT_CHARACTERISTIC_INTERFACE, "WriteValue", |args| {
+            args.append(value);
+            args.append(no_options());
         })
+        .map(|_| ())
+    }
+
+    pub fn start_notify(&self) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_GATT_CHARACTERISTIC_INTERFACE, "StartNotify", |_| ()).map(|_| ())
+    }
+
+    pub fn stop_notify(&self) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_GATT_CHARACTERISTIC_INTERFACE, "StopNotify", |_| ()).map(|_| ())
     }
 
-    pub fn start_discovery(&mut self, timeout_ms: Option<u32>) -> Result<(), BoxErr> {
-        let adapter = self.find_adapter(timeout_ms)?;
+    pub fn start_discovery(&self) -> Result<(), dbus::Error> {
+        self.call(BLUEZ_INTERFACE_ADAPTER1, "StartDiscovery", |_| ()).map(|_| ())
+    }
+}
+
+fn no_options() -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+    HashMap::new()
+}
 
-        debug!("Discoverying using {:?}", adapter);
+// A handle to the D-Bus connection and what is known about the BlueZ objects. Clones share both,
+// also between threads, so several devices can be talked to over one connection
+#[derive(Debug, Clone)]
+pub(crate) struct BluezManager {
+    bus: Bus,
+    timeouts: Timeouts,
+}
+
+impl BluezManager {
+    pub fn new(timeouts: Timeouts) -> Result<Self, BoxErr> {
+        info!("Creating BluezManager");
+
+        let discover = timeouts.ms(Phase::Discover)?;
+        let bus = Bus::start(move || Bluez::connect(discover))?;
+
+        Ok(BluezManager { bus, timeouts })
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub fn start_discovery(&self) -> Result<(), BoxErr> {
+        let adapter = self.find_adapter()?;
+
+        debug!("Discoverying using {:}", adapter.path);
+
+        let discovery = adapter_lock::adapter_name(&adapter.path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected adapter path {}", adapter.path)))
+            .and_then(DiscoveryRef::register)
+            .map_err(|e| warn!("Other hat processes may stop discovery: {:}", e))
+            .ok();
 
         adapter.start_discovery()?;
 
-        self.adapter = Some(adapter);
+        let DBusPath { path, timeout, .. } = adapter;
+
+        self.bus.run(move |bluez| bluez.adapter = Some((path, timeout, discovery)))?;
 
         Ok(())
     }
 
-    pub fn find_objects<F: FnMut(&dbus::Path, &DBusObject) -> bool>(
-        &mut self,
+    // What `f` makes of the objects - those known now, then those added until `timeout` has
+    // passed - as it is found
+    pub fn find_objects<T, F>(&self, mut f: F, timeout: Duration) -> Result<Found<T>, dbus::Error>
+    where
+        T: Send + 'static,
+        F: FnMut(&dbus::Path, &DBusObject) -> Option<T> + Send + 'static,
+    {
+        let until = self.timeouts.until(Some(Instant::now() + timeout));
+        let (found, receiver) = mpsc::channel();
+
+        self.bus.watch(until, move |seen| match seen {
+            Seen::Object(path, obj) => f(path, obj).is_none_or(|t| found.send(t).is_ok()),
+            Seen::Signal(..) => true,
+        })?;
+
+        Ok(Found { receiver, until })
+    }
+
+    fn find_object<T, F>(&self, f: F, timeout: Duration) -> Result<Option<T>, dbus::Error>
+    where
+        T: Send + 'static,
+        F: FnMut(&dbus::Path, &DBusObject) -> Option<T> + Send + 'static,
+    {
+        Ok(self.find_objects(f, timeout)?.next())
+    }
+
+    // Waits for the given duration. The D-Bus thread keeps the known objects current meanwhile
+    pub fn idle(&self, duration: Duration) {
+        thread::sleep(self.timeouts.cut(duration));
+    }
+
+    // Calls `f` with the device address and data, every time a device advertises service data
+    // for `uuid`. Runs until `duration` has passed (forever if None)
+    pub fn listen_service_data<F: FnMut(&str, &[u8]) -> Result<(), BoxErr>>(
+        &self,
+        uuid: &str,
+        duration: Option<Duration>,
         mut f: F,
-        timeout_ms: Option<u32>,
-    ) {
-        self.find_object(
-            |path, obj| if f(path, obj) { Some(()) } else { None },
-            timeout_ms,
-        );
+    ) -> Result<(), BoxErr> {
+        self.bus.run(|bluez| {
+            bluez.conn.add_match(&PropertiesPropertiesChanged::match_str(
+                Some(&dbus::BusName::from(BLUEZ_SERVICE)),
+                None,
+            ))
+        })??;
+
+        let until = self.timeouts.until(duration.map(|d| Instant::now() + d));
+        let uuid = uuid.to_string();
+        let (heard, receiver) = mpsc::channel();
+
+        self.bus.watch(until, move |seen| {
+            let found = match seen {
+                // Devices already known, with data from before we started listening, and new ones
+                Seen::Object(_, obj) => device_service_data(obj.get(BLUEZ_INTERFACE_DEVICE1), &uuid),
+                Seen::Signal(msg, objects) => changed_service_data(msg, objects, &uuid),
+            };
+
+            found.is_none_or(|found| heard.send(found).is_ok())
+        })?;
+
+        while let Some((address, data)) = receive(&receiver, until) {
+            f(&address, &data)?;
+        }
+
+        Ok(self.timeouts.check()?)
     }
 
-    fn find_object<T, F: FnMut(&dbus::Path, &DBusObject) -> Option<T>>(
-        &mut self,
+    // Calls `f` with the new value, every time the characteristic at `path` notifies. Runs until
+    // `duration` has passed (forever if None)
+    pub fn listen_value<F: FnMut(&[u8]) -> Result<(), BoxErr>>(
+        &self,
+        path: &dbus::Path,
+        duration: Option<Duration>,
         mut f: F,
-        timeout_ms: Option<u32>,
-    ) -> Option<T> {
-        let r = self
-            .objects
-            .iter()
-            .find_map(|(path, obj)| f(path, obj))
-            .or_else(|| {
-                (dbus::ConnMsgs {
-                    conn: self.conn.clone(),
-                    timeout_ms,
-                })
-                .find_map(|msg| {
-                    let mut t = None;
-                    self.process_interface_signal(&msg, |path, obj| {
-                        t = f(path, obj);
-                    });
-                    t
-                })
-            });
-
-        (dbus::ConnMsgs {
-            conn: self.conn.clone(),
-            timeout_ms: None,
-        })
-        .for_each(|msg| self.process_interface_signal(&msg, |_, _| ()));
-
-        r
-    }
-
-    fn process_interface_signal<F: FnOnce(&dbus::Path, &DBusObject)>(
-        &mut self,
-        msg: &dbus::Message,
-        f: F,
-    ) {
-        if let Some(ObjectManagerInterfacesAdded {
-            object: path,
-            interfaces,
-        }) = ObjectManagerInterfacesAdded::from_message(&msg)
-        {
-            let all_interfaces = self.objects.entry(path.clone()).or_default();
-            all_interfaces.extend(interfaces);
-
-            f(&path, &all_interfaces);
-        } else if let Some(ObjectManagerInterfacesRemoved {
-            object: path,
-            interfaces,
-        }) = ObjectManagerInterfacesRemoved::from_message(&msg)
-        {
-            match self.objects.entry(path) {
-                Entry::Occupied(mut e) => {
-                    let obj = e.get_mut();
-                    interfaces.iter().for_each(|i| {
-                        obj.remove(i);
-                    });
-                    if obj.is_empty() {
-                        e.remove();
-                    }
-                }
-                _ => (),
+    ) -> Result<(), BoxErr> {
+        let path = path.clone().into_static();
+        let rule = PropertiesPropertiesChanged::match_str(Some(&dbus::BusName::from(BLUEZ_SERVICE)), Some(&path));
+
+        self.bus.run(move |bluez| bluez.conn.add_match(&rule))??;
+
+        let until = self.timeouts.until(duration.map(|d| Instant::now() + d));
+        let (notified, receiver) = mpsc::channel();
+
+        self.bus.watch(until, move |seen| {
+            let value = match seen {
+                Seen::Signal(msg, _) if msg.path().as_ref() == Some(&path) => PropertiesPropertiesChanged::from_message(msg)
+                    .and_then(|changed| changed.changed_properties.get("Value").and_then(|v| bytes(&v.0))),
+                _ => None,
             };
+
+            value.is_none_or(|value| notified.send(value).is_ok())
+        })?;
+
+        while let Some(value) = receive(&receiver, until) {
+            f(&value)?;
         }
+
+        Ok(self.timeouts.check()?)
     }
 
-    pub fn scan(
-        &mut self,
-        required_uuid: &str,
-        timeout_ms: Option<u32>,
-    ) -> Result<Vec<DBusPath>, BoxErr> {
+    // Devices with the service `required_uuid`, known now or found within `duration`
+    pub fn scan(&self, required_uuid: &str, duration: Duration) -> Result<Vec<DBusPath>, BoxErr> {
         let mut result: Vec<DBusPath> = Vec::new();
-        let conn = self.conn.clone();
+        let timeout = self.timeouts.ms(Phase::Io)?;
+        let required_uuid = required_uuid.to_string();
 
-        self.find_objects(
-            |path, obj| {
+        let found = self.find_objects(
+            move |path, obj| {
                 obj.get(BLUEZ_INTERFACE_DEVICE1)
                     .and_then(|props| props.get("UUIDs"))
-                    .filter(|uuids| {
-                        let mut count = 0;
-
-                        uuids.0.as_iter().as_mut().map(|f| {
-                            f.for_each(|f| {
-                                if f.as_str() == Some(required_uuid) {
-                                    count += 1;
-                                }
-                            });
-                        });
-
-                        count > 0
-                    })
-                    .map(|_| {
-                        result.push(DBusPath {
-                            conn: conn.clone(),
-                            dest: dbus::BusName::from(BLUEZ_SERVICE),
-                            path: path.clone().into_static(),
-                            timeout: 30_000,
-                        });
-                    });
-
-                false
+                    .and_then(|uuids| uuids.0.as_iter())
+                    .and_then(|mut uuids| uuids.find(|uuid| uuid.as_str() == Some(&required_uuid)))
+                    .map(|_| path.clone().into_static())
             },
-            timeout_ms,
-        );
+            duration,
+        )?;
+
+        for path in found {
+            if !result.iter().any(|device| device.path == path) {
+                result.push(self.path(path, timeout));
+            }
+        }
 
         Ok(result)
     }
 
-    pub fn find_by_address(
-        &mut self,
-        hw_addr: &str,
-        timeout_ms: Option<u32>,
-    ) -> Result<DBusPath, BoxErr> {
-        let conn = self.conn.clone();
+    pub fn find_by_address(&self, hw_addr: &str) -> Result<DBusPath, BoxErr> {
+        let resolve = self.timeouts.of(Phase::Resolve)?;
+        let timeout = self.timeouts.ms(Phase::Io)?;
+        let hw_addr = hw_addr.to_string();
+
         self.find_object(
-            |path, obj| {
+            move |path, obj| {
                 obj.get(BLUEZ_INTERFACE_DEVICE1)
                     .and_then(|props| props.get("Address"))
                     .and_then(dbus::arg::Variant::as_str)
-                    .and_then(|a| if a == hw_addr { Some(()) } else { None })
-                    .map(|_| DBusPath {
-                        conn: conn.clone(),
-                        dest: dbus::BusName::from(BLUEZ_SERVICE),
-                        path: path.clone().into_static(),
-                        timeout: 60_000,
-                    })
+                    .filter(|a| *a == hw_addr)
+                    .map(|_| path.clone().into_static())
             },
-            timeout_ms,
-        )
-        .ok_or(Box::new(Error::new(ErrorKind::Other, "Device not found")))
+            resolve,
+        )?
+        .map(|path| self.path(path, timeout))
+        .ok_or_else(|| self.not_found("Device not found"))
     }
 
-    fn find_adapter(&mut self, timeout_ms: Option<u32>) -> Result<DBusPath, BoxErr> {
-        let conn = self.conn.clone();
+    fn find_adapter(&self) -> Result<DBusPath, BoxErr> {
+        let discover = self.timeouts.of(Phase::Discover)?;
+        let timeout = self.timeouts.ms(Phase::Io)?;
 
         self.find_object(
-            |path, obj| {
-                obj.get(BLUEZ_INTERFACE_ADAPTER1).map(|_| DBusPath {
-                    conn: conn.clone(),
-                    dest: dbus::BusName::from(BLUEZ_SERVICE),
-                    path: path.clone().into_static(),
-                    timeout: 60_000,
-                })
-            },
-            timeout_ms,
-        )
-        .ok_or(Box::new(Error::new(ErrorKind::Other, "Adapter not found")))
+            |path, obj| obj.get(BLUEZ_INTERFACE_ADAPTER1).map(|_| path.clone().into_static()),
+            discover,
+        )?
+        .map(|path| self.path(path, timeout))
+        .ok_or_else(|| self.not_found("Adapter not found"))
+    }
+
+    fn path(&self, path: dbus::Path<'static>, timeout: i32) -> DBusPath {
+        DBusPath {
+            bus: self.bus.clone(),
+            path,
+            timeout,
+        }
+    }
+
+    // What wasn't found may well have been there, if the deadline cut the search short
+    fn not_found(&self, message: &str) -> BoxErr {
+        match self.timeouts.check() {
+            Ok(_) => Box::new(Error::new(io::ErrorKind::NotFound, message)),
+            Err(e) => Box::new(e),
+        }
+    }
+}
+
+// What find_objects finds, as it is found. Stops once the time to look has passed
+pub(crate) struct Found<T> {
+    receiver: mpsc::Receiver<T>,
+    until: Option<Instant>,
+}
+
+impl<T> Iterator for Found<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        receive(&self.receiver, self.until)
     }
 }
 
-impl Drop for BluezManager {
+// The next thing sent, if it comes before `until` (None is never)
+fn receive<T>(receiver: &mpsc::Receiver<T>, until: Option<Instant>) -> Option<T> {
+    match until {
+        Some(until) => receiver.recv_timeout(until.saturating_duration_since(Instant::now())).ok(),
+        None => receiver.recv().ok(),
+    }
+}
+
+// A copy of `path` whose calls time out after `timeout_ms`
+pub(crate) fn with_timeout(path: &DBusPath, timeout_ms: i32) -> DBusPath {
+    DBusPath {
+        timeout: timeout_ms,
+        ..path.clone()
+    }
+}
+
+fn device_service_data(props: Option<&DBusProperties>, uuid: &str) -> Option<(String, Vec<u8>)> {
+    let props = props?;
+    let address = props.get("Address").and_then(dbus::arg::Variant::as_str)?;
+    let data = props.get("ServiceData").and_then(|v| service_data(&v.0, uuid))?;
+
+    Some((address.to_string(), data))
+}
+
+// The address and data of a device whose service data for `uuid` changed
+fn changed_service_data(msg: &dbus::Message, objects: &Objects, uuid: &str) -> Option<(String, Vec<u8>)> {
+    let changed = PropertiesPropertiesChanged::from_message(msg)?;
+
+    if changed.interface_name != BLUEZ_INTERFACE_DEVICE1 {
+        return None;
+    }
+
+    let address = objects
+        .get(&msg.path()?.into_static())
+        .and_then(|obj| obj.get(BLUEZ_INTERFACE_DEVICE1))
+        .and_then(|props| props.get("Address"))
+        .and_then(dbus::arg::Variant::as_str)?;
+    let data = changed
+        .changed_properties
+        .get("ServiceData")
+        .and_then(|v| service_data(&v.0, uuid))?;
+
+    Some((address.to_string(), data))
+}
+
+// ServiceData is a dict of uuid -> variant holding the bytes
+fn service_data(value: &dyn RefArg, uuid: &str) -> Option<Vec<u8>> {
+    let mut iter = value.as_iter()?;
+
+    while let (Some(key), Some(data)) = (iter.next(), iter.next()) {
+        if key.as_str() == Some(uuid) {
+            return bytes(data.as_iter()?.next()?);
+        }
+    }
+
+    None
+}
+
+// An array of bytes (ay)
+pub(crate) fn bytes(value: &dyn RefArg) -> Option<Vec<u8>> {
+    Some(value.as_iter()?.filter_map(|b| b.as_u64()).map(|b| b as u8).collect())
+}
+
+// What the D-Bus thread is asked to do
+enum Request {
+    /// Send a method call, and answer with its reply
+    Call {
+        msg: dbus::Message,
+        until: Instant,
+        reply: mpsc::Sender<Result<dbus::Message, dbus::Error>>,
+    },
+    Run(Box<dyn FnOnce(&mut Bluez) + Send>),
+    /// Show every object and signal to a watch, until `until` or the watch returns false
+    Watch {
+        until: Option<Instant>,
+        f: Box<dyn FnMut(Seen) -> bool + Send>,
+    },
+    Stop,
+}
+
+// What a watch is shown
+enum Seen<'a> {
+    /// Every object known when the watch starts, and every object added later (with all of its
+    /// interfaces)
+    Object(&'a dbus::Path<'static>, &'a DBusObject),
+    /// Any other signal, with the objects known
+    Signal(&'a dbus::Message, &'a Objects),
+}
+
+// The way to the D-Bus thread. Clones share the thread, which stops when the last is dropped
+#[derive(Debug, Clone)]
+struct Bus {
+    link: Arc<Link>,
+}
+
+#[derive(Debug)]
+struct Link {
+    requests: mpsc::Sender<Request>,
+    /// Written to after every request, to wake the thread up
+    wake: UnixStream,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Bus {
+    // Starts the D-Bus thread, with the connection made by `connect`
+    fn start<F>(connect: F) -> Result<Bus, BoxErr>
+    where
+        F: FnOnce() -> Result<Bluez, dbus::Error> + Send + 'static,
+    {
+        let (requests, received) = mpsc::channel();
+        let (wake, woken) = UnixStream::pair()?;
+        let (started, start) = mpsc::channel();
+
+        wake.set_nonblocking(true)?;
+        woken.set_nonblocking(true)?;
+
+        let thread = thread::Builder::new().name("dbus".to_string()).spawn(move || match connect() {
+            Ok(bluez) => {
+                started.send(Ok(())).ok();
+                bluez.run(received, woken);
+            }
+            Err(e) => {
+                started.send(Err(e)).ok();
+            }
+        })?;
+
+        let bus = Bus {
+            link: Arc::new(Link {
+                requests,
+                wake,
+                thread: Some(thread),
+            }),
+        };
+
+        start.recv().map_err(|_| stopped())??;
+
+        Ok(bus)
+    }
+
+    fn send(&self, request: Request) -> Result<(), dbus::Error> {
+        self.link.send(request)
+    }
+
+    // Sends a method call, and waits up to `timeout_ms` for its reply
+    fn call(&self, msg: dbus::Message, timeout_ms: i32) -> Result<dbus::Message, dbus::Error> {
+        let (reply, answer) = mpsc::channel();
+        let until = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+
+        self.send(Request::Call { msg, until, reply })?;
+
+        let mut msg = answer.recv().map_err(|_| stopped())??;
+
+        msg.as_result()?;
+
+        Ok(msg)
+    }
+
+    // Runs `f` on the D-Bus thread, and waits for what it returns
+    fn run<T, F>(&self, f: F) -> Result<T, dbus::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Bluez) -> T + Send + 'static,
+    {
+        let (done, result) = mpsc::channel();
+
+        self.send(Request::Run(Box::new(move |bluez| {
+            done.send(f(bluez)).ok();
+        })))?;
+
+        result.recv().map_err(|_| stopped())
+    }
+
+    fn watch<F>(&self, until: Option<Instant>, f: F) -> Result<(), dbus::Error>
+    where
+        F: FnMut(Seen) -> bool + Send + 'static,
+    {
+        self.send(Request::Watch { until, f: Box::new(f) })
+    }
+}
+
+impl Link {
+    fn send(&self, request: Request) -> Result<(), dbus::Error> {
+        self.requests.send(request).map_err(|_| stopped())?;
+
+        // A full socket wakes the thread as well
+        (&self.wake).write_all(&[0]).ok();
+
+        Ok(())
+    }
+}
+
+impl Drop for Link {
     fn drop(&mut self) {
-        if let Some(ref adapter) = self.adapter {
-            adapter.stop_discovery().ok();
+        self.send(Request::Stop).ok();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn stopped() -> dbus::Error {
+    dbus::Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "D-Bus thread has stopped")
+}
+
+// A method call waiting for its reply
+struct Call {
+    until: Instant,
+    reply: mpsc::Sender<Result<dbus::Message, dbus::Error>>,
+}
+
+struct Watch {
+    until: Option<Instant>,
+    f: Box<dyn FnMut(Seen) -> bool + Send>,
+}
+
+// The D-Bus connection and what is known about the BlueZ objects, on the D-Bus thread. A
+// connection can't be used from more than one thread, so every other thread sends it requests.
+// Method calls are sent without waiting for their reply, so the calls of several threads are
+// underway at once
+struct Bluez {
+    conn: Connection,
+    objects: Objects,
+    /// By serial
+    calls: HashMap<u32, Call>,
+    watches: Vec<Watch>,
+    /// Discovering, until the thread stops. Shared with other hat processes when the lock
+    /// directory can be used
+    adapter: Option<(dbus::Path<'static>, i32, Option<DiscoveryRef>)>,
+}
+
+impl Bluez {
+    fn connect(timeout_ms: i32) -> Result<Bluez, dbus::Error> {
+        let conn = Connection::get_private(BusType::System)?;
+
+        let bus_name = dbus::BusName::from(BLUEZ_SERVICE);
+        let root_path = dbus::Path::from("/");
+
+        let objects = conn.with_path(BLUEZ_SERVICE, &root_path, timeout_ms).get_managed_objects()?;
+
+        conn.add_match(&ObjectManagerInterfacesAdded::match_str(
+            Some(&bus_name),
+            Some(&root_path),
+        ))?;
+        conn.add_match(&ObjectManagerInterfacesRemoved::match_str(
+            Some(&bus_name),
+            Some(&root_path),
+        ))?;
+
+        Ok(Bluez {
+            conn,
+            objects,
+            calls: HashMap::new(),
+            watches: Vec::new(),
+            adapter: None,
+        })
+    }
+
+    fn run(mut self, requests: mpsc::Receiver<Request>, woken: UnixStream) {
+        loop {
+            loop {
+                match requests.try_recv() {
+                    Ok(Request::Stop) | Err(TryRecvError::Disconnected) => return self.stop(),
+                    Ok(request) => self.handle(request),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            loop {
+                let msg = self.conn.incoming(0).next();
+
+                match msg {
+                    Some(msg) => self.dispatch(msg),
+                    None => break,
+                }
+            }
+
+            self.expire();
+            self.wait(&woken);
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Call { msg, until, reply } => match self.conn.send(msg) {
+                Ok(serial) => {
+                    self.calls.insert(serial, Call { until, reply });
+                }
+                Err(()) => {
+                    reply
+                        .send(Err(dbus::Error::new_custom("org.freedesktop.DBus.Error.Failed", "Cannot send message")))
+                        .ok();
+                }
+            },
+            Request::Run(f) => f(self),
+            Request::Watch { until, mut f } => {
+                if self.objects.iter().all(|(path, obj)| f(Seen::Object(path, obj))) {
+                    self.watches.push(Watch { until, f });
+                }
+            }
+            Request::Stop => (),
+        }
+    }
+
+    fn dispatch(&mut self, msg: dbus::Message) {
+        match msg.msg_type() {
+            MessageType::MethodReturn | MessageType::Error => {
+                if let Some(call) = msg.get_reply_serial().and_then(|serial| self.calls.remove(&serial)) {
+                    // The caller may have given up already
+                    call.reply.send(Ok(msg)).ok();
+                }
+            }
+            MessageType::Signal => {
+                let objects = &mut self.objects;
+
+                if let Some(ObjectManagerInterfacesAdded {
+                    object: path,
+                    interfaces,
+                }) = ObjectManagerInterfacesAdded::from_message(&msg)
+                {
+                    let all_interfaces = objects.entry(path.clone()).or_default();
+                    all_interfaces.extend(interfaces);
+
+                    self.watches.retain_mut(|watch| (watch.f)(Seen::Object(&path, all_interfaces)));
+                } else if let Some(ObjectManagerInterfacesRemoved {
+                    object: path,
+                    interfaces,
+                }) = ObjectManagerInterfacesRemoved::from_message(&msg)
+                {
+                    if let Entry::Occupied(mut e) = objects.entry(path) {
+                        let obj = e.get_mut();
+                        interfaces.iter().for_each(|i| {
+                            obj.remove(i);
+                        });
+                        if obj.is_empty() {
+                            e.remove();
+                        }
+                    }
+                } else {
+                    self.watches.retain_mut(|watch| (watch.f)(Seen::Signal(&msg, objects)));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Calls without a reply in time get a NoReply error, as they would from libdbus
+    fn expire(&mut self) {
+        let now = Instant::now();
+
+        self.calls.retain(|_, call| {
+            if call.until > now {
+                return true;
+            }
+
+            call.reply
+                .send(Err(dbus::Error::new_custom(
+                    "org.freedesktop.DBus.Error.NoReply",
+                    "Did not receive a reply",
+                )))
+                .ok();
+
+            false
+        });
+        self.watches.retain(|watch| watch.until.is_none_or(|until| until > now));
+    }
+
+    // Waits for a request, a message, or the first call or watch to run out - a second at most
+    fn wait(&self, woken: &UnixStream) {
+        let now = Instant::now();
+        let until = self
+            .calls
+            .values()
+            .map(|call| call.until)
+            .chain(self.watches.iter().filter_map(|watch| watch.until))
+            .fold(now + Duration::from_secs(1), Instant::min);
+        let mut fds = vec![libc::pollfd {
+            fd: woken.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+
+        // A connection that was closed would wake us up over and over
+        if self.conn.is_connected() {
+            fds.extend(self.conn.watch_fds().iter().filter(|watch| watch.readable()).map(|watch| libc::pollfd {
+                fd: watch.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }));
+        }
+
+        let timeout_ms = until.saturating_duration_since(now).as_millis().clamp(1, 1000) as libc::c_int;
+
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+
+        let mut buf = [0; 64];
+
+        while matches!((&*woken).read(&mut buf), Ok(n) if n > 0) {}
+    }
+
+    fn stop(mut self) {
+        if let Some((path, timeout, discovery)) = self.adapter.take() {
+            // Others still discovering would be left without
+            if discovery.map_or(Ok(true), DiscoveryRef::release).unwrap_or(true) {
+                self.conn.with_path(BLUEZ_SERVICE, path, timeout).stop_discovery().ok();
+            } else {
+                debug!("Leaving discovery on for other hat processes");
+            }
         }
     }
 }
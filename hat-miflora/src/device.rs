@@ -1,20 +1,25 @@
-use std::collections::HashMap;
-use std::io::{Cursor, Read};
+use std::cell::{Cell, RefCell};
+use std::io::Cursor;
 use std::{error, thread};
 
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 use dbus::arg::RefArg;
 use serde::export::Formatter;
+use serde::Serialize;
 
-use dbus_common::org_bluez_device1::OrgBluezDevice1;
-use dbus_common::org_bluez_gatt_characteristic1::OrgBluezGattCharacteristic1;
+use dbus_common::adapter_lock::{self, OperationLock};
+use hat_common::exit::ErrorKind;
 
 use crate::dbus_bluez::{
-    BluezManager, DBusPath, TypedDbusError, BLUEZ_GATT_CHARACTERISTIC_INTERFACE, BLUEZ_SERVICE,
+    self, BluezManager, DBusPath, TypedDbusError, TypedDbusErrorKind, BLUEZ_GATT_CHARACTERISTIC_INTERFACE,
 };
+use crate::mibeacon::{self, MIBEACON_SERVICE_UUID};
+use crate::plant_sensor::{self, Characteristic, GattSensor, PlantSensor};
+use crate::protocol::{FirmwareVersion, Protocol};
+use crate::timeouts::Phase;
 use std::time::Duration;
 
-pub(crate) const XIAOMI_MIFLORA_SERVICE_UUID: &str = "0000fe95-0000-1000-8000-00805f9b34fb";
+const DISCONNECT_AFTER_DEADLINE_MS: i32 = 1000;
 
 #[derive(Debug)]
 pub(crate) enum Error {
@@ -25,6 +30,9 @@ pub(crate) enum Error {
     InvalidData {
         cause: std::io::Error,
     },
+    Timeout {
+        cause: std::io::Error,
+    },
     ErrorConnecting {
         cause: TypedDbusError,
     },
@@ -46,11 +54,50 @@ pub(crate) enum Error {
     DBusError {
         cause: TypedDbusError,
     },
+    UnsupportedFirmware {
+        version: String,
+        feature: &'static str,
+    },
+    UnsupportedModel {
+        model: &'static str,
+        cause: &'static str,
+    },
     ThisShouldNeverHappend,
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        let (kind, cause) = match self {
+            Error::GATTAttributeNotFound { .. } => (ErrorKind::NotFound, None),
+            Error::InvalidData { .. } => (ErrorKind::InvalidData, None),
+            Error::Timeout { .. } => (ErrorKind::Timeout, None),
+            Error::ErrorConnecting { cause } => (ErrorKind::ConnectFailed, Some(cause)),
+            Error::ErrorDisconnecting { cause } => (ErrorKind::Failed, Some(cause)),
+            Error::ErrorReadingData { cause, .. } => (ErrorKind::ReadFailed, Some(cause)),
+            Error::ErrorWritingData { cause, .. } => (ErrorKind::WriteFailed, Some(cause)),
+            Error::DBusError { cause } => (ErrorKind::Failed, Some(cause)),
+            Error::UnsupportedFirmware { .. } | Error::UnsupportedModel { .. } => (ErrorKind::Failed, None),
+            Error::ThisShouldNeverHappend => (ErrorKind::Failed, None),
+        };
+
+        cause.and_then(|cause| cause.kind.error_kind()).unwrap_or(kind)
+    }
+}
+
+// The kind of the errors talking to a device can end in, for exit::error_kind
+pub(crate) fn error_kind(e: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        Some(e.kind())
+    } else if let Some(e) = e.downcast_ref::<TypedDbusError>() {
+        Some(e.kind.error_kind().unwrap_or(ErrorKind::Failed))
+    } else {
+        e.downcast_ref::<dbus::Error>()
+            .map(|e| TypedDbusErrorKind::of(e).error_kind().unwrap_or(ErrorKind::Failed))
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "{:?}", self)
@@ -58,11 +105,16 @@ impl std::fmt::Display for Error {
 }
 
 #[derive(Debug)]
-pub(crate) struct Miflora<'a> {
-    manager: &'a mut BluezManager,
+pub(crate) struct Miflora {
+    manager: BluezManager,
     device: DBusPath,
     firmware: Option<DBusPath>,
-    firmware_raw: Option<Vec<u8>>,
+    /// Battery and firmware version share a characteristic, so it is only read once per connection
+    firmware_raw: RefCell<Option<Vec<u8>>>,
+    /// Detected from the advertisements or the name of the device
+    sensor: &'static dyn PlantSensor,
+    /// Detected on connect, from the firmware version
+    protocol: Cell<Protocol>,
     device_mode: Option<DBusPath>,
     device_data: Option<DBusPath>,
     device_time: Option<DBusPath>,
@@ -81,39 +133,49 @@ enum MifloraDeviceHistoryMode {
     ReadRecord(u16),
 }
 
+#[derive(Serialize, Clone)]
 pub(crate) struct RealtimeReadings {
     pub temperature: f32,
-    pub lux: u32,
+    /// None for models without a light sensor
+    pub lux: Option<u32>,
     pub moisture: u8,
     pub conductivity: u16,
+    /// Bytes 2 and 10-15, whose meaning isn't known
+    #[serde(skip)]
+    pub unknown: Vec<u8>,
+    /// Read right after switching to realtime mode
+    #[serde(skip)]
+    pub after_mode_switch: bool,
 }
 
 pub(crate) struct HistoryReadings {
     pub record_number: u16,
     pub epoch: u32,
     pub temperature: f32,
-    pub lux: u32,
+    /// None for models without a light sensor
+    pub lux: Option<u32>,
     pub moisture: u8,
     pub conductivity: u16,
+    /// Bytes 6, 10 and 14-15, whose meaning isn't known
+    pub unknown: Vec<u8>,
 }
 
-impl<'a> Miflora<'a> {
-    const FIRMWARE_CHARACTERISTIC_UUID: &'static str = "00001a02-0000-1000-8000-00805f9b34fb";
-    const DEVICE_MODE_CHARACTERISTIC_UUID: &'static str = "00001a00-0000-1000-8000-00805f9b34fb";
-    const DEVICE_DATA_CHARACTERISTIC_UUID: &'static str = "00001a01-0000-1000-8000-00805f9b34fb";
-    const DEVICE_TIME_CHARACTERISTIC_UUID: &'static str = "00001a12-0000-1000-8000-00805f9b34fb";
-    const HISTORY_MODE_CHARACTERISTIC_UUID: &'static str = "00001a10-0000-1000-8000-00805f9b34fb";
-    const HISTORY_DATA_CHARACTERISTIC_UUID: &'static str = "00001a11-0000-1000-8000-00805f9b34fb";
-
+impl Miflora {
     pub fn new(
         device: DBusPath,
-        manager: &'a mut BluezManager,
+        manager: &BluezManager,
     ) -> Result<Miflora, Box<dyn error::Error>> {
+        let sensor = plant_sensor::detect(advertised_product_id(&device), device.get_name().ok().as_deref());
+
+        debug!("{:} model: {:}", device.path, sensor.name());
+
         Ok(Miflora {
-            manager,
+            manager: manager.clone(),
+            sensor,
             device,
             firmware: None,
-            firmware_raw: None,
+            firmware_raw: RefCell::new(None),
+            protocol: Cell::new(Protocol::new(None)),
             device_mode: None,
             device_data: None,
             device_time: None,
@@ -125,27 +187,37 @@ impl<'a> Miflora<'a> {
     pub fn connect(&mut self) -> Result<(), Box<dyn error::Error>> {
         info!("{:} connect()", self.device.path);
 
-        self.device.connect()?;
+        self.firmware_raw.replace(None);
+        self.connect_device()?;
 
-        if !self.device.get_connected()? {
+        if !self.timed(&self.device, Phase::Io)?.get_connected()? {
             error!("Can't connect to device");
 
-            return Ok(()); // FIXME
+            return Err(Box::new(Error::ErrorConnecting {
+                cause: TypedDbusError::from(dbus::Error::new_custom(
+                    "org.bluez.Error.Failed",
+                    "Not connected after connecting",
+                )),
+            }));
         }
 
-        self.find_gatt_attributes();
+        // A model that can't be read is only connected to, for the raw commands
+        if self.sensor.gatt().is_some() {
+            self.find_gatt_attributes()?;
+            self.detect_protocol();
+        }
 
         // debug!("DMP DEBUG DeviceMode={:?}, HistoryMode={:?}",
         //        self.read_attr(
         //            &self.device_mode,
         //            "device mode",
-        //            Self::DEVICE_MODE_CHARACTERISTIC_UUID,
+        //            self.sensor.layout().device_mode,
         //            |v| Ok(v.get_ref().clone())
         //        ),
         //        self.read_attr(
         //            &self.history_mode,
         //            "history mode",
-        //            Self::HISTORY_MODE_CHARACTERISTIC_UUID,
+        //            self.sensor.layout().history_mode,
         //            |v| Ok(v.get_ref().clone())
         //        )
         // );
@@ -159,71 +231,231 @@ impl<'a> Miflora<'a> {
         Ok(())
     }
 
+    // Starts over with a new connection, after the old one went bad
+    pub fn reconnect(&mut self) -> Result<(), Box<dyn error::Error>> {
+        info!("{:} reconnect()", self.device.path);
+
+        // the connection may be gone already
+        self.disconnect().ok();
+
+        thread::sleep(self.manager.timeouts().cut(Duration::from_secs(2)));
+
+        self.connect()
+    }
+
     pub fn disconnect(&mut self) -> Result<(), Error> {
         debug!("disconnect: {:?}", self.device.path);
-        self.device.disconnect().map_err(|err| Error::DBusError {
+        self.firmware_raw.replace(None);
+
+        // Also once the deadline has passed, so the device isn't left connected
+        let timeout = self.manager.timeouts().ms(Phase::Io).unwrap_or(DISCONNECT_AFTER_DEADLINE_MS);
+
+        dbus_bluez::with_timeout(&self.device, timeout).disconnect().map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    // Connects after whoever - another hat process, or thread - is connecting through the same
+    // adapter, as BlueZ doesn't do two connects at once. Without a usable lock directory it just
+    // connects
+    fn connect_device(&self) -> Result<(), Error> {
+        let adapter = adapter_lock::adapter_name(&self.device.path).unwrap_or("hci0");
+        let _turn = match OperationLock::acquire(adapter, self.manager.timeouts().left()) {
+            Ok(turn) => Some(turn),
+            Err(cause) if cause.kind() == std::io::ErrorKind::TimedOut => return Err(Error::Timeout { cause }),
+            Err(e) => {
+                warn!("Connecting without waiting for other hat processes: {:}", e);
+                None
+            }
+        };
+
+        self.timed(&self.device, Phase::Connect)?.connect().map_err(|err| Error::ErrorConnecting {
             cause: TypedDbusError::from(err),
         })
     }
 
+    // The path with the timeout of `phase`, cut to the deadline
+    fn timed(&self, path: &DBusPath, phase: Phase) -> Result<DBusPath, Error> {
+        let timeout = self.manager.timeouts().ms(phase).map_err(|cause| Error::Timeout { cause })?;
+
+        Ok(dbus_bluez::with_timeout(path, timeout))
+    }
+
+    pub fn get_sensor(&self) -> &'static dyn PlantSensor {
+        self.sensor
+    }
+
+    // How the model is read over a connection
+    fn gatt(&self) -> Result<&'static dyn GattSensor, Error> {
+        self.sensor.gatt().ok_or(Error::UnsupportedModel {
+            model: self.sensor.name(),
+            cause: "what it keeps in which characteristic isn't known (listen reads its advertisements)",
+        })
+    }
+
     pub fn get_address(&self) -> Result<String, Error> {
-        OrgBluezDevice1::get_address(&self.device).map_err(|err| Error::DBusError {
+        self.timed(&self.device, Phase::Io)?.get_address().map_err(|err| Error::DBusError {
             cause: TypedDbusError::from(err),
         })
     }
 
     pub fn get_rssi(&self) -> Result<i16, Error> {
-        OrgBluezDevice1::get_rssi(&self.device).map_err(|err| Error::DBusError {
+        self.timed(&self.device, Phase::Io)?.get_rssi().map_err(|err| Error::DBusError {
             cause: TypedDbusError::from(err),
         })
     }
 
     pub fn get_name(&self) -> Result<String, Error> {
-        OrgBluezDevice1::get_name(&self.device).map_err(|err| Error::DBusError {
+        self.timed(&self.device, Phase::Io)?.get_name().map_err(|err| Error::DBusError {
             cause: TypedDbusError::from(err),
         })
     }
 
     pub fn get_alias(&self) -> Result<String, Error> {
-        OrgBluezDevice1::get_alias(&self.device).map_err(|err| Error::DBusError {
+        self.timed(&self.device, Phase::Io)?.get_alias().map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    pub fn set_alias(&self, alias: &str) -> Result<(), Error> {
+        self.timed(&self.device, Phase::Io)?.set_alias(alias.to_string()).map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    pub fn get_address_type(&self) -> Result<String, Error> {
+        self.timed(&self.device, Phase::Io)?.get_address_type().map_err(|err| Error::DBusError {
             cause: TypedDbusError::from(err),
         })
     }
 
+    pub fn get_tx_power(&self) -> Result<i16, Error> {
+        self.timed(&self.device, Phase::Io)?.get_tx_power().map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    pub fn get_paired(&self) -> Result<bool, Error> {
+        self.timed(&self.device, Phase::Io)?.get_paired().map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    pub fn get_trusted(&self) -> Result<bool, Error> {
+        self.timed(&self.device, Phase::Io)?.get_trusted().map_err(|err| Error::DBusError {
+            cause: TypedDbusError::from(err),
+        })
+    }
+
+    // Name and path of every characteristic used, or None for those that weren't found
+    pub fn get_characteristic_paths(&self) -> Vec<(&'static str, Option<String>)> {
+        let path = |attr: &Option<DBusPath>| attr.as_ref().map(|c| c.path.to_string());
+
+        vec![
+            ("firmware", path(&self.firmware)),
+            ("device_mode", path(&self.device_mode)),
+            ("device_data", path(&self.device_data)),
+            ("device_time", path(&self.device_time)),
+            ("history_mode", path(&self.history_mode)),
+            ("history_data", path(&self.history_data)),
+        ]
+    }
+
     pub fn get_firmware_version(&self) -> Result<String, Error> {
-        // TODO: Don't reread the same info twice
-        self.read_attr(
-            &self.firmware,
-            "Firmware info",
-            Self::FIRMWARE_CHARACTERISTIC_UUID,
-            |v| {
-                String::from_utf8(v.get_ref()[2..7].to_vec())
-                    .map_err(|_| panic!("Cannot convert to utf8"))
-            },
-        )
+        let raw = self.read_firmware()?;
+        let version = raw.get(2..7).ok_or_else(|| Self::invalid_data("firmware info too short"))?;
+
+        String::from_utf8(version.to_vec()).map_err(|_| Self::invalid_data("firmware version isn't utf8"))
     }
 
     pub fn get_battery_pct(&self) -> Result<u8, Error> {
-        // TODO: Don't reread the same info twice
-        self.read_attr(
+        let raw = self.read_firmware()?;
+
+        raw.first().copied().ok_or_else(|| Self::invalid_data("firmware info is empty"))
+    }
+
+    fn read_firmware(&self) -> Result<Vec<u8>, Error> {
+        if let Some(ref raw) = *self.firmware_raw.borrow() {
+            return Ok(raw.clone());
+        }
+
+        let raw = self.read_attr(
             &self.firmware,
             "Firmware info",
-            Self::FIRMWARE_CHARACTERISTIC_UUID,
-            |v| Ok(v.get_ref()[0]),
-        )
+            self.gatt()?.layout().firmware,
+            |v| Ok(v.into_inner()),
+        )?;
+
+        self.firmware_raw.replace(Some(raw.clone()));
+
+        Ok(raw)
+    }
+
+    // Older firmwares talk a slightly different protocol. A version that can't be read or isn't
+    // understood gets the newest one
+    fn detect_protocol(&self) {
+        let version = match self.get_firmware_version() {
+            Ok(version) => version.parse::<FirmwareVersion>().map_err(|e| warn!("{:}", e)).ok(),
+            Err(e) => {
+                warn!("Cannot read firmware version: {:}", e);
+                None
+            }
+        };
+        let protocol = match self.sensor.gatt() {
+            Some(gatt) => gatt.protocol(version),
+            None => return,
+        };
+
+        debug!("{:} protocol: {:?}", self.device.path, protocol);
+
+        self.protocol.set(protocol);
+    }
+
+    fn invalid_data(message: &str) -> Error {
+        Error::InvalidData {
+            cause: std::io::Error::new(std::io::ErrorKind::InvalidData, message),
+        }
     }
 
     pub fn get_realtime_reading(&self) -> Result<RealtimeReadings, Error> {
-        self.set_device_mode(MifloraDeviceMode::Realtime)?;
+        let mode_write = self.protocol.get().realtime_mode_write;
+
+        if mode_write {
+            self.set_device_mode(MifloraDeviceMode::Realtime)?;
+        }
+
+        let mut readings = self.read_realtime()?;
+
+        readings.after_mode_switch = mode_write;
+
+        Ok(readings)
+    }
+
+    // Switches to realtime mode for read_realtime. The first reading after the switch is sometimes
+    // stale (moisture 0), so that one is thrown away
+    pub fn start_realtime(&self) -> Result<(), Error> {
+        self.get_realtime_reading()?;
+
+        Ok(())
+    }
+
+    // Reads realtime data, without switching mode first (see start_realtime)
+    pub fn read_realtime(&self) -> Result<RealtimeReadings, Error> {
+        let gatt = self.gatt()?;
 
         self.read_attr(
             &self.device_data,
             "Device Realtime readout",
-            Self::DEVICE_DATA_CHARACTERISTIC_UUID,
-            |v| self.decode_realtime_data(&mut v.clone()),
+            gatt.layout().device_data,
+            |v| gatt.decode_realtime(v.get_ref()),
         )
     }
 
+    // Waits, while keeping up with D-Bus
+    pub fn idle(&mut self, duration: Duration) {
+        self.manager.idle(duration);
+    }
+
     pub fn blink(&self) -> Result<(), Error> {
         self.set_device_mode(MifloraDeviceMode::Blink)?;
         thread::sleep(Duration::from_millis(1000));
@@ -235,7 +467,7 @@ impl<'a> Miflora<'a> {
         self.read_attr(
             &self.device_time,
             "Device time",
-            Self::DEVICE_TIME_CHARACTERISTIC_UUID,
+            self.gatt()?.layout().device_time,
             |mut v| v.read_u32::<LittleEndian>(),
         )
     }
@@ -253,142 +485,183 @@ impl<'a> Miflora<'a> {
         self.read_attr(
             &self.history_data,
             "history date",
-            Self::HISTORY_DATA_CHARACTERISTIC_UUID,
+            self.gatt()?.layout().history_data,
             |mut v| v.read_u16::<LittleEndian>(),
         )
     }
 
-    pub fn get_history_records(&self, from: u16, to: u16) -> Result<Vec<HistoryReadings>, Error> {
-        let mut result = Vec::new();
-
+    // Reads the history records `from` up to `to`, handing every record to `f` as soon as it is read
+    pub fn for_each_history_record<F>(&self, from: u16, to: u16, mut f: F) -> Result<(), Box<dyn error::Error>>
+    where
+        F: FnMut(HistoryReadings) -> Result<(), Box<dyn error::Error>>,
+    {
         self.set_device_history_mode(MifloraDeviceHistoryMode::Init)?;
 
         for idx in from..to {
-            let record = self.read_history_record(idx)?;
-
-            if let Some(mut record) = record {
+            if let Some(mut record) = self.read_history_record(idx)? {
                 record.record_number = idx;
 
-                result.push(record);
+                f(record)?;
             }
         }
 
-        Ok(result)
-    }
-
-    pub fn clear_history(&self) -> Result<(), Error> {
-        self.set_device_history_mode(MifloraDeviceHistoryMode::Init)?;
-        self.set_device_history_mode(MifloraDeviceHistoryMode::Clear)?;
-
         Ok(())
     }
 
-    fn read_history_record(&self, idx: u16) -> Result<Option<HistoryReadings>, Error> {
-        debug!("Reading history record #{:?}", idx);
+    // The device time of a history record, if it has one
+    pub fn get_history_record_epoch(&self, number: u16) -> Result<Option<u32>, Box<dyn error::Error>> {
+        let mut epoch = None;
 
-        self.set_device_history_mode(MifloraDeviceHistoryMode::ReadRecord(idx))?;
+        self.for_each_history_record(number, number + 1, |record| {
+            epoch = Some(record.epoch);
+            Ok(())
+        })?;
 
-        self.read_attr(
-            &self.history_data,
-            "history record",
-            Self::HISTORY_DATA_CHARACTERISTIC_UUID,
-            |v| self.decode_history_data(&mut v.clone()),
-        )
+        Ok(epoch)
     }
 
-    fn decode_history_data(
-        &self,
-        data: &mut Cursor<Vec<u8>>,
-    ) -> Result<Option<HistoryReadings>, std::io::Error> {
-        debug!("read: {:?}", data);
-
-        // Lets check if its invalid data
-        {
-            let mut raw: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-            data.clone().read(&mut raw)?;
-
-            if raw == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] {
-                debug!("Zero record, ignoring");
-
-                return Ok(None);
-            }
-            if raw == [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255] {
-                debug!("0xFF record, ignoring");
-
-                return Ok(None);
-            }
-        }
-
-
-        // byte 0-3
-        let history_epoch_time = data.read_u32::<LittleEndian>()?;
+    // Looks up a characteristic of this device by UUID - known to Miflora or not
+    pub fn find_characteristic(&mut self, uuid: &str) -> Result<DBusPath, Error> {
+        let prefix = format!("{}/", self.device.path);
+        let wanted = uuid.to_string();
+        let resolve = self.manager.timeouts().of(Phase::Resolve).map_err(|cause| Error::Timeout { cause })?;
+
+        let found = self
+            .manager
+            .find_objects(
+                move |path, obj| {
+                    let matches = path.starts_with(&prefix)
+                        && obj
+                            .get(BLUEZ_GATT_CHARACTERISTIC_INTERFACE)
+                            .and_then(|props| props.get("UUID"))
+                            .and_then(dbus::arg::Variant::as_str)
+                            .is_some_and(|u| u.eq_ignore_ascii_case(&wanted));
+
+                    if matches {
+                        Some(path.clone().into_static())
+                    } else {
+                        None
+                    }
+                },
+                resolve,
+            )
+            .map_err(|err| Error::DBusError {
+                cause: TypedDbusError::from(err),
+            })?
+            .next();
+
+        found.map(|path| self.device.at(path)).ok_or_else(|| Error::GATTAttributeNotFound {
+            name: "characteristic".to_string(),
+            uuid: uuid.to_string(),
+        })
+    }
 
-        // byte 4-5 temperature in 0.1 degree celcius
-        let temperature = data.read_u16::<LittleEndian>()? as f32 * 0.1;
+    pub fn read_raw(&self, characteristic: &DBusPath, uuid: &str) -> Result<Vec<u8>, Error> {
+        self.read_attr(&Some(characteristic.clone()), "raw read", uuid, |v| Ok(v.into_inner()))
+    }
 
-        // byte 6 - unknown
-        let _unknown = data.read_u8()?;
+    pub fn write_raw(&self, characteristic: &DBusPath, uuid: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.write_attr(&Some(characteristic.clone()), value, "raw write", uuid, |v| v)
+    }
 
-        // byte 7-9 brightness in lux
-        let lux1: u32 = data.read_u8()?.into();
-        let lux2: u32 = data.read_u8()?.into();
-        let lux3: u32 = data.read_u8()?.into();
-        let mut lux = lux3;
-        lux = lux << 8;
-        lux = lux + lux2;
-        lux = lux << 8;
-        lux = lux + lux1;
-        lux = u32::from_le(lux);
+    // Hands every notification of the characteristic to `f`, until `duration` has passed
+    // (forever if None)
+    pub fn notify_raw<F>(
+        &mut self,
+        characteristic: &DBusPath,
+        duration: Option<Duration>,
+        f: F,
+    ) -> Result<(), Box<dyn error::Error>>
+    where
+        F: FnMut(&[u8]) -> Result<(), Box<dyn error::Error>>,
+    {
+        characteristic.start_notify()?;
 
-        // byte 10 - unknown
-        let _unknown2 = data.read_u8()?;
+        let result = self.manager.listen_value(&characteristic.path, duration, f);
 
-        // byte 11 - moisture in procent
-        let moisture = data.read_u8()?;
+        characteristic.stop_notify().ok();
 
-        // byte 12-13 - conductivity µS/cm
-        let conductivity = data.read_u16::<LittleEndian>()?;
+        result
+    }
 
-        // byte 14-15 - unknown
-        let _unknown3 = data.read_u16::<LittleEndian>()?;
+    // What a value of one of the known characteristics means, for debugging
+    pub fn describe(sensor: &dyn PlantSensor, uuid: &str, data: &[u8]) -> Option<String> {
+        let mut rdr = Cursor::new(data.to_vec());
+
+        let gatt = sensor.gatt()?;
+        let description = match gatt.layout().characteristic(uuid)? {
+            Characteristic::Firmware => format!(
+                "firmware info: battery {:} %, firmware version {:}",
+                data.first()?,
+                String::from_utf8_lossy(data.get(2..7)?)
+            ),
+            Characteristic::DeviceMode => match data {
+                [0xa0, 0x1f, ..] => "device mode: realtime".to_string(),
+                [0xfd, 0xff, ..] => "device mode: blink".to_string(),
+                _ => "device mode: unknown".to_string(),
+            },
+            Characteristic::DeviceData => {
+                let r = gatt.decode_realtime(data).ok()?;
+
+                format!(
+                    "realtime data: temperature {:} °C, lux {:}, moisture {:} %, conductivity {:} µS/cm",
+                    r.temperature,
+                    describe_lux(r.lux),
+                    r.moisture,
+                    r.conductivity
+                )
+            }
+            Characteristic::DeviceTime => {
+                format!("device time: {:} s since boot", rdr.read_u32::<LittleEndian>().ok()?)
+            }
+            Characteristic::HistoryMode => match data {
+                [0xa0, ..] => "history mode: init".to_string(),
+                [0xa1, lo, hi, ..] => format!("history mode: read record {:}", LittleEndian::read_u16(&[*lo, *hi])),
+                [0xa2, ..] => "history mode: clear".to_string(),
+                _ => "history mode: unknown".to_string(),
+            },
+            // A record after "read record", the record count after "init"
+            Characteristic::HistoryData if data.len() >= 16 => {
+                match gatt.decode_history(data).ok()? {
+                    Some(r) => format!(
+                        "history record: device time {:} s, temperature {:} °C, lux {:}, moisture {:} %, conductivity {:} µS/cm",
+                        r.epoch,
+                        r.temperature,
+                        describe_lux(r.lux),
+                        r.moisture,
+                        r.conductivity
+                    ),
+                    None => "history record: empty".to_string(),
+                }
+            }
+            Characteristic::HistoryData => {
+                format!("history record count: {:}", rdr.read_u16::<LittleEndian>().ok()?)
+            }
+        };
 
-        Ok(Some(HistoryReadings {
-            record_number: 0, // will be populated later
-            epoch: history_epoch_time,
-            temperature,
-            lux,
-            moisture,
-            conductivity,
-        }))
+        Some(description)
     }
 
-    fn decode_realtime_data(
-        &self,
-        data: &mut Cursor<Vec<u8>>,
-    ) -> Result<RealtimeReadings, std::io::Error> {
-        // byte 0-1
-        let temperature = data.read_u16::<LittleEndian>()? as f32 * 0.1;
-
-        // byte 2
-        let _unknown = data.read_u8()?;
+    pub fn clear_history(&self) -> Result<(), Error> {
+        self.set_device_history_mode(MifloraDeviceHistoryMode::Init)?;
+        self.set_device_history_mode(MifloraDeviceHistoryMode::Clear)?;
 
-        // byte 3-6
-        let lux = data.read_u32::<LittleEndian>()?;
+        Ok(())
+    }
 
-        // byte 7
-        let moisture = data.read_u8()?;
+    fn read_history_record(&self, idx: u16) -> Result<Option<HistoryReadings>, Error> {
+        debug!("Reading history record #{:?}", idx);
 
-        // byte 8-9
-        let conductivity = data.read_u16::<LittleEndian>()?;
+        self.set_device_history_mode(MifloraDeviceHistoryMode::ReadRecord(idx))?;
 
-        // byte 10-15 unknown
+        let gatt = self.gatt()?;
 
-        Ok(RealtimeReadings {
-            temperature,
-            lux,
-            moisture,
-            conductivity,
-        })
+        self.read_attr(
+            &self.history_data,
+            "history record",
+            gatt.layout().history_data,
+            |v| gatt.decode_history(v.get_ref()),
+        )
     }
 
     fn set_device_mode(&self, mode: MifloraDeviceMode) -> Result<(), Error> {
@@ -401,14 +674,26 @@ impl<'a> Miflora<'a> {
             &self.device_mode,
             value,
             name,
-            Self::DEVICE_MODE_CHARACTERISTIC_UUID,
+            self.gatt()?.layout().device_mode,
             |w| w.to_vec(),
         )?;
 
         Ok(())
     }
 
+    // Every use of the history starts here, so firmwares whose history isn't understood are
+    // refused before anything is read or cleared
     fn set_device_history_mode(&self, mode: MifloraDeviceHistoryMode) -> Result<(), Error> {
+        let layout = self.gatt()?.layout();
+        let protocol = self.protocol.get();
+
+        if !protocol.history {
+            return Err(Error::UnsupportedFirmware {
+                version: protocol.version.map(|v| v.to_string()).unwrap_or_default(),
+                feature: "history (firmware 3.0.0 or newer is needed)",
+            });
+        }
+
         let (value, name) = match mode {
             MifloraDeviceHistoryMode::Init => ([0xa0, 0x00, 0x00], "history mode -> init".to_string()),
             MifloraDeviceHistoryMode::Clear => ([0xa2, 0x00, 0x00], "history mode -> clear".to_string()),
@@ -426,7 +711,7 @@ impl<'a> Miflora<'a> {
             &self.history_mode,
             value,
             &name,
-            Self::HISTORY_MODE_CHARACTERISTIC_UUID,
+            layout.history_mode,
             |w| w.to_vec(),
         )?;
 
@@ -454,22 +739,18 @@ impl<'a> Miflora<'a> {
 
             if retry > 0 {
                 debug!("   disconnect");
-                self.device.disconnect().map_err(|error| {
+                self.timed(&self.device, Phase::Io)?.disconnect().map_err(|error| {
                     Error::ErrorDisconnecting {
                         cause: TypedDbusError::from(error),
                     }
                 })?;
 
-                let duration = Duration::from_millis(retry * 30_000);
+                let duration = self.manager.timeouts().cut(Duration::from_millis(retry * 30_000));
                 debug!("   sleeping for {:} s", duration.as_secs());
                 thread::sleep(duration);
 
                 debug!("   connect");
-                self.device.connect().map_err(|error| {
-                    Error::ErrorConnecting {
-                        cause: TypedDbusError::from(error),
-                    }
-                })?;
+                self.connect_device()?;
 
                 thread::sleep(Duration::from_millis(200));
                 debug!("   sleeping for {:} ms", 200);
@@ -481,7 +762,7 @@ impl<'a> Miflora<'a> {
                     uuid: uuid.to_string(),
                 })
                 .and_then(|c| {
-                    OrgBluezGattCharacteristic1::read_value(c, HashMap::new()).map_err(|error| {
+                    self.timed(c, Phase::Io)?.read_value().map_err(|error| {
                         Error::ErrorReadingData {
                             name: name.to_string(),
                             uuid: uuid.to_string(),
@@ -524,7 +805,7 @@ impl<'a> Miflora<'a> {
                 uuid: uuid.to_string(),
             })
             .and_then(|c| {
-                OrgBluezGattCharacteristic1::write_value(c, writer(value), HashMap::new()).map_err(
+                self.timed(c, Phase::Io)?.write_value(writer(value)).map_err(
                     |err| Error::ErrorWritingData {
                         name: name.to_string(),
                         uuid: uuid.to_string(),
@@ -535,7 +816,7 @@ impl<'a> Miflora<'a> {
             })
     }
 
-    fn find_gatt_attributes(&mut self) {
+    fn find_gatt_attributes(&mut self) -> Result<(), Error> {
         let mut firmware: Option<DBusPath> = None;
         let mut device_mode: Option<DBusPath> = None;
         let mut device_data: Option<DBusPath> = None;
@@ -543,49 +824,60 @@ impl<'a> Miflora<'a> {
         let mut history_mode: Option<DBusPath> = None;
         let mut history_data: Option<DBusPath> = None;
 
-        let conn = self.device.conn.clone();
+        // Other devices may be connected too, with the same characteristics
+        let prefix = format!("{}/", self.device.path);
+        let layout = self.gatt()?.layout();
+        let resolve = self.manager.timeouts().of(Phase::Resolve).map_err(|cause| Error::Timeout { cause })?;
+
+        let found = self
+            .manager
+            .find_objects(
+                move |path, obj| {
+                    if !path.starts_with(&prefix) {
+                        return None;
+                    }
 
-        self.manager.find_objects(
-            |path, obj| {
-                if let Some(props) = obj.get(BLUEZ_GATT_CHARACTERISTIC_INTERFACE.into()) {
-                    props
-                        .get("UUID")
+                    obj.get(BLUEZ_GATT_CHARACTERISTIC_INTERFACE)
+                        .and_then(|props| props.get("UUID"))
                         .and_then(dbus::arg::Variant::as_str)
-                        .and_then(|uuid| match uuid {
-                            Self::FIRMWARE_CHARACTERISTIC_UUID => Some(&mut firmware),
-                            Self::DEVICE_MODE_CHARACTERISTIC_UUID => Some(&mut device_mode),
-                            Self::DEVICE_DATA_CHARACTERISTIC_UUID => Some(&mut device_data),
-                            Self::DEVICE_TIME_CHARACTERISTIC_UUID => Some(&mut device_time),
-                            Self::HISTORY_MODE_CHARACTERISTIC_UUID => Some(&mut history_mode),
-                            Self::HISTORY_DATA_CHARACTERISTIC_UUID => Some(&mut history_data),
-                            _ => None,
-                        })
-                        .map(|char| {
-                            *char = Some(dbus::ConnPath {
-                                conn: conn.clone(),
-                                dest: dbus::BusName::from(BLUEZ_SERVICE),
-                                path: path.clone().into_static(),
-                                timeout: 30_000,
-                            })
-                        });
-                }
-
-                firmware.is_some()
-                    && device_mode.is_some()
-                    && device_data.is_some()
-                    && device_time.is_some()
-                    && history_mode.is_some()
-                    && history_data.is_some()
-            },
-            Some(30_000),
-        );
+                        .and_then(|uuid| layout.characteristic(uuid))
+                        .map(|characteristic| (characteristic, path.clone().into_static()))
+                },
+                resolve,
+            )
+            .map_err(|err| Error::DBusError {
+                cause: TypedDbusError::from(err),
+            })?;
+
+        for (characteristic, path) in found {
+            let char = match characteristic {
+                Characteristic::Firmware => &mut firmware,
+                Characteristic::DeviceMode => &mut device_mode,
+                Characteristic::DeviceData => &mut device_data,
+                Characteristic::DeviceTime => &mut device_time,
+                Characteristic::HistoryMode => &mut history_mode,
+                Characteristic::HistoryData => &mut history_data,
+            };
+
+            *char = Some(self.device.at(path));
+
+            if firmware.is_some()
+                && device_mode.is_some()
+                && device_data.is_some()
+                && device_time.is_some()
+                && history_mode.is_some()
+                && history_data.is_some()
+            {
+                break;
+            }
+        }
 
-        debug!("firmware: {:?}", firmware);
-        debug!("device_mode: {:?}", device_mode);
-        debug!("device_data: {:?}", device_data);
-        debug!("device_time: {:?}", device_time);
-        debug!("history_mode: {:?}", history_mode);
-        debug!("history_data: {:?}", history_data);
+        debug!("firmware: {:?}", firmware.as_ref().map(|c| &c.path));
+        debug!("device_mode: {:?}", device_mode.as_ref().map(|c| &c.path));
+        debug!("device_data: {:?}", device_data.as_ref().map(|c| &c.path));
+        debug!("device_time: {:?}", device_time.as_ref().map(|c| &c.path));
+        debug!("history_mode: {:?}", history_mode.as_ref().map(|c| &c.path));
+        debug!("history_data: {:?}", history_data.as_ref().map(|c| &c.path));
 
         self.firmware = firmware;
         self.device_mode = device_mode;
@@ -593,11 +885,83 @@ impl<'a> Miflora<'a> {
         self.device_time = device_time;
         self.history_mode = history_mode;
         self.history_data = history_data;
+
+        Ok(())
     }
 }
 
-impl<'a> Drop for Miflora<'a> {
+impl Drop for Miflora {
     fn drop(&mut self) {
         self.disconnect().ok();
     }
 }
+
+// Product id from the MiBeacon advertisement BlueZ last saw from the device. None if it hasn't
+// seen one
+pub(crate) fn advertised_product_id(device: &DBusPath) -> Option<u16> {
+    let service_data = device.get_service_data().ok()?;
+    let data = dbus_bluez::bytes(&*service_data.get(MIBEACON_SERVICE_UUID)?.0)?;
+
+    match mibeacon::decode(&data) {
+        Ok(frame) => Some(frame.product_id),
+        Err(e) => {
+            debug!("invalid MiBeacon frame {:02x?}: {:}", data, e);
+            None
+        }
+    }
+}
+
+fn describe_lux(lux: Option<u32>) -> String {
+    lux.map(|lux| lux.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_firmware_info() {
+        let sensor = plant_sensor::detect(None, None);
+        let description = Miflora::describe(
+            sensor,
+            sensor.gatt().unwrap().layout().firmware,
+            &[0x62, 0x13, b'3', b'.', b'1', b'.', b'8'],
+        );
+
+        assert_eq!(description.as_deref(), Some("firmware info: battery 98 %, firmware version 3.1.8"));
+    }
+
+    fn dbus_error(name: &str) -> TypedDbusError {
+        TypedDbusError::from(dbus::Error::new_custom(name, "failed"))
+    }
+
+    fn writing(cause: TypedDbusError) -> Error {
+        Error::ErrorWritingData {
+            name: "device mode".to_string(),
+            uuid: plant_sensor::detect(None, None).gatt().unwrap().layout().device_mode.to_string(),
+            path: "/org/bluez/hci0/dev_C4_7C_8D_67_C2_8B/service0031/char0032".to_string(),
+            cause,
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_kinds() {
+        assert_eq!(writing(dbus_error("org.bluez.Error.Failed")).kind(), ErrorKind::WriteFailed);
+        assert_eq!(
+            Error::ErrorConnecting { cause: dbus_error("org.bluez.Error.Failed") }.kind(),
+            ErrorKind::ConnectFailed
+        );
+        assert_eq!(
+            Error::GATTAttributeNotFound { name: "device mode".to_string(), uuid: String::new() }.kind(),
+            ErrorKind::NotFound
+        );
+
+        // The D-Bus error tells more than what was being done
+        assert_eq!(writing(dbus_error("org.bluez.Error.NotAuthorized")).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(writing(dbus_error("org.freedesktop.DBus.Error.NoReply")).kind(), ErrorKind::Timeout);
+
+        let e: Box<dyn std::error::Error> = Box::new(writing(dbus_error("org.bluez.Error.Failed")));
+
+        assert_eq!(error_kind(&*e), Some(ErrorKind::WriteFailed));
+    }
+}
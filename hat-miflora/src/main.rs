@@ -1,28 +1,67 @@
 #[macro_use]
 extern crate log;
 
-use std::time::{Duration, SystemTime};
-
-use chrono::prelude::DateTime;
-use chrono::Local;
-use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
-use cmd_opts::CmdOpts;
+use cmd_opts::{CmdOpts, DeviceSelection, HistoryOpts, QueryOpts};
 
-use crate::dbus_bluez::BluezManager;
-use crate::device::XIAOMI_MIFLORA_SERVICE_UUID;
+use hat_common::exit::{self, ErrorKind, ErrorReport, Failure};
+use hat_common::format::{Field, Format, Formatter, Record, Unit, Value};
+use hat_common::store::Filter;
+use hat_common::time::parse_datetime;
 
+use crate::clock::{BootTime, Clock};
+use crate::config::Config;
+use crate::daemon::Daemon;
+use crate::dbus_bluez::BluezManager;
+use crate::mibeacon::{SensorValues, MIBEACON_SERVICE_UUID};
+use crate::parallel::{Emit, Event};
+use crate::plant_sensor::PlantSensor;
+use crate::records::{AdvertisementResult, HistoryRecordResult, InfoResult, ReadResult};
+use crate::recorder::Recorder;
+use crate::state::StateFiles;
+use crate::sync::SyncStore;
+use crate::timeouts::Timeouts;
+
+mod clock;
 mod cmd_opts;
+mod config;
+mod daemon;
 mod dbus_bluez;
 mod device;
+mod mibeacon;
+mod parallel;
+mod plant_sensor;
+mod plants;
+mod protocol;
+mod quality;
+mod query;
+mod recorder;
+mod records;
+mod state;
+mod sync;
+mod timeouts;
 
 #[derive(Serialize)]
 struct ScanResultDevice {
     addr: String,
     name: String,
     alias: String,
+    /// Alias given to the device in the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configured_alias: Option<String>,
     rssi: i16,
+    /// From the MiBeacon advertisement, if one was seen
+    product_id: Option<u16>,
+    model: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -32,141 +71,495 @@ struct ScanResult {
 
 #[derive(Serialize)]
 struct HistoryCountResult {
+    #[serde(skip)]
+    datetime: DateTime<Local>,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
     total_records: u16,
 }
 
+impl Record for HistoryCountResult {
+    fn measurement(&self) -> &'static str {
+        "miflora"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.datetime
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        vec![Field::new("total_records", Unit::None, Value::Int(self.total_records as i64))]
+    }
+}
+
+// How far an interrupted `history` read got, so running it again continues from there
+#[derive(Serialize, Deserialize)]
+struct HistoryCheckpoint {
+    from: u16,
+    to: Option<u16>,
+    next_record: u16,
+    /// The records before next_record were kept for --clear
+    #[serde(default)]
+    clear: bool,
+}
+
+// The times --since, --until or --last ask history records for
+#[derive(Clone, Copy)]
+struct TimeWindow {
+    since: Option<DateTime<Local>>,
+    until: Option<DateTime<Local>>,
+}
+
+impl TimeWindow {
+    fn new(opts: &HistoryOpts) -> std::result::Result<Option<TimeWindow>, String> {
+        let since = match opts.last {
+            Some(last) => Some(
+                chrono::Duration::from_std(last)
+                    .ok()
+                    .and_then(|last| Local::now().checked_sub_signed(last))
+                    .ok_or("--last is too long")?,
+            ),
+            None => opts.since.as_deref().map(parse_datetime).transpose()?,
+        };
+        let until = opts.until.as_deref().map(parse_datetime).transpose()?;
+
+        Ok(Some(TimeWindow { since, until }).filter(|_| since.is_some() || until.is_some()))
+    }
+
+    fn contains(&self, datetime: &DateTime<Local>) -> bool {
+        self.since.is_none_or(|since| *datetime >= since) && self.until.is_none_or(|until| *datetime <= until)
+    }
+}
+
+// What became of history --clear
+#[derive(Serialize)]
+struct HistoryClearResult {
+    address: String,
+    /// Records known to be stored when the device was about to be cleared
+    confirmed_records: u16,
+    cleared: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refused: Option<String>,
+}
+
+// What `history` hands on to the output
+enum HistoryEvent {
+    Record(HistoryRecordResult),
+    /// Make sure every record so far is on disk
+    Persist,
+    Cleared(HistoryClearResult),
+}
+
+#[derive(Serialize)]
+struct RawResult {
+    address: String,
+    uuid: String,
+    path: String,
+    /// read, write or notify
+    operation: &'static str,
+    value: String,
+    /// What the value means, for known characteristics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decoded: Option<String>,
+}
+
+impl RawResult {
+    fn new(
+        sensor: &dyn PlantSensor,
+        address: &str,
+        uuid: &str,
+        path: &dbus::Path,
+        operation: &'static str,
+        value: &[u8],
+    ) -> RawResult {
+        RawResult {
+            address: address.to_string(),
+            uuid: uuid.to_string(),
+            path: path.to_string(),
+            operation,
+            value: value.iter().map(|b| format!("{:02x}", b)).collect(),
+            decoded: device::Miflora::describe(sensor, uuid, value),
+        }
+    }
+
+    fn print(&self, cmd_options: &CmdOpts) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if cmd_options.format.is_json() {
+            println!("{}", serde_json::to_string(self)?);
+        } else {
+            println!("- {:} {:} ({:})", self.operation, self.uuid, self.path);
+            println!("  value:   {:}", self.value);
+
+            if let Some(ref decoded) = self.decoded {
+                println!("  decoded: {:}", decoded);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceErrorResult {
+    address: String,
+    error: String,
+    kind: ErrorKind,
+}
+
+const ALL_DEVICES_SCAN_DURATION: Duration = Duration::from_secs(10);
+
+// Time between the readings of read --samples
+const SAMPLE_INTERVAL_MS: u64 = 1000;
+
+type Output = Formatter<io::Stdout>;
+
+fn output(cmd_options: &CmdOpts) -> Output {
+    let mut output = Formatter::stdout(cmd_options.format, !cmd_options.no_headers);
+
+    output.alias_column(cmd_options.several_devices());
+    output
+}
+
+fn resolve_addresses(
+    manager: &BluezManager,
+    devices: &DeviceSelection,
+) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
+    if !devices.all {
+        return Ok(devices.addrs.clone());
+    }
+
+    let found = manager.scan(MIBEACON_SERVICE_UUID, ALL_DEVICES_SCAN_DURATION)?;
+    let mut result = Vec::new();
+
+    for device in found {
+        let address = device.get_address()?;
+        let product_id = device::advertised_product_id(&device);
+        let sensor = plant_sensor::detect(product_id, device.get_name().ok().as_deref());
+
+        if !is_plant_sensor(product_id) {
+            debug!("{:} isn't a plant sensor, skipping", address);
+        } else if sensor.gatt().is_none() {
+            info!("{:} is a {:}, which can't be read over a connection, skipping", address, sensor.name());
+        } else {
+            result.push(address);
+        }
+    }
+
+    debug!("found devices: {:?}", result);
+
+    Ok(result)
+}
+
+// Devices whose product id isn't known yet are given the benefit of the doubt
+fn is_plant_sensor(product_id: Option<u16>) -> bool {
+    product_id.is_none_or(|id| mibeacon::model(id).is_some_and(|model| model.plant))
+}
+
+// Runs `work` for every requested device, on up to --parallel of them at a time, and `handle`
+// for everything it emits. A failing device is reported and skipped, so one bad sensor doesn't
+// stop the others from being read. `handle` always runs on this thread, so it is where the
+// recorder is used
+fn for_each_device<R, W, H>(
+    manager: &BluezManager,
+    output: &mut Output,
+    devices: &DeviceSelection,
+    work: W,
+    mut handle: H,
+) -> std::result::Result<(), Box<dyn std::error::Error>>
+where
+    R: Send,
+    W: Fn(&BluezManager, &str, &mut Emit<R>) -> std::result::Result<(), Box<dyn std::error::Error>> + Sync,
+    H: FnMut(&mut Output, R) -> std::result::Result<(), Box<dyn std::error::Error>>,
+{
+    let addrs = resolve_addresses(manager, devices)?;
+    let mut failed = Vec::new();
+    let mut fail = |output: &mut Output, address: &str, error: String, kind: ErrorKind| {
+        let result = DeviceErrorResult {
+            address: address.to_string(),
+            error,
+            kind,
+        };
+
+        failed.push(result.kind);
+
+        if !output.write_other(&result)? {
+            eprintln!("ERROR: {:}: {:}", result.address, result.error);
+        }
+
+        Ok::<(), Box<dyn std::error::Error>>(())
+    };
+
+    if devices.parallel > 1 && addrs.len() > 1 {
+        parallel::run(manager, &addrs, devices.parallel, &work, |event| match event {
+            Event::Record { record, done } => {
+                let handled = handle(output, record).map_err(|e| Failure::new(exit::error_kind(&*e, device::error_kind), e.to_string()));
+
+                // The worker may have given up already
+                done.send(handled).ok();
+                Ok(())
+            }
+            Event::Failed { address, error, kind } => fail(output, &address, error, kind),
+        })?;
+    } else {
+        for addr in &addrs {
+            if let Err(e) = work(manager, addr, &mut |record| handle(output, record)) {
+                fail(output, addr, e.to_string(), exit::error_kind(&*e, device::error_kind))?;
+            }
+        }
+    }
+
+    output.finish()?;
+
+    if !failed.is_empty() {
+        return Err(Box::new(Failure::new(
+            ErrorKind::common(failed.iter().copied()),
+            format!("{:} of {:} devices failed", failed.len(), addrs.len()),
+        )));
+    }
+
+    Ok(())
+}
+
 fn scan(
-    manager: &mut BluezManager,
+    manager: &BluezManager,
     cmd_options: &CmdOpts,
+    recorder: &Recorder,
     duration_sec: u8,
+    set_alias: bool,
+    all: bool,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let devices = manager.scan(
-        XIAOMI_MIFLORA_SERVICE_UUID,
-        Some(duration_sec as u32 * 1000),
-    )?;
+    let devices = manager.scan(MIBEACON_SERVICE_UUID, Duration::from_secs(duration_sec as u64))?;
     let mut scan_result: ScanResult = ScanResult {
         devices: Vec::new(),
     };
 
     for device in devices {
+        let product_id = device::advertised_product_id(&device);
+
+        if !all && !is_plant_sensor(product_id) {
+            debug!("{:?} isn't a plant sensor (product id {:?}), skipping", device.path, product_id);
+            continue;
+        }
+
         let device = device::Miflora::new(device, manager)?;
 
         let name = device.get_name()?;
-        let alias = device.get_alias()?;
         let addr = device.get_address()?;
         let rssi = device.get_rssi()?;
+        let configured_alias = recorder.aliases.alias(&addr).map(String::from);
+
+        if let (true, Some(alias)) = (set_alias, configured_alias.as_ref()) {
+            debug!("{:} setting alias {:}", addr, alias);
+            device.set_alias(alias)?;
+        }
 
-        scan_result
-            .devices
-            .push(ScanResultDevice { name, addr, alias, rssi });
+        let alias = device.get_alias()?;
+
+        scan_result.devices.push(ScanResultDevice {
+            name,
+            addr,
+            alias,
+            configured_alias,
+            rssi,
+            product_id,
+            model: product_id
+                .and_then(mibeacon::model)
+                .map(|model| format!("{:} ({:})", model.name, model.description)),
+        });
     }
 
-    if cmd_options.json {
+    if cmd_options.format.is_json() {
         println!("{}", serde_json::to_string(&scan_result)?);
     } else {
         for device in scan_result.devices {
             println!("- addr:  {:}", device.addr);
             println!("  name:  {:}", device.name);
-            println!("  alias: {:}", device.alias);
+            match device.configured_alias {
+                Some(configured_alias) => println!("  alias: {:} (configured: {:})", device.alias, configured_alias),
+                None => println!("  alias: {:}", device.alias),
+            }
             println!("  rssi:  {:}", device.rssi);
+            match (device.model, device.product_id) {
+                (Some(model), _) => println!("  model: {:}", model),
+                (None, Some(product_id)) => println!("  model: unknown (product id {:#06x})", product_id),
+                (None, None) => println!("  model: unknown"),
+            }
         }
     }
 
     Ok(())
 }
 
-#[derive(Serialize)]
-struct ReadResult {
-    #[serde(with = "date_format")]
-    datetime: DateTime<Local>,
-    address: String,
-    battery_pct: u8,
-    firmware_version: String,
-    temperature: f32,
-    lux: u32,
-    moisture: u8,
-    conductivity: u16,
+fn read(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    recorder: &Recorder,
+    devices: &DeviceSelection,
+    samples: Option<NonZeroUsize>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    for_each_device(
+        manager,
+        &mut output(cmd_options),
+        devices,
+        |manager, addr, emit| emit(read_device(manager, addr, samples)?),
+        |output, result| output.write(&recorder.realtime(result)?),
+    )
 }
 
-#[derive(Serialize)]
-struct HistoryRecordResult {
-    #[serde(with = "date_format")]
-    datetime: DateTime<Local>,
-    record_number: u16,
-    total_records: u16,
-    address: String,
-    temperature: f32,
-    lux: u32,
-    moisture: u8,
-    conductivity: u16,
-}
+fn read_device(
+    manager: &BluezManager,
+    addr: &str,
+    samples: Option<NonZeroUsize>,
+) -> std::result::Result<ReadResult, Box<dyn std::error::Error>> {
+    let device = manager.find_by_address(addr)?;
+
+    debug!("find_by_address: {:?}", device);
 
-mod date_format {
-    use chrono::{DateTime, Local};
-    use serde::{self, Serializer};
+    let mut device = device::Miflora::new(device, manager)?;
 
-    pub(crate) const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+    device.connect()?;
 
-    pub fn serialize<S>(date: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let s = format!("{}", date.format(FORMAT));
-        serializer.serialize_str(&s)
-    }
+    Ok(match samples {
+        Some(count) => ReadResult::sample(&mut device, count, Duration::from_millis(SAMPLE_INTERVAL_MS))?,
+        None => ReadResult::read(&device)?,
+    })
 }
 
-fn read(
-    manager: &mut BluezManager,
+fn watch(
+    manager: &BluezManager,
     cmd_options: &CmdOpts,
+    recorder: &Recorder,
     addr: &str,
+    interval_sec: u64,
+    duration_sec: u64,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let device = manager.find_by_address(addr, Some(60000))?;
+    let mut output = output(cmd_options);
+    let interval = Duration::from_secs(interval_sec);
+    let deadline = if duration_sec > 0 {
+        Some(Instant::now() + Duration::from_secs(duration_sec))
+    } else {
+        None
+    };
+
+    let device = manager.find_by_address(addr)?;
 
     debug!("find_by_address: {:?}", device);
 
     let mut device = device::Miflora::new(device, manager)?;
 
     device.connect()?;
+    device.start_realtime()?;
 
-    let firmware_version = device.get_firmware_version()?;
-    let battery_pct = device.get_battery_pct()?;
-    let address = device.get_address()?;
-    let readings = device.get_realtime_reading()?;
+    loop {
+        output.write(&recorder.realtime(ReadResult::read_realtime(&device)?)?)?;
 
-    let result = ReadResult {
-        datetime: Local::now(),
-        address,
-        battery_pct,
-        firmware_version,
-        temperature: readings.temperature,
-        lux: readings.lux,
-        moisture: readings.moisture,
-        conductivity: readings.conductivity,
-    };
+        let next = Instant::now() + interval;
 
-    if cmd_options.json {
-        println!("{}", serde_json::to_string(&result)?);
-    } else {
-        println!("- addr:         {:}", result.address);
-        println!("  datetime:     {:}", result.datetime.format(date_format::FORMAT));
-        println!("  battery:      {:} %", result.battery_pct);
-        println!("  alias:        {:}", result.firmware_version);
-        println!("  temperature:  {:} °C", result.temperature);
-        println!("  lux:          {:}", result.lux);
-        println!("  moisture:     {:} %", result.moisture);
-        println!("  conductivity: {:} µS/cm", result.conductivity);
+        if deadline.is_some_and(|deadline| next > deadline) {
+            break;
+        }
+
+        device.idle(interval);
     }
 
-    Ok(())
+    output.finish()
+}
+
+fn info(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    recorder: &Recorder,
+    devices: &DeviceSelection,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    for_each_device(
+        manager,
+        &mut output(cmd_options),
+        devices,
+        |manager, addr, emit| {
+            let device = manager.find_by_address(addr)?;
+
+            debug!("find_by_address: {:?}", device);
+
+            let mut device = device::Miflora::new(device, manager)?;
+
+            device.connect()?;
+
+            emit(InfoResult::read(&device)?)
+        },
+        |output, mut result| {
+            result.alias = recorder.aliases.alias(&result.address).map(String::from);
+
+            output.write(&result)
+        },
+    )
+}
+
+fn listen(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    recorder: &Recorder,
+    addrs: &[String],
+    duration_sec: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut output = output(cmd_options);
+    let mut values: HashMap<String, SensorValues> = HashMap::new();
+    let duration = if duration_sec > 0 {
+        Some(Duration::from_secs(duration_sec))
+    } else {
+        None
+    };
+
+    manager.listen_service_data(MIBEACON_SERVICE_UUID, duration, |address, data| {
+        if !addrs.is_empty() && !addrs.iter().any(|a| a.eq_ignore_ascii_case(address)) {
+            return Ok(());
+        }
+
+        let frame = match mibeacon::decode(data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!("{:} invalid MiBeacon frame {:02x?}: {:}", address, data, e);
+                return Ok(());
+            }
+        };
+
+        debug!("{:} MiBeacon frame: {:?}", address, frame);
+
+        if !is_plant_sensor(Some(frame.product_id)) {
+            return Ok(());
+        }
+
+        if let Some(ref object) = frame.object {
+            let values = values.entry(address.to_string()).or_default();
+
+            values.update(object);
+
+            let sensor = plant_sensor::detect(Some(frame.product_id), None);
+
+            if let Some(values) = values.take(sensor.has_lux()) {
+                output.write(&recorder.advertisement(AdvertisementResult::new(address, sensor, values)))?;
+            }
+        }
+
+        Ok(())
+    })?;
+
+    output.finish()
 }
 
 fn blink(
-    manager: &mut BluezManager,
+    manager: &BluezManager,
     _cmd_options: &CmdOpts,
     addr: &str,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let device = manager.find_by_address(addr, Some(60000))?;
+    let device = manager.find_by_address(addr)?;
 
     debug!("find_by_address: {:?}", device);
 
@@ -181,12 +574,74 @@ fn blink(
     Ok(())
 }
 
+fn raw_read(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    addr: &str,
+    uuid: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let device = manager.find_by_address(addr)?;
+    let mut device = device::Miflora::new(device, manager)?;
+
+    device.connect()?;
+
+    let characteristic = device.find_characteristic(uuid)?;
+    let value = device.read_raw(&characteristic, uuid)?;
+
+    RawResult::new(device.get_sensor(), addr, uuid, &characteristic.path, "read", &value).print(cmd_options)
+}
+
+fn raw_write(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    addr: &str,
+    uuid: &str,
+    value: &[u8],
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let device = manager.find_by_address(addr)?;
+    let mut device = device::Miflora::new(device, manager)?;
+
+    device.connect()?;
+
+    let characteristic = device.find_characteristic(uuid)?;
+
+    device.write_raw(&characteristic, uuid, value.to_vec())?;
+
+    RawResult::new(device.get_sensor(), addr, uuid, &characteristic.path, "write", value).print(cmd_options)
+}
+
+fn raw_notify(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    addr: &str,
+    uuid: &str,
+    duration_sec: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let device = manager.find_by_address(addr)?;
+    let mut device = device::Miflora::new(device, manager)?;
+
+    device.connect()?;
+
+    let characteristic = device.find_characteristic(uuid)?;
+    let duration = if duration_sec > 0 {
+        Some(Duration::from_secs(duration_sec))
+    } else {
+        None
+    };
+
+    let sensor = device.get_sensor();
+
+    device.notify_raw(&characteristic, duration, |value| {
+        RawResult::new(sensor, addr, uuid, &characteristic.path, "notify", value).print(cmd_options)
+    })
+}
+
 fn clear_history(
-    manager: &mut BluezManager,
+    manager: &BluezManager,
     _cmd_options: &CmdOpts,
     addr: &str,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let device = manager.find_by_address(addr, Some(60000))?;
+    let device = manager.find_by_address(addr)?;
 
     debug!("find_by_address: {:?}", device);
 
@@ -202,16 +657,81 @@ fn clear_history(
 }
 
 fn history(
-    manager: &mut BluezManager,
+    manager: &BluezManager,
     cmd_options: &CmdOpts,
+    config: &Config,
+    recorder: &Recorder,
+    opts: &HistoryOpts,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let clock = Clock::new(config.state_dir());
+    let checkpoints = StateFiles::new(config.state_dir().join("history"), "history checkpoint");
+    let window = TimeWindow::new(opts)?;
+
+    // History is only cleared once the records are on disk: in the store, or else in the file
+    // standard output goes to
+    let output_file = match (opts.clear, &recorder.store) {
+        (true, None) => Some(stdout_file(cmd_options.format).ok_or_else(|| {
+            Failure::new(
+                ErrorKind::Failed,
+                "history --clear needs --store, or the output redirected to a file (not json or prometheus)",
+            )
+        })?),
+        _ => None,
+    };
+
+    for_each_device(
+        manager,
+        &mut output(cmd_options),
+        &opts.devices,
+        |manager, addr, emit| history_device(manager, emit, &clock, &checkpoints, opts, window, addr),
+        |output, event| match event {
+            HistoryEvent::Record(result) => output.write(&recorder.history(result)?),
+            HistoryEvent::Persist => {
+                // The store has committed every record already
+                if let Some(ref file) = output_file {
+                    io::stdout().flush()?;
+                    file.sync_data()?;
+                }
+
+                Ok(())
+            }
+            HistoryEvent::Cleared(result) => {
+                if !output.write_other(&result)? {
+                    match result.refused {
+                        Some(ref refused) => eprintln!("{:}: not cleared, {:}", result.address, refused),
+                        None => eprintln!("{:}: cleared {:} stored records", result.address, result.confirmed_records),
+                    }
+                }
+
+                Ok(())
+            }
+        },
+    )
+}
+
+// Standard output, when it is a file that records are written to as they come (so not json or
+// prometheus, which are written at the end)
+fn stdout_file(format: Format) -> Option<File> {
+    let path = "/proc/self/fd/1";
+
+    if matches!(format, Format::Json | Format::Prometheus) || !fs::metadata(path).is_ok_and(|m| m.is_file()) {
+        return None;
+    }
+
+    OpenOptions::new().append(true).open(path).ok()
+}
+
+fn history_device(
+    manager: &BluezManager,
+    emit: &mut Emit<HistoryEvent>,
+    clock: &Clock,
+    checkpoints: &StateFiles,
+    opts: &HistoryOpts,
+    window: Option<TimeWindow>,
     addr: &str,
-    from_requested: Option<u16>,
-    to_requested: Option<u16>,
-    page: Option<u16>,
-    clear: bool,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
     const DEFAULT_PAGE_SIZE: u16 = 10;
-    let device = manager.find_by_address(addr, Some(60000))?;
+    let device = manager.find_by_address(addr)?;
 
     debug!("find_by_address: {:?}", device);
 
@@ -219,32 +739,20 @@ fn history(
 
     device.connect()?;
 
-    let address = device.get_address()?;
-
     let total_records = device.get_history_record_count()?;
 
     if total_records == 0 {
         return Ok(());
     }
-    
-    // NOTE: Re-running this will give different timestamp - but within 3-5 seconds. I wonder
-    // if this is a bug or due to the slow communication with Miflora
-    let device_epoch = device.get_device_time()?;
-    debug!("device epoch: {:?}", device_epoch);
-    let device_boot_time = SystemTime::now() - Duration::from_secs(device_epoch as u64);
-    debug!(
-        "device boot time: {:?}",
-        DateTime::<Local>::from(device_boot_time)
-    );
 
     let mut from: u16 = 0;
     let mut to: u16;
 
-    if let Some(from_requested) = from_requested {
+    if let Some(from_requested) = opts.from {
         from = from_requested;
     }
 
-    if let Some(to_requested) = to_requested {
+    if let Some(to_requested) = opts.to {
         to = to_requested;
     }
     else {
@@ -261,7 +769,13 @@ fn history(
         to = total_records;
     }
 
-    let page_size = if let Some(p) = page {
+    let boot_time = clock.boot_time(&device)?;
+
+    if let Some(ref window) = window {
+        (from, to) = records_in(&device, &boot_time, window, total_records)?;
+    }
+
+    let page_size = if let Some(p) = opts.page {
         if p > 1 {
             p
         }
@@ -273,84 +787,181 @@ fn history(
         DEFAULT_PAGE_SIZE
     };
 
-    let clear = clear && from == 0 && to == total_records;
-
-    debug!("reading history records {:} to {:} with page size {:}", from, to, page_size);
+    // Reading until the last record includes those logged while reading
+    let to = if to == total_records { None } else { Some(to) };
+    let clear = opts.clear && from == 0 && to.is_none();
+    let address = device.get_address()?;
 
-    if !cmd_options.json && !cmd_options.no_headers {
-        println!(
-            "{datetime:19} {address:16} {temperature:5}    {lux:4}     {moisture:4}   {conductivity:4}      ",
-            datetime = "datetime",
-            address = "address",
-            temperature = "temp",
-            lux = "lux",
-            moisture = "moist",
-            conductivity = "cond"
-        );
-    }
+    // Clearing can only count on the records of an interrupted read if they were kept for it
+    let start = match checkpoints.load::<HistoryCheckpoint>(&address)? {
+        Some(checkpoint)
+            if !opts.restart && checkpoint.from == from && checkpoint.to == to && (checkpoint.clear || !clear) =>
+        {
+            info!("{:} resuming interrupted history read at record {:}", address, checkpoint.next_record);
+            checkpoint.next_record
+        }
+        _ => from,
+    };
 
-    let mut page_from = from;
+    let mut confirmed = start;
 
-    loop {
-        let page_to = if page_from + page_size > to {
-            to
-        } else {
-            page_from + page_size
+    records::read_history(&mut device, &boot_time, start, to, page_size, |result| {
+        let checkpoint = HistoryCheckpoint {
+            from,
+            to,
+            next_record: result.record_number + 1,
+            clear,
         };
 
-        debug!("reading page records {:?}-{:?} of {:?}", page_from, page_to, to);
+        if window.is_none_or(|window| window.contains(&result.datetime)) {
+            emit(HistoryEvent::Record(result))?;
+        }
 
-        // device.reconnect()?;
+        confirmed = checkpoint.next_record;
+        checkpoints.save(&address, &checkpoint)
+    })?;
 
-        let records = device.get_history_records(page_from, page_to)?;
+    clock.check(&device, &boot_time)?;
 
-        page_from = page_to;
+    if clear {
+        emit(HistoryEvent::Persist)?;
+        emit(HistoryEvent::Cleared(clear_confirmed(&device, &address, confirmed)?))?;
+    }
 
-        debug!("got {:?} records", records.len());
+    checkpoints.remove(&address)?;
 
-        for reading in records {
-            let record_time = DateTime::<Local>::from(
-                device_boot_time - Duration::from_secs(reading.epoch as u64),
-            );
+    Ok(())
+}
 
-            let result = HistoryRecordResult {
-                datetime: record_time,
-                record_number: reading.record_number,
-                total_records,
-                address: address.clone(),
-                temperature: reading.temperature,
-                lux: reading.lux,
-                moisture: reading.moisture,
-                conductivity: reading.conductivity,
-            };
+// The records `from..to` logged within `window`, worked out from the time of the first and last
+// record, so only those are read. When that doesn't tell, every record is read (and filtered by
+// time)
+fn records_in(
+    device: &device::Miflora,
+    boot_time: &BootTime,
+    window: &TimeWindow,
+    total_records: u16,
+) -> std::result::Result<(u16, u16), Box<dyn std::error::Error>> {
+    let last = total_records - 1;
+    let range = match (device.get_history_record_epoch(0)?, device.get_history_record_epoch(last)?) {
+        (Some(first_epoch), Some(last_epoch)) => clock::record_range(
+            boot_time,
+            (0, first_epoch),
+            (last, last_epoch),
+            window.since.map(|since| since.timestamp()),
+            window.until.map(|until| until.timestamp()),
+            Local::now().timestamp(),
+        ),
+        _ => None,
+    };
 
-            if cmd_options.json {
-                println!("{}", serde_json::to_string(&result)?);
-            } else {
-                println!("{datetime:19} {address:16} {temperature:4.1} °C {lux:5} lux {moisture:4} % {conductivity:4} µS/cm", datetime=result.datetime.format(date_format::FORMAT), address=result.address, temperature=result.temperature, lux=result.lux, moisture=result.moisture, conductivity=result.conductivity);
-            }
+    match range {
+        Some((from, to)) => {
+            debug!("{:} reading records {:} to {:} for the time asked", device.get_address()?, from, to);
+            Ok((from, to))
         }
-
-        if page_to == to {
-            debug!("Got everything, stopping");
-            break;
+        None => {
+            info!("{:} history isn't one hourly run of records, reading all of them", device.get_address()?);
+            Ok((0, total_records))
         }
     }
+}
 
-    if clear {
-        debug!("Clearing history");
+// Clears the history of the device, if it has no records but the `confirmed` ones that are stored.
+// Records logged since they were read would be lost, so then it is left for the next run
+fn clear_confirmed(
+    device: &device::Miflora,
+    address: &str,
+    confirmed: u16,
+) -> std::result::Result<HistoryClearResult, Box<dyn std::error::Error>> {
+    let count = device.get_history_record_count()?;
+    let refused = if count > confirmed {
+        Some(format!("{:} records were logged after reading", count - confirmed))
+    } else if count < confirmed {
+        Some(format!("the device has {:} records, fewer than were read", count))
+    } else {
+        None
+    };
+
+    if let Some(ref refused) = refused {
+        warn!("{:} not clearing history: {:}", address, refused);
+    } else {
+        debug!("{:} clearing {:} stored records", address, confirmed);
         device.clear_history()?;
     }
 
-    Ok(())
+    Ok(HistoryClearResult {
+        address: address.to_string(),
+        confirmed_records: confirmed,
+        cleared: refused.is_none(),
+        refused,
+    })
+}
+
+fn sync(
+    manager: &BluezManager,
+    cmd_options: &CmdOpts,
+    config: &Config,
+    recorder: &Recorder,
+    devices: &DeviceSelection,
+    page: Option<u16>,
+    reset: bool,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let sync_store = SyncStore::new(config.state_dir());
+    let clock = Clock::new(config.state_dir());
+    let page_size = page.filter(|p| *p > 1).unwrap_or(10);
+
+    for_each_device(
+        manager,
+        &mut output(cmd_options),
+        devices,
+        |manager, addr, emit| {
+            let device = manager.find_by_address(addr)?;
+
+            debug!("find_by_address: {:?}", device);
+
+            let mut device = device::Miflora::new(device, manager)?;
+
+            device.connect()?;
+
+            if reset {
+                sync_store.remove(&device.get_address()?)?;
+            }
+
+            let new_records = sync::sync(&mut device, &sync_store, &clock, page_size, emit)?;
+
+            info!("{:} synced {:} new records", addr, new_records);
+
+            Ok(())
+        },
+        |output, result| output.write(&recorder.history(result)?),
+    )
 }
 
 fn history_count(
-    manager: &mut BluezManager,
+    manager: &BluezManager,
     cmd_options: &CmdOpts,
-    addr: &str,
+    recorder: &Recorder,
+    devices: &DeviceSelection,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let device = manager.find_by_address(addr, Some(60000))?;
+    for_each_device(
+        manager,
+        &mut output(cmd_options),
+        devices,
+        |manager, addr, emit| emit(history_count_device(manager, addr)?),
+        |output, mut result| {
+            result.alias = recorder.aliases.alias(&result.address).map(String::from);
+
+            output.write(&result)
+        },
+    )
+}
+
+fn history_count_device(
+    manager: &BluezManager,
+    addr: &str,
+) -> std::result::Result<HistoryCountResult, Box<dyn std::error::Error>> {
+    let device = manager.find_by_address(addr)?;
 
     debug!("find_by_address: {:?}", device);
 
@@ -358,31 +969,80 @@ fn history_count(
 
     device.connect()?;
 
-    let result = HistoryCountResult { total_records: device.get_history_record_count()? };
+    Ok(HistoryCountResult {
+        datetime: Local::now(),
+        address: device.get_address()?,
+        alias: None,
+        total_records: device.get_history_record_count()?,
+    })
+}
 
-    if cmd_options.json {
-        println!("{}", serde_json::to_string(&result)?);
-    }
-    else {
-        println!("Total history records: {:}", result.total_records);
+fn query(
+    cmd_options: &CmdOpts,
+    recorder: &Recorder,
+    opts: &QueryOpts,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let store = recorder.store.as_ref().ok_or("No store configured - use --store or set store in the config")?;
+    let filter = Filter {
+        addresses: opts.addresses.clone(),
+        since: opts.since.as_deref().map(parse_datetime).transpose()?,
+        until: opts.until.as_deref().map(parse_datetime).transpose()?,
+    };
+    let mut output = output(cmd_options);
+
+    output.select(opts.metrics.iter().map(|m| m.field()).collect());
+
+    if opts.history {
+        for row in store.history(&filter)? {
+            let mut result = HistoryRecordResult::from_row(row);
+
+            recorder.annotate(&mut result);
+            output.write(&result)?;
+        }
+    } else {
+        for row in store.realtime(&filter)? {
+            let mut result = ReadResult::from_row(row);
+
+            recorder.annotate(&mut result);
+            output.write(&result)?;
+        }
     }
 
-    Ok(())
+    output.finish()
 }
 
-fn run() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let mut manager = dbus_bluez::BluezManager::new()?;
-    let cmd_opts = CmdOpts::from_args();
+fn run(mut cmd_opts: CmdOpts) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(cmd_opts.config.as_deref())?;
+    let recorder = Recorder::new(&cmd_opts, &config)?;
+
+    cmd_opts.resolve_aliases(&recorder.aliases)?;
+
+    // Querying the store doesn't need bluetooth
+    if let cmd_opts::Command::Query(ref opts) = cmd_opts.cmd {
+        return query(&cmd_opts, &recorder, opts);
+    }
+
+    let timeouts = Timeouts::new(config.timeouts, cmd_opts.deadline_sec.map(Duration::from_secs));
+    let manager = dbus_bluez::BluezManager::new(timeouts)?;
 
-    manager.start_discovery(Some(4000))?;
+    manager.start_discovery()?;
 
     match cmd_opts.cmd {
-        cmd_opts::Command::Scan { duration_sec } => scan(&mut manager, &cmd_opts, duration_sec)?,
-        cmd_opts::Command::Read { ref addr } => read(&mut manager, &cmd_opts, addr)?,
-        cmd_opts::Command::Blink { ref addr } => blink(&mut manager, &cmd_opts, addr)?,
-        cmd_opts::Command::History { ref addr, from, to, page, clear } => history(&mut manager, &cmd_opts, addr, from, to, page, clear)?,
-        cmd_opts::Command::HistoryCount { ref addr } => history_count(&mut manager, &cmd_opts, addr)?,
-        cmd_opts::Command::HistoryClear { ref addr } => clear_history(&mut manager, &cmd_opts, addr)?,
+        cmd_opts::Command::Scan { duration_sec, set_alias, all } => scan(&manager, &cmd_opts, &recorder, duration_sec, set_alias, all)?,
+        cmd_opts::Command::Read { ref devices, samples } => read(&manager, &cmd_opts, &recorder, devices, samples)?,
+        cmd_opts::Command::Watch { ref addr, interval_sec, duration_sec } => watch(&manager, &cmd_opts, &recorder, addr, interval_sec, duration_sec)?,
+        cmd_opts::Command::Info { ref devices } => info(&manager, &cmd_opts, &recorder, devices)?,
+        cmd_opts::Command::Listen { ref addrs, duration_sec } => listen(&manager, &cmd_opts, &recorder, addrs, duration_sec)?,
+        cmd_opts::Command::Blink { ref addr } => blink(&manager, &cmd_opts, addr)?,
+        cmd_opts::Command::History(ref opts) => history(&manager, &cmd_opts, &config, &recorder, opts)?,
+        cmd_opts::Command::HistoryCount { ref devices } => history_count(&manager, &cmd_opts, &recorder, devices)?,
+        cmd_opts::Command::HistoryClear { ref addr } => clear_history(&manager, &cmd_opts, addr)?,
+        cmd_opts::Command::Sync { ref devices, page, reset } => sync(&manager, &cmd_opts, &config, &recorder, devices, page, reset)?,
+        cmd_opts::Command::RawRead { ref addr, ref uuid } => raw_read(&manager, &cmd_opts, addr, uuid)?,
+        cmd_opts::Command::RawWrite { ref addr, ref uuid, ref value } => raw_write(&manager, &cmd_opts, addr, uuid, &value.0)?,
+        cmd_opts::Command::RawNotify { ref addr, ref uuid, duration_sec } => raw_notify(&manager, &cmd_opts, addr, uuid, duration_sec)?,
+        cmd_opts::Command::Daemon => Daemon::new(&manager, &config, &recorder, cmd_opts.format, !cmd_opts.no_headers)?.run()?,
+        cmd_opts::Command::Query(_) => unreachable!(),
     }
 
     Ok(())
@@ -393,11 +1053,14 @@ fn main() {
 
     info!("starting up");
 
-    std::process::exit(match run() {
+    let cmd_opts = CmdOpts::from_args();
+    let format = cmd_opts.format;
+
+    std::process::exit(match run(cmd_opts) {
         Ok(_) => 0,
         Err(e) => {
-            error!("{:?}", e);
-            1
+            debug!("{:?}", e);
+            ErrorReport::new(exit::error_kind(&*e, device::error_kind), &*e).print(format)
         }
     })
 }
@@ -0,0 +1,256 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+// Xiaomi's service: every Miflora advertises it, and its service data carries the MiBeacon frames
+pub(crate) static MIBEACON_SERVICE_UUID: &str = "0000fe95-0000-1000-8000-00805f9b34fb";
+
+pub(crate) const MIFLORA_PRODUCT_ID: u16 = 0x0098;
+pub(crate) const FLOWER_POT_PRODUCT_ID: u16 = 0x015d;
+
+// A kind of Xiaomi device, as told by the product id of its MiBeacon frames
+#[derive(Debug, PartialEq)]
+pub(crate) struct Model {
+    pub product_id: u16,
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Plant sensors are what hat-miflora is for - everything else is left out of scans
+    pub plant: bool,
+}
+
+// Every Xiaomi device advertises the same service UUID, so scans turn up these as well
+const MODELS: &[Model] = &[
+    Model { product_id: MIFLORA_PRODUCT_ID, name: "HHCCJCY01", description: "Flower care", plant: true },
+    Model { product_id: FLOWER_POT_PRODUCT_ID, name: "HHCCPOT002", description: "Flower pot", plant: true },
+    Model { product_id: 0x03bc, name: "GCLS002", description: "Grow care garden", plant: true },
+    Model { product_id: 0x01aa, name: "LYWSDCGQ", description: "Thermometer", plant: false },
+    Model { product_id: 0x045b, name: "LYWSD02", description: "Clock thermometer", plant: false },
+    Model { product_id: 0x055b, name: "LYWSD03MMC", description: "Thermometer", plant: false },
+    Model { product_id: 0x0347, name: "CGG1", description: "Thermometer", plant: false },
+    Model { product_id: 0x0153, name: "YLYK01YL", description: "Remote control", plant: false },
+    Model { product_id: 0x0489, name: "M1S-T500", description: "Toothbrush", plant: false },
+];
+
+pub(crate) fn model(product_id: u16) -> Option<&'static Model> {
+    MODELS.iter().find(|model| model.product_id == product_id)
+}
+
+// Frame control bits
+const FRAME_ENCRYPTED: u16 = 0x0008;
+const FRAME_HAS_MAC: u16 = 0x0010;
+const FRAME_HAS_CAPABILITY: u16 = 0x0020;
+const FRAME_HAS_OBJECT: u16 = 0x0040;
+
+// Capability bit telling that two bytes of IO capability follow
+const CAPABILITY_HAS_IO: u8 = 0x20;
+
+const OBJECT_TEMPERATURE: u16 = 0x1004;
+const OBJECT_LUX: u16 = 0x1007;
+const OBJECT_MOISTURE: u16 = 0x1008;
+const OBJECT_CONDUCTIVITY: u16 = 0x1009;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Object {
+    Temperature(f32),
+    Lux(u32),
+    Moisture(u8),
+    Conductivity(u16),
+    Unknown { object_type: u16, data: Vec<u8> },
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Frame {
+    pub frame_control: u16,
+    pub product_id: u16,
+    pub frame_counter: u8,
+    pub version: u8,
+    pub encrypted: bool,
+    /// Address as sent in the frame (most significant byte first)
+    pub mac: Option<[u8; 6]>,
+    pub capability: Option<u8>,
+    /// Missing if the frame doesn't carry one, or it is encrypted
+    pub object: Option<Object>,
+}
+
+// Decodes the service data of a MiBeacon advertisement:
+//
+//   frame control (2) | product id (2) | frame counter (1) | [mac (6, reversed)]
+//   | [capability (1) | [io capability (2)]] | [object type (2) | length (1) | data]
+//
+// All values are little endian
+pub(crate) fn decode(data: &[u8]) -> Result<Frame, std::io::Error> {
+    let mut rdr = Cursor::new(data);
+
+    let frame_control = rdr.read_u16::<LittleEndian>()?;
+    let product_id = rdr.read_u16::<LittleEndian>()?;
+    let frame_counter = rdr.read_u8()?;
+    let encrypted = frame_control & FRAME_ENCRYPTED != 0;
+
+    let mac = if frame_control & FRAME_HAS_MAC != 0 {
+        let mut mac = [0; 6];
+        rdr.read_exact(&mut mac)?;
+        mac.reverse();
+        Some(mac)
+    } else {
+        None
+    };
+
+    let capability = if frame_control & FRAME_HAS_CAPABILITY != 0 {
+        let capability = rdr.read_u8()?;
+
+        if capability & CAPABILITY_HAS_IO != 0 {
+            rdr.read_u16::<LittleEndian>()?;
+        }
+
+        Some(capability)
+    } else {
+        None
+    };
+
+    let object = if frame_control & FRAME_HAS_OBJECT != 0 && !encrypted {
+        let object_type = rdr.read_u16::<LittleEndian>()?;
+        let length = rdr.read_u8()?;
+        let mut data = vec![0; length as usize];
+
+        rdr.read_exact(&mut data)?;
+
+        Some(decode_object(object_type, data)?)
+    } else {
+        None
+    };
+
+    Ok(Frame {
+        frame_control,
+        product_id,
+        frame_counter,
+        version: (frame_control >> 12) as u8,
+        encrypted,
+        mac,
+        capability,
+        object,
+    })
+}
+
+fn decode_object(object_type: u16, data: Vec<u8>) -> Result<Object, std::io::Error> {
+    let mut rdr = Cursor::new(&data);
+
+    Ok(match object_type {
+        OBJECT_TEMPERATURE => Object::Temperature(rdr.read_i16::<LittleEndian>()? as f32 / 10.0),
+        OBJECT_LUX => Object::Lux(rdr.read_u24::<LittleEndian>()?),
+        OBJECT_MOISTURE => Object::Moisture(rdr.read_u8()?),
+        OBJECT_CONDUCTIVITY => Object::Conductivity(rdr.read_u16::<LittleEndian>()?),
+        _ => Object::Unknown { object_type, data },
+    })
+}
+
+// Mifloras send one object per advertisement, so the values are collected until all of them
+// have been seen
+#[derive(Default)]
+pub(crate) struct SensorValues {
+    temperature: Option<f32>,
+    lux: Option<u32>,
+    moisture: Option<u8>,
+    conductivity: Option<u16>,
+}
+
+impl SensorValues {
+    pub fn update(&mut self, object: &Object) {
+        match *object {
+            Object::Temperature(v) => self.temperature = Some(v),
+            Object::Lux(v) => self.lux = Some(v),
+            Object::Moisture(v) => self.moisture = Some(v),
+            Object::Conductivity(v) => self.conductivity = Some(v),
+            Object::Unknown { object_type, .. } => debug!("ignoring object type {:#06x}", object_type),
+        }
+    }
+
+    // Returns temperature, lux, moisture and conductivity once all are known, and starts over.
+    // Lux is only waited for from sensors that have a light sensor
+    pub fn take(&mut self, has_lux: bool) -> Option<(f32, Option<u32>, u8, u16)> {
+        match (self.temperature, self.lux, self.moisture, self.conductivity) {
+            (Some(temperature), lux, Some(moisture), Some(conductivity)) if lux.is_some() || !has_lux => {
+                *self = SensorValues::default();
+                Some((temperature, lux, moisture, conductivity))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames as broadcast by a HHCCJCY01, one object each
+    const TEMPERATURE: [u8; 17] = [
+        0x71, 0x20, 0x98, 0x00, 0x12, 0xb5, 0xcf, 0x6a, 0x8d, 0x7c, 0xc4, 0x0d, 0x04, 0x10, 0x02,
+        0xf4, 0x00,
+    ];
+    const LUX: [u8; 18] = [
+        0x71, 0x20, 0x98, 0x00, 0x13, 0xb5, 0xcf, 0x6a, 0x8d, 0x7c, 0xc4, 0x0d, 0x07, 0x10, 0x03,
+        0x3d, 0x01, 0x00,
+    ];
+    const MOISTURE: [u8; 16] = [
+        0x71, 0x20, 0x98, 0x00, 0x14, 0xb5, 0xcf, 0x6a, 0x8d, 0x7c, 0xc4, 0x0d, 0x08, 0x10, 0x01,
+        0x2b,
+    ];
+    const CONDUCTIVITY: [u8; 17] = [
+        0x71, 0x20, 0x98, 0x00, 0x15, 0xb5, 0xcf, 0x6a, 0x8d, 0x7c, 0xc4, 0x0d, 0x09, 0x10, 0x02,
+        0xf6, 0x01,
+    ];
+
+    #[test]
+    fn decodes_frame_header() {
+        let frame = decode(&TEMPERATURE).unwrap();
+
+        assert_eq!(frame.product_id, MIFLORA_PRODUCT_ID);
+        assert_eq!(frame.frame_counter, 0x12);
+        assert_eq!(frame.version, 2);
+        assert!(!frame.encrypted);
+        assert_eq!(frame.mac, Some([0xc4, 0x7c, 0x8d, 0x6a, 0xcf, 0xb5]));
+        assert_eq!(frame.capability, Some(0x0d));
+    }
+
+    #[test]
+    fn knows_plant_sensors_by_product_id() {
+        let frame = decode(&TEMPERATURE).unwrap();
+
+        assert_eq!(model(frame.product_id).map(|m| (m.name, m.plant)), Some(("HHCCJCY01", true)));
+        assert_eq!(model(0x055b).map(|m| m.plant), Some(false));
+        assert_eq!(model(0xffff), None);
+    }
+
+    #[test]
+    fn decodes_objects() {
+        assert_eq!(decode(&TEMPERATURE).unwrap().object, Some(Object::Temperature(24.4)));
+        assert_eq!(decode(&LUX).unwrap().object, Some(Object::Lux(317)));
+        assert_eq!(decode(&MOISTURE).unwrap().object, Some(Object::Moisture(43)));
+        assert_eq!(decode(&CONDUCTIVITY).unwrap().object, Some(Object::Conductivity(502)));
+    }
+
+    #[test]
+    fn collects_sensor_values() {
+        let mut values = SensorValues::default();
+
+        for frame in [&TEMPERATURE[..], &MOISTURE[..], &CONDUCTIVITY[..]] {
+            values.update(&decode(frame).unwrap().object.unwrap());
+        }
+
+        assert_eq!(values.take(true), None);
+        assert_eq!(values.take(false), Some((24.4, None, 43, 502)));
+        assert_eq!(values.take(false), None);
+    }
+
+    #[test]
+    fn skips_encrypted_objects() {
+        let mut data = TEMPERATURE;
+        data[0] |= FRAME_ENCRYPTED as u8;
+
+        assert_eq!(decode(&data).unwrap().object, None);
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        assert!(decode(&TEMPERATURE[..14]).is_err());
+    }
+}
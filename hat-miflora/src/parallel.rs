@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::slice;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use hat_common::exit::{self, ErrorKind, Failure};
+
+use crate::dbus_bluez::BluezManager;
+use crate::device;
+
+// Hands what was read from a device on, to be output. Returns once it has been, so a device can
+// rely on what it emitted having been stored
+pub(crate) type Emit<'e, R> = dyn FnMut(R) -> Result<(), Box<dyn Error>> + 'e;
+
+// What a worker sends back to the thread doing the output
+pub(crate) enum Event<R> {
+    /// Answered on `done` once handled
+    Record { record: R, done: mpsc::Sender<Result<(), Failure>> },
+    Failed { address: String, error: String, kind: ErrorKind },
+}
+
+// Runs `work` for every address, on up to `parallel` devices at a time. The workers share the
+// manager, and with it the D-Bus connection. What the workers emit, and how devices failed, is
+// handed to `f` on the calling thread as it arrives. An error from `f` stops the workers after the
+// device they are busy with
+pub(crate) fn run<R, W, F>(
+    manager: &BluezManager,
+    addrs: &[String],
+    parallel: usize,
+    work: &W,
+    mut f: F,
+) -> Result<(), Box<dyn Error>>
+where
+    R: Send,
+    W: Fn(&BluezManager, &str, &mut Emit<R>) -> Result<(), Box<dyn Error>> + Sync,
+    F: FnMut(Event<R>) -> Result<(), Box<dyn Error>>,
+{
+    let queue = Mutex::new(addrs.iter());
+
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..parallel.min(addrs.len()) {
+            let tx = tx.clone();
+            let queue = &queue;
+
+            let manager = manager.clone();
+
+            scope.spawn(move || worker(&manager, queue, work, tx));
+        }
+
+        drop(tx);
+
+        for event in rx {
+            f(event)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn worker<R, W>(manager: &BluezManager, queue: &Mutex<slice::Iter<String>>, work: &W, tx: mpsc::Sender<Event<R>>)
+where
+    W: Fn(&BluezManager, &str, &mut Emit<R>) -> Result<(), Box<dyn Error>>,
+{
+    while let Some(address) = queue.lock().ok().and_then(|mut queue| queue.next()) {
+        debug!("{:} on {:?}", address, thread::current().id());
+
+        let result = work(manager, address, &mut |record| {
+            let (done, handled) = mpsc::channel();
+
+            tx.send(Event::Record { record, done }).map_err(|_| "Output has stopped")?;
+
+            Ok(handled.recv().map_err(|_| "Output has stopped")??)
+        });
+
+        if let Err(e) = result {
+            let failed = Event::Failed {
+                address: address.clone(),
+                error: e.to_string(),
+                kind: exit::error_kind(&*e, device::error_kind),
+            };
+
+            if tx.send(failed).is_err() {
+                break;
+            }
+        }
+    }
+}
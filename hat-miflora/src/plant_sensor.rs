@@ -0,0 +1,336 @@
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::device::{HistoryReadings, RealtimeReadings};
+use crate::mibeacon::{FLOWER_POT_PRODUCT_ID, MIFLORA_PRODUCT_ID};
+use crate::protocol::{FirmwareVersion, Protocol};
+
+// The characteristics a model keeps its data in, by UUID
+#[derive(Debug)]
+pub(crate) struct GattLayout {
+    pub firmware: &'static str,
+    pub device_mode: &'static str,
+    pub device_data: &'static str,
+    pub device_time: &'static str,
+    pub history_mode: &'static str,
+    pub history_data: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Characteristic {
+    /// Battery level and firmware version
+    Firmware,
+    DeviceMode,
+    /// Realtime readings
+    DeviceData,
+    DeviceTime,
+    HistoryMode,
+    /// The record count, or a record
+    HistoryData,
+}
+
+impl GattLayout {
+    pub const CHARACTERISTICS: [Characteristic; 6] = [
+        Characteristic::Firmware,
+        Characteristic::DeviceMode,
+        Characteristic::DeviceData,
+        Characteristic::DeviceTime,
+        Characteristic::HistoryMode,
+        Characteristic::HistoryData,
+    ];
+
+    pub fn uuid(&self, characteristic: Characteristic) -> &'static str {
+        match characteristic {
+            Characteristic::Firmware => self.firmware,
+            Characteristic::DeviceMode => self.device_mode,
+            Characteristic::DeviceData => self.device_data,
+            Characteristic::DeviceTime => self.device_time,
+            Characteristic::HistoryMode => self.history_mode,
+            Characteristic::HistoryData => self.history_data,
+        }
+    }
+
+    // Which of the characteristics a UUID is, in any case
+    pub fn characteristic(&self, uuid: &str) -> Option<Characteristic> {
+        Self::CHARACTERISTICS
+            .iter()
+            .copied()
+            .find(|characteristic| self.uuid(*characteristic).eq_ignore_ascii_case(uuid))
+    }
+}
+
+// What differs between the plant sensor models
+pub(crate) trait PlantSensor: fmt::Debug + Sync {
+    /// Model number, e.g. HHCCJCY01
+    fn name(&self) -> &'static str;
+
+    /// Whether the model has a light sensor
+    fn has_lux(&self) -> bool;
+
+    /// How the model is read over a connection, None if that isn't known
+    fn gatt(&self) -> Option<&'static dyn GattSensor>;
+}
+
+// Where a model keeps its data over a connection, and what it means. The commands written to the
+// mode characteristics are the same for every model
+pub(crate) trait GattSensor: fmt::Debug + Sync {
+    fn layout(&self) -> &'static GattLayout;
+
+    fn protocol(&self, version: Option<FirmwareVersion>) -> Protocol;
+
+    fn decode_realtime(&self, data: &[u8]) -> Result<RealtimeReadings, std::io::Error>;
+
+    // None for the empty records of a cleared history
+    fn decode_history(&self, data: &[u8]) -> Result<Option<HistoryReadings>, std::io::Error>;
+}
+
+// The Flower care (HHCCJCY01), the original Miflora
+#[derive(Debug)]
+pub(crate) struct FlowerCare;
+
+// The Flower pot (HHCCPOT002, also sold as VegTrug and "ropot"). It has no light sensor. Its
+// advertisements are those of the Flower care, but what it keeps in which characteristic hasn't
+// been checked against a real pot, so it isn't read over a connection
+#[derive(Debug)]
+pub(crate) struct FlowerPot;
+
+static FLOWER_CARE: FlowerCare = FlowerCare;
+static FLOWER_POT: FlowerPot = FlowerPot;
+
+static FLOWER_CARE_LAYOUT: GattLayout = GattLayout {
+    firmware: "00001a02-0000-1000-8000-00805f9b34fb",
+    device_mode: "00001a00-0000-1000-8000-00805f9b34fb",
+    device_data: "00001a01-0000-1000-8000-00805f9b34fb",
+    device_time: "00001a12-0000-1000-8000-00805f9b34fb",
+    history_mode: "00001a10-0000-1000-8000-00805f9b34fb",
+    history_data: "00001a11-0000-1000-8000-00805f9b34fb",
+};
+
+// Empty records of a cleared history are all 0x00 or all 0xff
+fn empty_history_record(data: &[u8]) -> bool {
+    data.len() >= 16 && (data[..16].iter().all(|b| *b == 0) || data[..16].iter().all(|b| *b == 0xff))
+}
+
+// Tells the model by the product id of its advertisements, or else by its name. Anything not
+// known to be a Flower pot is treated as a Flower care
+pub(crate) fn detect(product_id: Option<u16>, name: Option<&str>) -> &'static dyn PlantSensor {
+    match product_id {
+        Some(FLOWER_POT_PRODUCT_ID) => &FLOWER_POT,
+        Some(MIFLORA_PRODUCT_ID) => &FLOWER_CARE,
+        _ if name.is_some_and(|name| name.eq_ignore_ascii_case("ropot")) => &FLOWER_POT,
+        _ => &FLOWER_CARE,
+    }
+}
+
+impl PlantSensor for FlowerCare {
+    fn name(&self) -> &'static str {
+        "HHCCJCY01"
+    }
+
+    fn has_lux(&self) -> bool {
+        true
+    }
+
+    fn gatt(&self) -> Option<&'static dyn GattSensor> {
+        Some(&FLOWER_CARE)
+    }
+}
+
+impl GattSensor for FlowerCare {
+    fn layout(&self) -> &'static GattLayout {
+        &FLOWER_CARE_LAYOUT
+    }
+
+    fn protocol(&self, version: Option<FirmwareVersion>) -> Protocol {
+        Protocol::new(version)
+    }
+
+    fn decode_realtime(&self, data: &[u8]) -> Result<RealtimeReadings, std::io::Error> {
+        // What firmware 2.6.6 and newer answer when realtime mode wasn't written first
+        if data.starts_with(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "device isn't in realtime mode",
+            ));
+        }
+
+        let mut data = Cursor::new(data);
+
+        // byte 0-1, signed
+        let temperature = data.read_i16::<LittleEndian>()? as f32 * 0.1;
+
+        // byte 2
+        let unknown = data.read_u8()?;
+
+        // byte 3-6
+        let lux = data.read_u32::<LittleEndian>()?;
+
+        // byte 7
+        let moisture = data.read_u8()?;
+
+        // byte 8-9
+        let conductivity = data.read_u16::<LittleEndian>()?;
+
+        // byte 10-15 unknown
+        let mut unknown = vec![unknown];
+        data.read_to_end(&mut unknown)?;
+
+        Ok(RealtimeReadings {
+            temperature,
+            lux: Some(lux),
+            moisture,
+            conductivity,
+            unknown,
+            after_mode_switch: false,
+        })
+    }
+
+    fn decode_history(&self, data: &[u8]) -> Result<Option<HistoryReadings>, std::io::Error> {
+        debug!("read: {:?}", data);
+
+        if empty_history_record(data) {
+            debug!("Empty record, ignoring");
+
+            return Ok(None);
+        }
+
+        let mut data = Cursor::new(data);
+
+        // byte 0-3
+        let history_epoch_time = data.read_u32::<LittleEndian>()?;
+
+        // byte 4-5 temperature in 0.1 degree celcius, signed
+        let temperature = data.read_i16::<LittleEndian>()? as f32 * 0.1;
+
+        // byte 6 - unknown
+        let unknown = data.read_u8()?;
+
+        // byte 7-9 brightness in lux
+        let lux = data.read_u24::<LittleEndian>()?;
+
+        // byte 10 - unknown
+        let unknown2 = data.read_u8()?;
+
+        // byte 11 - moisture in procent
+        let moisture = data.read_u8()?;
+
+        // byte 12-13 - conductivity µS/cm
+        let conductivity = data.read_u16::<LittleEndian>()?;
+
+        // byte 14-15 - unknown
+        let mut unknown3 = [0; 2];
+        data.read_exact(&mut unknown3)?;
+
+        Ok(Some(HistoryReadings {
+            record_number: 0, // will be populated later
+            epoch: history_epoch_time,
+            temperature,
+            lux: Some(lux),
+            moisture,
+            conductivity,
+            unknown: vec![unknown, unknown2, unknown3[0], unknown3[1]],
+        }))
+    }
+}
+
+impl PlantSensor for FlowerPot {
+    fn name(&self) -> &'static str {
+        "HHCCPOT002"
+    }
+
+    fn has_lux(&self) -> bool {
+        false
+    }
+
+    fn gatt(&self) -> Option<&'static dyn GattSensor> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Firmware 2.6.6 and newer, after writing realtime mode. Older firmwares send the same
+    // layout without it
+    const REALTIME: [u8; 16] = [
+        0xef, 0x00, 0x00, 0x70, 0x00, 0x00, 0x00, 0x29, 0xf2, 0x01, 0x02, 0x3c, 0x00, 0xfb, 0x34, 0x9b,
+    ];
+    // Firmware 3.x
+    const HISTORY: [u8; 16] = [
+        0x20, 0x1c, 0x00, 0x00, 0xef, 0x00, 0x00, 0x70, 0x00, 0x00, 0x00, 0x29, 0xf2, 0x01, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn decodes_realtime_data() {
+        let r = FlowerCare.decode_realtime(&REALTIME).unwrap();
+
+        assert_eq!((r.temperature, r.lux, r.moisture, r.conductivity), (23.9, Some(112), 41, 498));
+        assert_eq!(r.unknown, vec![0x00, 0x02, 0x3c, 0x00, 0xfb, 0x34, 0x9b]);
+    }
+
+    #[test]
+    fn decodes_negative_temperature() {
+        let mut data = REALTIME;
+        data[..2].copy_from_slice(&[0xcc, 0xff]);
+
+        let r = FlowerCare.decode_realtime(&data).unwrap();
+
+        assert!((r.temperature - -5.2).abs() < 0.001, "{}", r.temperature);
+
+        let mut data = HISTORY;
+        data[4..6].copy_from_slice(&[0xcc, 0xff]);
+
+        let r = FlowerCare.decode_history(&data).unwrap().unwrap();
+
+        assert!((r.temperature - -5.2).abs() < 0.001, "{}", r.temperature);
+    }
+
+    #[test]
+    fn rejects_realtime_data_without_mode_write() {
+        assert!(FlowerCare
+            .decode_realtime(&[
+                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x99, 0x88, 0x77, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn decodes_history_record() {
+        let r = FlowerCare.decode_history(&HISTORY).unwrap().unwrap();
+
+        assert_eq!(r.epoch, 7200);
+        assert_eq!((r.temperature, r.lux, r.moisture, r.conductivity), (23.9, Some(112), 41, 498));
+    }
+
+    #[test]
+    fn skips_empty_history_records() {
+        assert!(FlowerCare.decode_history(&[0x00; 16]).unwrap().is_none());
+        assert!(FlowerCare.decode_history(&[0xff; 16]).unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_characteristics_by_uuid() {
+        let layout = FlowerCare.layout();
+
+        assert_eq!(layout.characteristic("00001A01-0000-1000-8000-00805F9B34FB"), Some(Characteristic::DeviceData));
+        assert_eq!(layout.characteristic("00002a00-0000-1000-8000-00805f9b34fb"), None);
+
+        for characteristic in GattLayout::CHARACTERISTICS.iter() {
+            assert_eq!(layout.characteristic(layout.uuid(*characteristic)), Some(*characteristic));
+        }
+    }
+
+    #[test]
+    fn detects_model() {
+        assert_eq!(detect(Some(0x015d), None).name(), "HHCCPOT002");
+        assert_eq!(detect(None, Some("ropot")).name(), "HHCCPOT002");
+        assert_eq!(detect(Some(0x0098), Some("Flower care")).name(), "HHCCJCY01");
+        assert_eq!(detect(None, None).name(), "HHCCJCY01");
+
+        assert!(detect(None, None).gatt().is_some());
+        assert!(detect(Some(0x015d), None).gatt().is_none());
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use hat_common::alias::Aliases;
+
+use crate::config::PlantsConfig;
+use crate::records::Reading;
+
+// Column names of the widely shared Flower Care plant database are accepted as well, so that
+// can be imported as is
+#[derive(Deserialize)]
+pub(crate) struct Species {
+    #[serde(alias = "pid")]
+    pub name: String,
+    #[serde(default, alias = "min_soil_moist")]
+    pub min_moisture: Option<f32>,
+    #[serde(default, alias = "max_soil_moist")]
+    pub max_moisture: Option<f32>,
+    #[serde(default, alias = "min_light_lux")]
+    pub min_lux: Option<f32>,
+    #[serde(default, alias = "max_light_lux")]
+    pub max_lux: Option<f32>,
+    #[serde(default, alias = "min_temp")]
+    pub min_temperature: Option<f32>,
+    #[serde(default, alias = "max_temp")]
+    pub max_temperature: Option<f32>,
+    #[serde(default, alias = "min_soil_ec")]
+    pub min_conductivity: Option<f32>,
+    #[serde(default, alias = "max_soil_ec")]
+    pub max_conductivity: Option<f32>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    TooLow,
+    Ok,
+    TooHigh,
+}
+
+impl Status {
+    fn of(value: f32, min: Option<f32>, max: Option<f32>) -> Option<Status> {
+        if min.is_none() && max.is_none() {
+            return None;
+        }
+
+        Some(if min.is_some_and(|min| value < min) {
+            Status::TooLow
+        } else if max.is_some_and(|max| value > max) {
+            Status::TooHigh
+        } else {
+            Status::Ok
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Health {
+    /// Every metric is within range
+    Good,
+    /// One metric is out of range
+    Warning,
+    /// More than one metric is out of range
+    Bad,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let health = match self {
+            Health::Good => "good",
+            Health::Warning => "warning",
+            Health::Bad => "bad",
+        };
+
+        write!(f, "{}", health)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct PlantStatus {
+    pub species: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moisture: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conductivity: Option<Status>,
+    pub health: Health,
+    /// "ok", or the metrics out of range - e.g. "moisture too low, lux too high"
+    pub summary: String,
+}
+
+impl PlantStatus {
+    pub const INFO_NAMES: [&'static str; 3] = ["species", "health", "summary"];
+
+    // Columns for table and csv output
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("species", self.species.clone()),
+            ("health", self.health.to_string()),
+            ("summary", self.summary.clone()),
+        ]
+    }
+}
+
+pub(crate) struct Plants {
+    /// address -> species
+    devices: HashMap<String, String>,
+    species: HashMap<String, Species>,
+}
+
+impl Plants {
+    // Devices can be given by address or alias
+    pub fn load(config: &PlantsConfig, aliases: &Aliases) -> Result<Plants, Box<dyn Error>> {
+        let species = match config.database {
+            Some(ref path) => load_species(path)?,
+            None => Vec::new(),
+        };
+        let species: HashMap<String, Species> = species
+            .into_iter()
+            .map(|s| (s.name.to_lowercase(), s))
+            .collect();
+
+        for (address, name) in &config.devices {
+            if !species.contains_key(&name.to_lowercase()) {
+                return Err(Box::from(format!(
+                    "Unknown species '{}' for {} - it isn't in the plant database",
+                    name, address
+                )));
+            }
+        }
+
+        let devices = config
+            .devices
+            .iter()
+            .map(|(device, name)| Ok((aliases.resolve(device)?, name.to_lowercase())))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Plants { devices, species })
+    }
+
+    pub fn status(&self, address: &str, (temperature, lux, moisture, conductivity): (f32, Option<u32>, u8, u16)) -> Option<PlantStatus> {
+        let species = self.species.get(self.devices.get(&address.to_uppercase())?)?;
+
+        let moisture = Status::of(moisture as f32, species.min_moisture, species.max_moisture);
+        let lux = lux.and_then(|lux| Status::of(lux as f32, species.min_lux, species.max_lux));
+        let temperature = Status::of(temperature, species.min_temperature, species.max_temperature);
+        let conductivity = Status::of(
+            conductivity as f32,
+            species.min_conductivity,
+            species.max_conductivity,
+        );
+
+        let problems: Vec<String> = [
+            ("moisture", moisture),
+            ("lux", lux),
+            ("temperature", temperature),
+            ("conductivity", conductivity),
+        ]
+        .iter()
+        .filter_map(|(name, status)| match status {
+            Some(Status::TooLow) => Some(format!("{} too low", name)),
+            Some(Status::TooHigh) => Some(format!("{} too high", name)),
+            _ => None,
+        })
+        .collect();
+
+        let health = match problems.len() {
+            0 => Health::Good,
+            1 => Health::Warning,
+            _ => Health::Bad,
+        };
+        let summary = if problems.is_empty() {
+            "ok".to_string()
+        } else {
+            problems.join(", ")
+        };
+
+        Some(PlantStatus {
+            species: species.name.clone(),
+            moisture,
+            lux,
+            temperature,
+            conductivity,
+            health,
+            summary,
+        })
+    }
+
+    pub fn annotate<R: Reading>(&self, reading: &mut R) {
+        if let Some(plant) = self.status(reading.address(), reading.sensor_values()) {
+            reading.set_plant(plant);
+        }
+    }
+}
+
+// The database is a JSON array or a CSV file with a header line, depending on the extension
+fn load_species(path: &Path) -> Result<Vec<Species>, Box<dyn Error>> {
+    debug!("loading plant database {:?}", path);
+
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read plant database {:?}: {:}", path, e))?;
+
+    let is_csv = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+
+    let species = if is_csv {
+        parse_csv(&content)?
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<Species>, _>>()
+    } else {
+        serde_json::from_str(&content)
+    };
+
+    species.map_err(|e| Box::from(format!("Invalid plant database {:?}: {:}", path, e)))
+}
+
+// Turns every CSV line into a JSON object keyed by the header. Numbers become numbers, and
+// empty values are left out
+fn parse_csv(content: &str) -> Result<Vec<serde_json::Value>, Box<dyn Error>> {
+    let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+    let header = split_csv_line(lines.next().ok_or("Empty plant database")?);
+    let mut rows = Vec::new();
+
+    for line in lines {
+        let mut row = serde_json::Map::new();
+
+        for (name, value) in header.iter().zip(split_csv_line(line)) {
+            if value.is_empty() {
+                continue;
+            }
+
+            let value = match value.parse::<f64>() {
+                Ok(number) if name != "pid" && name != "name" => serde_json::json!(number),
+                _ => serde_json::Value::String(value),
+            };
+
+            row.insert(name.to_string(), value);
+        }
+
+        rows.push(serde_json::Value::Object(row));
+    }
+
+    Ok(rows)
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                value.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => values.push(std::mem::take(&mut value).trim().to_string()),
+            c => value.push(c),
+        }
+    }
+
+    values.push(value.trim().to_string());
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn species(csv: &str) -> Vec<Species> {
+        parse_csv(csv)
+            .unwrap()
+            .into_iter()
+            .map(|row| serde_json::from_value(row).unwrap())
+            .collect()
+    }
+
+    fn plants() -> Plants {
+        let species = species("pid,min_soil_moist,max_soil_moist,min_light_lux,max_light_lux,min_temp,max_temp\nbasil,15,60,2500,60000,8,32\n");
+
+        Plants {
+            devices: vec![("C4:7C:8D:65:BD:8B".to_string(), "basil".to_string())].into_iter().collect(),
+            species: species.into_iter().map(|s| (s.name.clone(), s)).collect(),
+        }
+    }
+
+    #[test]
+    fn splits_quoted_values() {
+        assert_eq!(split_csv_line("a, b ,c"), vec!["a", "b", "c"]);
+        assert_eq!(split_csv_line("\"monstera, deliciosa\",15"), vec!["monstera, deliciosa", "15"]);
+        assert_eq!(split_csv_line("\"the \"\"big\"\" one\",\"\""), vec!["the \"big\" one", ""]);
+        assert_eq!(split_csv_line("basil,15\r"), vec!["basil", "15"]);
+        assert_eq!(split_csv_line("basil,,60"), vec!["basil", "", "60"]);
+    }
+
+    #[test]
+    fn parses_flower_care_database() {
+        let species = species(
+            "pid,min_soil_moist,max_soil_moist,min_soil_ec\r\n\
+             \"monstera, deliciosa\",15,60,\r\n\
+             \r\n\
+             1234,10,,350\r\n",
+        );
+
+        assert_eq!(species.len(), 2);
+        assert_eq!(species[0].name, "monstera, deliciosa");
+        assert_eq!((species[0].min_moisture, species[0].max_moisture), (Some(15.0), Some(60.0)));
+        assert_eq!(species[0].min_conductivity, None);
+        // a name that looks like a number stays a name
+        assert_eq!(species[1].name, "1234");
+        assert_eq!((species[1].max_moisture, species[1].min_conductivity), (None, Some(350.0)));
+    }
+
+    #[test]
+    fn status_is_ok_on_the_limits() {
+        assert!(Status::of(15.0, Some(15.0), Some(60.0)) == Some(Status::Ok));
+        assert!(Status::of(60.0, Some(15.0), Some(60.0)) == Some(Status::Ok));
+        assert!(Status::of(14.9, Some(15.0), Some(60.0)) == Some(Status::TooLow));
+        assert!(Status::of(60.1, Some(15.0), None) == Some(Status::Ok));
+        assert!(Status::of(60.1, None, Some(60.0)) == Some(Status::TooHigh));
+        assert!(Status::of(60.1, None, None).is_none());
+    }
+
+    #[test]
+    fn health_counts_metrics_out_of_range() {
+        let plants = plants();
+        let status = |values| plants.status("c4:7c:8d:65:bd:8b", values).unwrap();
+
+        let good = status((20.0, Some(3000), 30, 500));
+        assert!(good.health == Health::Good);
+        assert_eq!(good.summary, "ok");
+
+        let warning = status((20.0, Some(3000), 10, 500));
+        assert!(warning.health == Health::Warning);
+        assert_eq!(warning.summary, "moisture too low");
+
+        let bad = status((35.0, Some(70000), 10, 500));
+        assert!(bad.health == Health::Bad);
+        assert_eq!(bad.summary, "moisture too low, lux too high, temperature too high");
+
+        // no light sensor, so no light status
+        assert!(status((20.0, None, 30, 500)).lux.is_none());
+        assert!(plants.status("C4:7C:8D:67:C2:8B", (20.0, None, 30, 500)).is_none());
+    }
+}
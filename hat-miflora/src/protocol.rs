@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Firmware version, as found in bytes 2-6 of the firmware characteristic ("3.1.8")
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct FirmwareVersion(pub u8, pub u8, pub u8);
+
+impl FromStr for FirmwareVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .trim_end_matches('\0')
+            .split('.')
+            .map(|part| part.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("Unknown firmware version '{}'", s))?;
+
+        match parts.as_slice() {
+            [major, minor, patch] => Ok(FirmwareVersion(*major, *minor, *patch)),
+            _ => Err(format!("Unknown firmware version '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+// Realtime data can be read right away before this version. From it on, the device has to be
+// switched to realtime mode first, or the data read is all 0xaa 0xbb ...
+const REALTIME_MODE_WRITE: FirmwareVersion = FirmwareVersion(2, 6, 6);
+
+// The history record layout decoded here is the one of the 3.x firmwares. What older ones send
+// isn't known (no records of them have been captured), so their history isn't read at all
+const HISTORY_V3: FirmwareVersion = FirmwareVersion(3, 0, 0);
+
+// What differs between firmware versions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Protocol {
+    pub version: Option<FirmwareVersion>,
+    /// Realtime mode has to be written before reading realtime data
+    pub realtime_mode_write: bool,
+    /// The history records are laid out as decoded here
+    pub history: bool,
+}
+
+impl Protocol {
+    // Unknown versions are treated as the newest protocol
+    pub fn new(version: Option<FirmwareVersion>) -> Protocol {
+        Protocol {
+            version,
+            realtime_mode_write: version.is_none_or(|v| v >= REALTIME_MODE_WRITE),
+            history: version.is_none_or(|v| v >= HISTORY_V3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol(version: &str) -> Protocol {
+        Protocol::new(Some(version.parse().unwrap()))
+    }
+
+    #[test]
+    fn parses_firmware_versions() {
+        assert_eq!("3.1.8".parse(), Ok(FirmwareVersion(3, 1, 8)));
+        assert_eq!("2.6.2\0".parse(), Ok(FirmwareVersion(2, 6, 2)));
+        assert!("3.1".parse::<FirmwareVersion>().is_err());
+        assert!("abc".parse::<FirmwareVersion>().is_err());
+    }
+
+    // The supported matrix
+    #[test]
+    fn picks_protocol_by_firmware_version() {
+        let matrix = |version: &str| (protocol(version).realtime_mode_write, protocol(version).history);
+
+        assert_eq!(matrix("2.6.2"), (false, false));
+        assert_eq!(matrix("2.6.6"), (true, false));
+        assert_eq!(matrix("2.7.0"), (true, false));
+        assert_eq!(matrix("3.0.0"), (true, true));
+        assert_eq!(matrix("3.1.8"), (true, true));
+        assert_eq!(matrix("3.2.2"), (true, true));
+    }
+
+    #[test]
+    fn unknown_version_gets_newest_protocol() {
+        let protocol = Protocol::new(None);
+
+        assert!(protocol.realtime_mode_write);
+        assert!(protocol.history);
+    }
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+
+// What the Miflora sensors can measure, per the specification
+const LUX_MAX: u32 = 100_000;
+const CONDUCTIVITY_MAX: u16 = 10_000;
+
+// Values outside of these can't be real (the temperature range is that of the chip)
+const TEMPERATURE_MIN: f32 = -40.0;
+const TEMPERATURE_MAX: f32 = 85.0;
+const MOISTURE_MAX: u8 = 100;
+
+// Doubts about a reading, attached to it instead of dropping it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Flag {
+    /// A value that can't be real
+    OutOfRange,
+    /// First reading after switching to realtime mode, with moisture and conductivity still 0
+    Stale,
+    /// A value at the top of what the sensor measures - the real value may be higher
+    Saturated,
+}
+
+impl Flag {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::OutOfRange => "out_of_range",
+            Flag::Stale => "stale",
+            Flag::Saturated => "saturated",
+        }
+    }
+}
+
+pub(crate) fn check(temperature: f32, lux: Option<u32>, moisture: u8, conductivity: u16, after_mode_switch: bool) -> Vec<Flag> {
+    let mut flags = Vec::new();
+
+    if !(TEMPERATURE_MIN..=TEMPERATURE_MAX).contains(&temperature) || moisture > MOISTURE_MAX {
+        flags.push(Flag::OutOfRange);
+    }
+
+    if after_mode_switch && moisture == 0 && conductivity == 0 {
+        flags.push(Flag::Stale);
+    }
+
+    if lux.is_some_and(|lux| lux >= LUX_MAX) || conductivity >= CONDUCTIVITY_MAX {
+        flags.push(Flag::Saturated);
+    }
+
+    flags
+}
+
+// Flags as a tag value, e.g. "out_of_range,saturated"
+pub(crate) fn names(flags: &[Flag]) -> String {
+    flags.iter().map(Flag::name).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plausible_reading_has_no_flags() {
+        assert_eq!(check(-5.2, Some(112), 41, 498, true), vec![]);
+    }
+
+    #[test]
+    fn flags_impossible_values() {
+        assert_eq!(check(6553.5, Some(112), 41, 498, false), vec![Flag::OutOfRange]);
+        assert_eq!(check(20.0, Some(112), 140, 498, false), vec![Flag::OutOfRange]);
+    }
+
+    #[test]
+    fn flags_stale_reading_only_after_mode_switch() {
+        assert_eq!(check(20.0, Some(112), 0, 0, true), vec![Flag::Stale]);
+        assert_eq!(check(20.0, Some(112), 0, 0, false), vec![]);
+    }
+
+    #[test]
+    fn flags_saturated_sensors() {
+        assert_eq!(names(&check(35.0, Some(120_000), 41, 10_000, false)), "saturated");
+        assert_eq!(check(35.0, None, 41, 498, false), vec![]);
+    }
+}
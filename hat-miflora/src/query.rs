@@ -0,0 +1,41 @@
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Battery,
+    Temperature,
+    Lux,
+    Moisture,
+    Conductivity,
+}
+
+impl Metric {
+    // Name of the field holding this metric
+    pub fn field(self) -> &'static str {
+        match self {
+            Metric::Battery => "battery_pct",
+            Metric::Temperature => "temperature",
+            Metric::Lux => "lux",
+            Metric::Moisture => "moisture",
+            Metric::Conductivity => "conductivity",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "battery" => Ok(Metric::Battery),
+            "temperature" => Ok(Metric::Temperature),
+            "lux" => Ok(Metric::Lux),
+            "moisture" => Ok(Metric::Moisture),
+            "conductivity" => Ok(Metric::Conductivity),
+            _ => Err(format!(
+                "unknown metric '{}' (expected battery, temperature, lux, moisture or conductivity)",
+                s
+            )),
+        }
+    }
+}
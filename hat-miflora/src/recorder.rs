@@ -0,0 +1,126 @@
+use std::error::Error;
+
+use hat_common::alias::Aliases;
+use hat_common::calibration::Calibrations;
+use hat_common::store::Store;
+
+use crate::cmd_opts::CmdOpts;
+use crate::config::Config;
+use crate::plants::Plants;
+use crate::records::{AdvertisementResult, HistoryRecordResult, RawValues, ReadResult, Reading};
+
+// Everything that happens to a reading before it is output: it is stored (if there is a store),
+// calibrated, and annotated with the alias of the device and the status of the plant next to it.
+// The store keeps the values as read, so a changed calibration also applies to stored readings
+pub(crate) struct Recorder {
+    pub store: Option<Store>,
+    pub aliases: Aliases,
+    calibrations: Calibrations,
+    /// Keep the values from before calibration, and the bytes not decoded, in the output
+    raw: bool,
+    plants: Plants,
+}
+
+impl Recorder {
+    pub fn new(cmd_options: &CmdOpts, config: &Config) -> Result<Recorder, Box<dyn Error>> {
+        let store = match cmd_options.store.as_ref().or(config.store.as_ref()) {
+            Some(path) => Some(Store::open(path)?),
+            None => None,
+        };
+        let aliases = config.aliases()?;
+        let calibrations = Calibrations::new(&config.calibration, &aliases)?;
+        let plants = Plants::load(&config.plants, &aliases)?;
+
+        Ok(Recorder {
+            store,
+            aliases,
+            calibrations,
+            raw: cmd_options.raw,
+            plants,
+        })
+    }
+
+    pub fn realtime(&self, mut result: ReadResult) -> Result<ReadResult, Box<dyn Error>> {
+        if let Some(ref store) = self.store {
+            store.insert_realtime(&result.to_row())?;
+        }
+
+        if let Some(ref mut samples) = result.samples {
+            for r in [&mut samples.min, &mut samples.max] {
+                let values = (r.temperature, r.lux, r.moisture, r.conductivity);
+
+                if let Some((temperature, lux, moisture, conductivity)) = self.calibrate(&result.address, values) {
+                    r.temperature = temperature;
+                    r.lux = lux;
+                    r.moisture = moisture;
+                    r.conductivity = conductivity;
+                }
+            }
+        }
+
+        if !self.raw {
+            result.unknown = None;
+        }
+
+        self.annotate(&mut result);
+        Ok(result)
+    }
+
+    // Records already in the store are output again, as they were asked for
+    pub fn history(&self, mut result: HistoryRecordResult) -> Result<HistoryRecordResult, Box<dyn Error>> {
+        if let Some(ref store) = self.store {
+            if !store.insert_history(&result.to_row())? {
+                debug!("record {:} already stored", result.record_number);
+            }
+        }
+
+        if !self.raw {
+            result.unknown = None;
+        }
+
+        self.annotate(&mut result);
+        Ok(result)
+    }
+
+    pub fn advertisement(&self, mut result: AdvertisementResult) -> AdvertisementResult {
+        self.annotate(&mut result);
+        result
+    }
+
+    pub fn annotate<R: Reading>(&self, reading: &mut R) {
+        let values = reading.sensor_values();
+
+        if let Some(calibrated) = self.calibrate(reading.address(), values) {
+            reading.set_calibrated(calibrated);
+        }
+
+        // Readings of devices that aren't calibrated get them too (the same values), so every
+        // record has the same fields
+        if self.raw {
+            reading.set_raw(RawValues::new(values));
+        }
+
+        if let Some(alias) = self.aliases.alias(reading.address()) {
+            reading.set_alias(alias.to_string());
+        }
+
+        self.plants.annotate(reading);
+    }
+
+    // Calibrated temperature, lux, moisture and conductivity, or None if the device isn't
+    // calibrated. Whole numbers are rounded (and kept within what fits)
+    fn calibrate(&self, address: &str, (temperature, lux, moisture, conductivity): (f32, Option<u32>, u8, u16)) -> Option<(f32, Option<u32>, u8, u16)> {
+        if !self.calibrations.is_calibrated(address) {
+            return None;
+        }
+
+        let apply = |metric: &str, value: f64| self.calibrations.apply(address, metric, value).unwrap_or(value);
+
+        Some((
+            apply("temperature", temperature as f64) as f32,
+            lux.map(|lux| apply("lux", lux as f64).round() as u32),
+            apply("moisture", moisture as f64).round() as u8,
+            apply("conductivity", conductivity as f64).round() as u16,
+        ))
+    }
+}
@@ -0,0 +1,783 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use chrono::prelude::DateTime;
+use chrono::Local;
+use hat_common::format::{Field, Record, Unit, Value};
+use hat_common::store::{HistoryRow, RealtimeRow};
+use serde::Serialize;
+
+use crate::clock::BootTime;
+use crate::device::{Error, Miflora, RealtimeReadings};
+use crate::plant_sensor::PlantSensor;
+use crate::plants::PlantStatus;
+use crate::quality::{self, Flag};
+
+#[derive(Serialize)]
+pub(crate) struct ReadResult {
+    #[serde(with = "date_format")]
+    pub datetime: DateTime<Local>,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Model number, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<&'static str>,
+    pub battery_pct: u8,
+    pub firmware_version: String,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+    /// Set when several readings were taken. The values above are their median then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples: Option<Samples>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quality: Vec<Flag>,
+    /// Bytes of the reading whose meaning isn't known, as hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown: Option<String>,
+    /// Set when the values above were calibrated
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub calibrated: bool,
+    /// Values before calibration, when asked for with --raw
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawValues>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plant: Option<PlantStatus>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Samples {
+    pub count: usize,
+    pub min: RealtimeReadings,
+    pub max: RealtimeReadings,
+}
+
+impl ReadResult {
+    pub fn read(device: &Miflora) -> Result<ReadResult, Error> {
+        let readings = device.get_realtime_reading()?;
+
+        ReadResult::new(device, readings)
+    }
+
+    // Use after Miflora::start_realtime
+    pub fn read_realtime(device: &Miflora) -> Result<ReadResult, Error> {
+        let readings = device.read_realtime()?;
+
+        ReadResult::new(device, readings)
+    }
+
+    // Takes `count` readings, `interval` apart, and reports their median
+    pub fn sample(device: &mut Miflora, count: NonZeroUsize, interval: Duration) -> Result<ReadResult, Error> {
+        let mut readings = Vec::with_capacity(count.get());
+
+        device.start_realtime()?;
+
+        for i in 0..count.get() {
+            if i > 0 {
+                device.idle(interval);
+            }
+
+            readings.push(device.read_realtime()?);
+        }
+
+        let (temperature, lux, moisture, conductivity) = (
+            spread(readings.iter().map(|r| r.temperature).collect()),
+            spread(readings.iter().map(|r| r.lux).collect()),
+            spread(readings.iter().map(|r| r.moisture).collect()),
+            spread(readings.iter().map(|r| r.conductivity).collect()),
+        );
+        let pick = |i: usize| RealtimeReadings {
+            temperature: temperature[i],
+            lux: lux[i],
+            moisture: moisture[i],
+            conductivity: conductivity[i],
+            unknown: Vec::new(),
+            after_mode_switch: false,
+        };
+
+        let mut result = ReadResult::new(device, pick(1))?;
+
+        result.samples = Some(Samples {
+            count: readings.len(),
+            min: pick(0),
+            max: pick(2),
+        });
+
+        Ok(result)
+    }
+
+    fn new(device: &Miflora, readings: RealtimeReadings) -> Result<ReadResult, Error> {
+        let quality = quality::check(
+            readings.temperature,
+            readings.lux,
+            readings.moisture,
+            readings.conductivity,
+            readings.after_mode_switch,
+        );
+
+        Ok(ReadResult {
+            datetime: Local::now(),
+            address: device.get_address()?,
+            alias: None,
+            model: Some(device.get_sensor().name()),
+            battery_pct: device.get_battery_pct()?,
+            firmware_version: device.get_firmware_version()?,
+            temperature: readings.temperature,
+            lux: readings.lux,
+            moisture: readings.moisture,
+            conductivity: readings.conductivity,
+            samples: None,
+            quality,
+            unknown: (!readings.unknown.is_empty()).then(|| hex(&readings.unknown)),
+            calibrated: false,
+            raw: None,
+            plant: None,
+        })
+    }
+
+    pub fn to_row(&self) -> RealtimeRow {
+        RealtimeRow {
+            address: self.address.clone(),
+            datetime: self.datetime,
+            battery_pct: self.battery_pct,
+            firmware_version: self.firmware_version.clone(),
+            temperature: self.temperature,
+            lux: self.lux,
+            moisture: self.moisture,
+            conductivity: self.conductivity,
+        }
+    }
+
+    pub fn from_row(row: RealtimeRow) -> ReadResult {
+        ReadResult {
+            datetime: row.datetime,
+            address: row.address,
+            alias: None,
+            model: None,
+            battery_pct: row.battery_pct,
+            firmware_version: row.firmware_version,
+            temperature: row.temperature,
+            lux: row.lux,
+            moisture: row.moisture,
+            conductivity: row.conductivity,
+            samples: None,
+            quality: quality::check(row.temperature, row.lux, row.moisture, row.conductivity, false),
+            unknown: None,
+            calibrated: false,
+            raw: None,
+            plant: None,
+        }
+    }
+}
+
+// Min, median and max. The median of an even number of values is the lower of the middle two
+fn spread<T: PartialOrd + Copy>(mut values: Vec<T>) -> [T; 3] {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    [values[0], values[(values.len() - 1) / 2], values[values.len() - 1]]
+}
+
+impl Record for ReadResult {
+    fn measurement(&self) -> &'static str {
+        "miflora"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.datetime
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn info(&self) -> Vec<(&'static str, String)> {
+        let mut info = vec![("firmware_version", self.firmware_version.clone())];
+
+        info.extend(self.model.map(|model| ("model", model.to_string())));
+
+        if let Some(ref samples) = self.samples {
+            info.push(("samples", samples.count.to_string()));
+        }
+
+        if !self.quality.is_empty() {
+            info.push(("quality", quality::names(&self.quality)));
+        }
+
+        if self.calibrated {
+            info.push(("calibrated", "true".to_string()));
+        }
+
+        info.extend(self.plant.iter().flat_map(PlantStatus::info));
+        info
+    }
+
+    fn info_names(&self) -> Vec<&'static str> {
+        let mut names = vec!["firmware_version", "model"];
+
+        // --samples is given for all readings or none
+        if self.samples.is_some() {
+            names.push("samples");
+        }
+
+        names.extend(["quality", "calibrated"]);
+        names.extend(PlantStatus::INFO_NAMES);
+        names
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = vec![Field::new("battery_pct", Unit::Percent, Value::Int(self.battery_pct as i64))];
+
+        fields.extend(sensor_fields(self.temperature, self.lux, self.moisture, self.conductivity));
+
+        if let Some(ref samples) = self.samples {
+            let (min, max) = (&samples.min, &samples.max);
+
+            fields.extend(vec![
+                Field::new("min_temperature", Unit::Celsius, Value::Float(min.temperature)),
+                Field::new("max_temperature", Unit::Celsius, Value::Float(max.temperature)),
+                Field::new("min_lux", Unit::Lux, lux(min.lux)),
+                Field::new("max_lux", Unit::Lux, lux(max.lux)),
+                Field::new("min_moisture", Unit::Percent, Value::Int(min.moisture as i64)),
+                Field::new("max_moisture", Unit::Percent, Value::Int(max.moisture as i64)),
+                Field::new("min_conductivity", Unit::MicroSiemensPerCm, Value::Int(min.conductivity as i64)),
+                Field::new("max_conductivity", Unit::MicroSiemensPerCm, Value::Int(max.conductivity as i64)),
+            ]);
+        }
+
+        fields.extend(self.raw.iter().flat_map(RawValues::fields));
+        fields
+    }
+}
+
+// Realtime values received from an advertisement. There is no battery level or firmware version
+// in those
+#[derive(Serialize)]
+pub(crate) struct AdvertisementResult {
+    #[serde(with = "date_format")]
+    pub datetime: DateTime<Local>,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub source: &'static str,
+    pub model: &'static str,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+    /// Set when the values above were calibrated
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub calibrated: bool,
+    /// Values before calibration, when asked for with --raw
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawValues>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plant: Option<PlantStatus>,
+}
+
+impl AdvertisementResult {
+    pub fn new(address: &str, sensor: &dyn PlantSensor, (temperature, lux, moisture, conductivity): (f32, Option<u32>, u8, u16)) -> AdvertisementResult {
+        AdvertisementResult {
+            datetime: Local::now(),
+            address: address.to_string(),
+            alias: None,
+            source: "advertisement",
+            model: sensor.name(),
+            temperature,
+            lux,
+            moisture,
+            conductivity,
+            calibrated: false,
+            raw: None,
+            plant: None,
+        }
+    }
+}
+
+impl Record for AdvertisementResult {
+    fn measurement(&self) -> &'static str {
+        "miflora"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.datetime
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn info(&self) -> Vec<(&'static str, String)> {
+        let mut info = vec![("source", self.source.to_string()), ("model", self.model.to_string())];
+
+        if self.calibrated {
+            info.push(("calibrated", "true".to_string()));
+        }
+
+        info.extend(self.plant.iter().flat_map(PlantStatus::info));
+        info
+    }
+
+    fn info_names(&self) -> Vec<&'static str> {
+        let mut names = vec!["source", "model", "calibrated"];
+
+        names.extend(PlantStatus::INFO_NAMES);
+        names
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = sensor_fields(self.temperature, self.lux, self.moisture, self.conductivity);
+
+        fields.extend(self.raw.iter().flat_map(RawValues::fields));
+        fields
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct HistoryRecordResult {
+    #[serde(with = "date_format")]
+    pub datetime: DateTime<Local>,
+    // device time (seconds since boot) the record was logged at
+    #[serde(skip)]
+    pub epoch: u32,
+    pub record_number: u16,
+    pub total_records: u16,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Model number, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<&'static str>,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quality: Vec<Flag>,
+    /// Bytes of the record whose meaning isn't known, as hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown: Option<String>,
+    /// Set when the values above were calibrated
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub calibrated: bool,
+    /// Values before calibration, when asked for with --raw
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawValues>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plant: Option<PlantStatus>,
+}
+
+impl HistoryRecordResult {
+    pub fn to_row(&self) -> HistoryRow {
+        HistoryRow {
+            address: self.address.clone(),
+            datetime: self.datetime,
+            record_number: self.record_number,
+            total_records: self.total_records,
+            temperature: self.temperature,
+            lux: self.lux,
+            moisture: self.moisture,
+            conductivity: self.conductivity,
+        }
+    }
+
+    // The device time isn't stored, so `epoch` is left at 0
+    pub fn from_row(row: HistoryRow) -> HistoryRecordResult {
+        HistoryRecordResult {
+            datetime: row.datetime,
+            epoch: 0,
+            record_number: row.record_number,
+            total_records: row.total_records,
+            address: row.address,
+            alias: None,
+            model: None,
+            temperature: row.temperature,
+            lux: row.lux,
+            moisture: row.moisture,
+            conductivity: row.conductivity,
+            quality: quality::check(row.temperature, row.lux, row.moisture, row.conductivity, false),
+            unknown: None,
+            calibrated: false,
+            raw: None,
+            plant: None,
+        }
+    }
+}
+
+impl Record for HistoryRecordResult {
+    fn measurement(&self) -> &'static str {
+        "miflora"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.datetime
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn info(&self) -> Vec<(&'static str, String)> {
+        let mut info = vec![
+            ("record_number", self.record_number.to_string()),
+            ("total_records", self.total_records.to_string()),
+        ];
+
+        info.extend(self.model.map(|model| ("model", model.to_string())));
+
+        if !self.quality.is_empty() {
+            info.push(("quality", quality::names(&self.quality)));
+        }
+
+        if self.calibrated {
+            info.push(("calibrated", "true".to_string()));
+        }
+
+        info.extend(self.plant.iter().flat_map(PlantStatus::info));
+        info
+    }
+
+    fn info_names(&self) -> Vec<&'static str> {
+        let mut names = vec!["record_number", "total_records", "model", "quality", "calibrated"];
+
+        names.extend(PlantStatus::INFO_NAMES);
+        names
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = sensor_fields(self.temperature, self.lux, self.moisture, self.conductivity);
+
+        fields.extend(self.raw.iter().flat_map(RawValues::fields));
+        fields
+    }
+}
+
+// A reading of the sensors, which gets annotated with the alias of the device and the status of
+// the plant next to it
+pub(crate) trait Reading {
+    fn address(&self) -> &str;
+    // temperature, lux, moisture and conductivity
+    fn sensor_values(&self) -> (f32, Option<u32>, u8, u16);
+    fn set_alias(&mut self, alias: String);
+    fn set_plant(&mut self, plant: PlantStatus);
+    // Replaces the sensor values with calibrated ones
+    fn set_calibrated(&mut self, values: (f32, Option<u32>, u8, u16));
+    fn set_raw(&mut self, raw: RawValues);
+}
+
+// Sensor values as read, before calibration
+#[derive(Serialize, Clone, Copy)]
+pub(crate) struct RawValues {
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<u32>,
+    pub moisture: u8,
+    pub conductivity: u16,
+}
+
+impl RawValues {
+    pub fn new((temperature, lux, moisture, conductivity): (f32, Option<u32>, u8, u16)) -> RawValues {
+        RawValues {
+            temperature,
+            lux,
+            moisture,
+            conductivity,
+        }
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("raw_temperature", Unit::Celsius, Value::Float(self.temperature)),
+            Field::new("raw_lux", Unit::Lux, lux(self.lux)),
+            Field::new("raw_moisture", Unit::Percent, Value::Int(self.moisture as i64)),
+            Field::new("raw_conductivity", Unit::MicroSiemensPerCm, Value::Int(self.conductivity as i64)),
+        ]
+    }
+}
+
+// Everything known about a device, gathered in one connection
+#[derive(Serialize)]
+pub(crate) struct InfoResult {
+    #[serde(with = "date_format")]
+    pub datetime: DateTime<Local>,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub firmware_version: String,
+    pub battery_pct: u8,
+    /// Seconds since the device booted
+    pub uptime: u32,
+    #[serde(with = "date_format")]
+    pub boot_time: DateTime<Local>,
+    pub history_records: u16,
+    /// Only known while BlueZ is discovering
+    pub rssi: Option<i16>,
+    /// Not every device advertises it
+    pub tx_power: Option<i16>,
+    pub address_type: String,
+    pub paired: bool,
+    pub trusted: bool,
+    /// Characteristic -> D-Bus object path
+    pub characteristics: BTreeMap<&'static str, Option<String>>,
+}
+
+impl InfoResult {
+    pub fn read(device: &Miflora) -> Result<InfoResult, Error> {
+        let datetime = Local::now();
+        let uptime = device.get_device_time()?;
+
+        Ok(InfoResult {
+            datetime,
+            address: device.get_address()?,
+            alias: None,
+            firmware_version: device.get_firmware_version()?,
+            battery_pct: device.get_battery_pct()?,
+            uptime,
+            boot_time: datetime - chrono::Duration::seconds(uptime as i64),
+            history_records: device.get_history_record_count()?,
+            rssi: device.get_rssi().ok(),
+            tx_power: device.get_tx_power().ok(),
+            address_type: device.get_address_type()?,
+            paired: device.get_paired()?,
+            trusted: device.get_trusted()?,
+            characteristics: device.get_characteristic_paths().into_iter().collect(),
+        })
+    }
+}
+
+impl Record for InfoResult {
+    fn measurement(&self) -> &'static str {
+        "miflora"
+    }
+
+    fn datetime(&self) -> DateTime<Local> {
+        self.datetime
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    fn info(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("firmware_version", self.firmware_version.clone()),
+            ("boot_time", self.boot_time.format(date_format::FORMAT).to_string()),
+            ("address_type", self.address_type.clone()),
+            ("paired", self.paired.to_string()),
+            ("trusted", self.trusted.to_string()),
+        ]
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let dbm = |v: Option<i16>| v.map(|v| Value::Int(v as i64)).unwrap_or(Value::Missing);
+
+        vec![
+            Field::new("battery_pct", Unit::Percent, Value::Int(self.battery_pct as i64)),
+            Field::new("uptime", Unit::Seconds, Value::Int(self.uptime as i64)),
+            Field::new("history_records", Unit::None, Value::Int(self.history_records as i64)),
+            Field::new("rssi", Unit::Dbm, dbm(self.rssi)),
+            Field::new("tx_power", Unit::Dbm, dbm(self.tx_power)),
+        ]
+    }
+}
+
+macro_rules! impl_reading {
+    ($t:ty) => {
+        impl Reading for $t {
+            fn address(&self) -> &str {
+                &self.address
+            }
+
+            fn sensor_values(&self) -> (f32, Option<u32>, u8, u16) {
+                (self.temperature, self.lux, self.moisture, self.conductivity)
+            }
+
+            fn set_alias(&mut self, alias: String) {
+                self.alias = Some(alias);
+            }
+
+            fn set_plant(&mut self, plant: PlantStatus) {
+                self.plant = Some(plant);
+            }
+
+            fn set_calibrated(&mut self, (temperature, lux, moisture, conductivity): (f32, Option<u32>, u8, u16)) {
+                self.temperature = temperature;
+                self.lux = lux;
+                self.moisture = moisture;
+                self.conductivity = conductivity;
+                self.calibrated = true;
+            }
+
+            fn set_raw(&mut self, raw: RawValues) {
+                self.raw = Some(raw);
+            }
+        }
+    };
+}
+
+impl_reading!(ReadResult);
+impl_reading!(AdvertisementResult);
+impl_reading!(HistoryRecordResult);
+
+// Missing for sensors without a light sensor
+fn lux(lux: Option<u32>) -> Value {
+    lux.map(|lux| Value::Int(lux as i64)).unwrap_or(Value::Missing)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sensor_fields(temperature: f32, lux: Option<u32>, moisture: u8, conductivity: u16) -> Vec<Field> {
+    vec![
+        Field::new("temperature", Unit::Celsius, Value::Float(temperature)),
+        Field::new("lux", Unit::Lux, self::lux(lux)),
+        Field::new("moisture", Unit::Percent, Value::Int(moisture as i64)),
+        Field::new("conductivity", Unit::MicroSiemensPerCm, Value::Int(conductivity as i64)),
+    ]
+}
+
+pub(crate) mod date_format {
+    use chrono::{DateTime, Local};
+    use serde::{self, Serializer};
+
+    pub(crate) const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+    pub fn serialize<S>(date: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let s = format!("{}", date.format(FORMAT));
+        serializer.serialize_str(&s)
+    }
+}
+
+// A run of failed pages is given up on after this many reconnects
+const MAX_RECONNECTS: u32 = 3;
+
+// Reads history records `from` until `to`, `page_size` records at a time. Without `to`, records
+// are read until the last one - including those logged while reading. Every record is handed to
+// `f` as soon as it has been read. If the connection fails, the device is reconnected and the read
+// continues after the last record handed to `f`. Returns the record count when done
+pub(crate) fn read_history<F>(
+    device: &mut Miflora,
+    boot_time: &BootTime,
+    from: u16,
+    to: Option<u16>,
+    page_size: u16,
+    mut f: F,
+) -> Result<u16, Box<dyn std::error::Error>>
+where
+    F: FnMut(HistoryRecordResult) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let address = device.get_address()?;
+    let model = device.get_sensor().name();
+    let mut total_records = device.get_history_record_count()?;
+    let mut next = from;
+    let mut failures = 0;
+
+    debug!("reading history records {:} to {:?} with page size {:}", from, to, page_size);
+
+    loop {
+        let end = to.unwrap_or(total_records).min(total_records);
+
+        if next >= end {
+            if to.is_some() {
+                break;
+            }
+
+            let count = device.get_history_record_count()?;
+
+            if count <= total_records {
+                break;
+            }
+
+            debug!("{:} records logged while reading", count - total_records);
+            total_records = count;
+            continue;
+        }
+
+        let page_to = (next + page_size).min(end);
+        let mut output_failed = false;
+
+        debug!("reading page records {:?}-{:?} of {:?}", next, page_to, end);
+
+        let result = device.for_each_history_record(next, page_to, |reading| {
+            let record_number = reading.record_number;
+
+            f(HistoryRecordResult {
+                datetime: boot_time.record_time(reading.epoch),
+                epoch: reading.epoch,
+                record_number,
+                total_records,
+                address: address.clone(),
+                alias: None,
+                model: Some(model),
+                temperature: reading.temperature,
+                lux: reading.lux,
+                moisture: reading.moisture,
+                conductivity: reading.conductivity,
+                quality: quality::check(
+                    reading.temperature,
+                    reading.lux,
+                    reading.moisture,
+                    reading.conductivity,
+                    false,
+                ),
+                unknown: (!reading.unknown.is_empty()).then(|| hex(&reading.unknown)),
+                calibrated: false,
+                raw: None,
+                plant: None,
+            })
+            .inspect_err(|_| output_failed = true)?;
+
+            next = record_number + 1;
+
+            Ok(())
+        });
+
+        match result {
+            Ok(_) => {
+                next = page_to;
+                failures = 0;
+            }
+            Err(e) if output_failed => return Err(e),
+            Err(e) => {
+                failures += 1;
+
+                if failures > MAX_RECONNECTS {
+                    return Err(e);
+                }
+
+                warn!("{:} reading history failed at record {:}, reconnecting: {:}", address, next, e);
+
+                device.reconnect()?;
+            }
+        }
+    }
+
+    debug!("Got everything, stopping");
+
+    Ok(total_records)
+}
@@ -0,0 +1,60 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Small JSON files kept per device in the state directory
+pub(crate) struct StateFiles {
+    dir: PathBuf,
+    /// What the files hold, for error messages
+    name: &'static str,
+}
+
+impl StateFiles {
+    pub fn new(dir: PathBuf, name: &'static str) -> StateFiles {
+        StateFiles { dir, name }
+    }
+
+    fn path(&self, address: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", address.replace(':', "")))
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, address: &str) -> Result<Option<T>, Box<dyn Error>> {
+        let path = self.path(address);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)?;
+
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| Box::from(format!("Invalid {} {:?}: {:}", self.name, path, e)))
+    }
+
+    pub fn save<T: Serialize>(&self, address: &str, state: &T) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+
+        // write + rename, so a crash never leaves a half written state behind
+        let path = self.path(address);
+        let tmp = path.with_extension("json.tmp");
+
+        fs::write(&tmp, serde_json::to_string(state)?)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.path(address);
+
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
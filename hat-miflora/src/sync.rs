@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::device::Miflora;
+use crate::records::{self, HistoryRecordResult};
+use crate::state::StateFiles;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) struct LastRecord {
+    pub number: u16,
+    pub epoch: u32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct SyncState {
+    /// Next history record to read
+    pub next_record: u16,
+    /// History record count at the last sync
+    pub record_count: u16,
+    /// Last record synced - re-read to notice a history that was cleared and refilled
+    pub last_record: Option<LastRecord>,
+}
+
+pub(crate) struct SyncStore {
+    files: StateFiles,
+}
+
+impl SyncStore {
+    pub fn new(dir: PathBuf) -> SyncStore {
+        SyncStore {
+            files: StateFiles::new(dir, "sync state"),
+        }
+    }
+
+    pub fn load(&self, address: &str) -> Result<Option<SyncState>, Box<dyn Error>> {
+        self.files.load(address)
+    }
+
+    pub fn save(&self, address: &str, state: &SyncState) -> Result<(), Box<dyn Error>> {
+        self.files.save(address, state)
+    }
+
+    pub fn remove(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        self.files.remove(address)
+    }
+}
+
+// Reads the history records that weren't seen by an earlier sync, and hands them to `f`. The
+// state is saved after every record, so an interrupted sync continues where it stopped (a lost
+// connection is picked up again right away). Returns the number of new records
+pub(crate) fn sync<F>(
+    device: &mut Miflora,
+    store: &SyncStore,
+    clock: &Clock,
+    page_size: u16,
+    mut f: F,
+) -> Result<u16, Box<dyn Error>>
+where
+    F: FnMut(HistoryRecordResult) -> Result<(), Box<dyn Error>>,
+{
+    let address = device.get_address()?;
+    let total_records = device.get_history_record_count()?;
+    // A reboot keeps the records, but the device clock starts over - the clock takes care of that
+    let boot_time = clock.boot_time(device)?;
+
+    let mut state = match store.load(&address)? {
+        None => SyncState::default(),
+        Some(mut state) => {
+            let cleared = total_records < state.record_count
+                || match state.last_record {
+                    Some(last) if last.number < total_records => {
+                        device.get_history_record_epoch(last.number)? != Some(last.epoch)
+                    }
+                    _ => false,
+                };
+
+            if cleared {
+                warn!("{:} history was cleared since last sync, starting over", address);
+                state.next_record = 0;
+                state.last_record = None;
+            }
+
+            state
+        }
+    };
+
+    state.record_count = total_records;
+
+    debug!("{:} syncing records {:} to {:}", address, state.next_record, total_records);
+
+    let from = state.next_record;
+    let mut new_records = 0;
+
+    let total_records = records::read_history(device, &boot_time, from, None, page_size, |record| {
+        let last = LastRecord {
+            number: record.record_number,
+            epoch: record.epoch,
+        };
+
+        f(record)?;
+
+        new_records += 1;
+        state.next_record = last.number + 1;
+        state.last_record = Some(last);
+
+        store.save(&address, &state)
+    })?;
+
+    state.next_record = total_records;
+    state.record_count = total_records;
+    store.save(&address, &state)?;
+    clock.check(device, &boot_time)?;
+
+    Ok(new_records)
+}
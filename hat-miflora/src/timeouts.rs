@@ -0,0 +1,144 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+// How long every phase of talking to a device may take, in seconds
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TimeoutConfig {
+    /// Finding the bluetooth adapter and starting discovery
+    pub discover_sec: u64,
+    /// Finding a device, or the characteristics of a connected one
+    pub resolve_sec: u64,
+    /// Connecting to a device
+    pub connect_sec: u64,
+    /// Every read and write of a characteristic or property
+    pub io_sec: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            discover_sec: 4,
+            resolve_sec: 60,
+            connect_sec: 30,
+            io_sec: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    Discover,
+    Resolve,
+    Connect,
+    Io,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Discover => "discover",
+            Phase::Resolve => "resolve",
+            Phase::Connect => "connect",
+            Phase::Io => "I/O",
+        }
+    }
+}
+
+// The phase timeouts, and the deadline (--deadline) the whole command has to be done by. Every
+// wait is cut short to what is left until the deadline
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    config: TimeoutConfig,
+    deadline: Option<Instant>,
+}
+
+impl Timeouts {
+    pub fn new(config: TimeoutConfig, deadline: Option<Duration>) -> Timeouts {
+        Timeouts {
+            config,
+            deadline: deadline.map(|deadline| Instant::now() + deadline),
+        }
+    }
+
+    // What a phase may take. Fails once the deadline has passed
+    pub fn of(&self, phase: Phase) -> Result<Duration, io::Error> {
+        let timeout = match phase {
+            Phase::Discover => self.config.discover_sec,
+            Phase::Resolve => self.config.resolve_sec,
+            Phase::Connect => self.config.connect_sec,
+            Phase::Io => self.config.io_sec,
+        };
+
+        self.check()
+            .map(|_| self.cut(Duration::from_secs(timeout)))
+            .map_err(|e| io::Error::new(e.kind(), format!("{} ({} phase)", e, phase.name())))
+    }
+
+    // As `of`, in milliseconds for D-Bus
+    pub fn ms(&self, phase: Phase) -> Result<i32, io::Error> {
+        self.of(phase).map(|timeout| timeout.as_millis().clamp(1, i32::MAX as u128) as i32)
+    }
+
+    // `duration`, or what is left until the deadline if that is less
+    pub fn cut(&self, duration: Duration) -> Duration {
+        match self.deadline {
+            Some(deadline) => duration.min(deadline.saturating_duration_since(Instant::now())),
+            None => duration,
+        }
+    }
+
+    // What is left until the deadline, if there is one
+    pub fn left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // The earlier of `until` (None is never) and the deadline
+    pub fn until(&self, until: Option<Instant>) -> Option<Instant> {
+        match (until, self.deadline) {
+            (Some(until), Some(deadline)) => Some(until.min(deadline)),
+            (until, deadline) => until.or(deadline),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), io::Error> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Deadline passed"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_cut_to_the_deadline() {
+        let timeouts = Timeouts::new(TimeoutConfig::default(), Some(Duration::from_secs(10)));
+
+        assert_eq!(timeouts.of(Phase::Discover).unwrap(), Duration::from_secs(4));
+        assert!(timeouts.of(Phase::Resolve).unwrap() <= Duration::from_secs(10));
+        assert!(timeouts.of(Phase::Resolve).unwrap() > Duration::from_secs(9));
+    }
+
+    #[test]
+    fn fails_once_the_deadline_has_passed() {
+        let timeouts = Timeouts::new(TimeoutConfig::default(), Some(Duration::from_secs(0)));
+
+        assert_eq!(timeouts.of(Phase::Io).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(timeouts.cut(Duration::from_secs(5)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn no_deadline_keeps_phase_timeouts() {
+        let timeouts = Timeouts::new(TimeoutConfig::default(), None);
+
+        assert_eq!(timeouts.of(Phase::Resolve).unwrap(), Duration::from_secs(60));
+        assert_eq!(timeouts.until(None), None);
+    }
+}